@@ -0,0 +1,321 @@
+use crate::hqm_game::HQMTeam;
+use nalgebra::Point3;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HQMBlackboardValue {
+    Bool(bool),
+    Int(i64),
+    Float(f32),
+    Text(String),
+    /// Object or player index, such as the skater attacking the puck
+    Index(usize),
+    Position(Point3<f32>),
+}
+
+/// A value on the blackboard with the write that put it there
+#[derive(Debug, Clone, PartialEq)]
+pub struct HQMBlackboardEntry {
+    pub game_id: u32,
+    pub step: u32,
+    pub slot: usize,
+    /// None if the key was removed
+    pub value: Option<HQMBlackboardValue>,
+}
+
+impl HQMBlackboardEntry {
+    fn order(&self) -> (u32, u32, usize) {
+        (self.game_id, self.step, self.slot)
+    }
+}
+
+/// Where a slot is: the team it plays for, and the game and step it is ticking
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct HQMBlackboardCursor {
+    team: HQMTeam,
+    game_id: u32,
+    step: u32,
+}
+
+#[derive(Default)]
+struct HQMBlackboardState {
+    /// Writes per team and key, sorted by game, step and then slot
+    entries: HashMap<HQMTeam, BTreeMap<String, Vec<HQMBlackboardEntry>>>,
+    /// Where each slot is, missing before its first tick or after it left
+    cursors: HashMap<usize, HQMBlackboardCursor>,
+}
+
+impl HQMBlackboardState {
+    /// The entry a slot at `game_id`, `step` sees: the last write from an earlier step of the same game,
+    /// or its own write from this step
+    fn visible(entries: &[HQMBlackboardEntry], slot: usize, game_id: u32, step: u32) -> Option<&HQMBlackboardEntry> {
+        entries.iter().rev().find(|x| x.game_id == game_id
+            && (x.step < step || (x.step == step && x.slot == slot)))
+    }
+
+    /// The entry `slot` sees, if it is on a team
+    fn visible_to(&self, slot: usize, key: &str) -> Option<&HQMBlackboardEntry> {
+        let cursor = self.cursors.get(&slot)?;
+        let entries = self.entries.get(&cursor.team)?.get(key)?;
+        Self::visible(entries, slot, cursor.game_id, cursor.step)
+    }
+
+    /// Drops writes that no slot on `team` can see anymore
+    fn prune(&mut self, team: HQMTeam, key: &str) {
+        let cursors: Vec<(usize, u32, u32)> = self.cursors.iter()
+            .filter(|(_, cursor)| cursor.team == team)
+            .map(|(&slot, cursor)| (slot, cursor.game_id, cursor.step))
+            .collect();
+        let entries = match self.entries.get_mut(&team).and_then(|x| x.get_mut(key)) {
+            Some(entries) => entries,
+            None => return
+        };
+        let newest = entries.last().cloned();
+        let keep: Vec<HQMBlackboardEntry> = entries.iter().filter(|entry| {
+            cursors.iter().any(|&(slot, game_id, step)| {
+                entry.game_id == game_id && (entry.step >= step
+                    || Self::visible(entries, slot, game_id, step) == Some(*entry))
+            })
+        }).cloned().collect();
+        *entries = keep;
+        // Nobody is ticking, keep the last word
+        if entries.is_empty() {
+            entries.extend(newest);
+        }
+    }
+}
+
+/// Shared state for bots on the same server, for example for role assignment, pass targets or
+/// who is attacking the puck. Each team has its own keys: a bot reads and writes those of the team
+/// it is playing for, and a bot that isn't on a team sees nothing.
+///
+/// Every write is stamped with the game step it was made in and the slot of the writer, and writes are
+/// ordered by step and then slot, the later one winning. During a step a bot only sees what was written
+/// in earlier steps, plus its own writes, so what it reads doesn't depend on which bot happened to tick
+/// first. The same writes give the same blackboard whatever order they arrive in. Bots connected over
+/// a network may still drift a step or two apart, a bot behind the others doesn't see their writes yet.
+#[derive(Clone, Default)]
+pub struct HQMBlackboard {
+    state: Arc<Mutex<HQMBlackboardState>>,
+}
+
+impl HQMBlackboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle for the bot in `slot`. Slots decide who wins between writes in the same step.
+    pub fn slot(&self, slot: usize) -> HQMBlackboardSlot {
+        HQMBlackboardSlot {
+            board: self.clone(),
+            slot
+        }
+    }
+
+    /// The newest value of every key of `team`, whoever wrote it and whatever step they are at
+    pub fn latest(&self, team: HQMTeam) -> BTreeMap<String, HQMBlackboardEntry> {
+        let state = self.state.lock().unwrap();
+        let entries = match state.entries.get(&team) {
+            Some(entries) => entries,
+            None => return BTreeMap::new()
+        };
+        entries.iter().filter_map(|(key, entries)| {
+            entries.last().filter(|x| x.value.is_some()).map(|x| (key.clone(), x.clone()))
+        }).collect()
+    }
+}
+
+/// The blackboard as seen by one bot. Reads and writes use the team and step of the bot's current tick.
+#[derive(Clone)]
+pub struct HQMBlackboardSlot {
+    board: HQMBlackboard,
+    slot: usize,
+}
+
+impl HQMBlackboardSlot {
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// Moves the slot to a new tick for `team`. `BotFleet` does this before calling `HQMBotLogic::tick`,
+    /// for bots on a team.
+    pub fn begin_tick(&self, team: HQMTeam, game_id: u32, step: u32) {
+        self.board.state.lock().unwrap().cursors.insert(self.slot, HQMBlackboardCursor { team, game_id, step });
+    }
+
+    /// Stops the slot from holding on to old writes, until its next tick. `BotFleet` does this
+    /// for bots that aren't on a team.
+    pub fn leave(&self) {
+        self.board.state.lock().unwrap().cursors.remove(&self.slot);
+    }
+
+    pub fn get(&self, key: &str) -> Option<HQMBlackboardValue> {
+        self.entry(key).and_then(|x| x.value)
+    }
+
+    /// The visible write of `key`, with who made it and when
+    pub fn entry(&self, key: &str) -> Option<HQMBlackboardEntry> {
+        self.board.state.lock().unwrap().visible_to(self.slot, key).cloned()
+    }
+
+    /// Every visible key with a value
+    pub fn snapshot(&self) -> BTreeMap<String, HQMBlackboardValue> {
+        let state = self.board.state.lock().unwrap();
+        let cursor = match state.cursors.get(&self.slot) {
+            Some(cursor) => cursor,
+            None => return BTreeMap::new()
+        };
+        let entries = match state.entries.get(&cursor.team) {
+            Some(entries) => entries,
+            None => return BTreeMap::new()
+        };
+        entries.iter().filter_map(|(key, entries)| {
+            HQMBlackboardState::visible(entries, self.slot, cursor.game_id, cursor.step)
+                .and_then(|x| x.value.clone())
+                .map(|value| (key.clone(), value))
+        }).collect()
+    }
+
+    /// Writes `key` for the slot's team. Ignored while the slot has no tick, as the write has no
+    /// team or step.
+    pub fn set(&self, key: &str, value: HQMBlackboardValue) {
+        self.write(key, Some(value));
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.write(key, None);
+    }
+
+    fn write(&self, key: &str, value: Option<HQMBlackboardValue>) {
+        let mut state = self.board.state.lock().unwrap();
+        let cursor = match state.cursors.get(&self.slot) {
+            Some(&cursor) => cursor,
+            None => return
+        };
+        let entry = HQMBlackboardEntry { game_id: cursor.game_id, step: cursor.step, slot: self.slot, value };
+        let entries = state.entries.entry(cursor.team).or_default().entry(key.to_owned()).or_default();
+        match entries.binary_search_by_key(&entry.order(), HQMBlackboardEntry::order) {
+            Ok(i) => entries[i] = entry,
+            Err(i) => entries.insert(i, entry)
+        }
+        state.prune(cursor.team, key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(board: &HQMBlackboard, slot: usize, game_id: u32, step: u32, key: &str, value: i64) {
+        let slot = board.slot(slot);
+        slot.begin_tick(HQMTeam::Red, game_id, step);
+        slot.set(key, HQMBlackboardValue::Int(value));
+    }
+
+    #[test]
+    fn writes_are_seen_from_the_next_step() {
+        let board = HQMBlackboard::new();
+        let a = board.slot(0);
+        let b = board.slot(1);
+        a.begin_tick(HQMTeam::Red, 1, 10);
+        b.begin_tick(HQMTeam::Red, 1, 10);
+        assert_eq!(a.get("attacker"), None);
+        b.set("attacker", HQMBlackboardValue::Index(1));
+        a.set("attacker", HQMBlackboardValue::Index(0));
+        // Neither sees the other's write from the same step
+        assert_eq!(a.get("attacker"), Some(HQMBlackboardValue::Index(0)));
+        assert_eq!(b.get("attacker"), Some(HQMBlackboardValue::Index(1)));
+
+        // The higher slot wins the tie
+        a.begin_tick(HQMTeam::Red, 1, 11);
+        b.begin_tick(HQMTeam::Red, 1, 11);
+        assert_eq!(a.get("attacker"), Some(HQMBlackboardValue::Index(1)));
+        assert_eq!(b.entry("attacker").unwrap().slot, 1);
+
+        a.remove("attacker");
+        a.begin_tick(HQMTeam::Red, 1, 12);
+        b.begin_tick(HQMTeam::Red, 1, 12);
+        assert_eq!(b.get("attacker"), None);
+        assert!(b.snapshot().is_empty());
+        assert!(board.latest(HQMTeam::Red).is_empty());
+    }
+
+    #[test]
+    fn arrival_order_does_not_matter() {
+        let writes = [(0, 5, 1), (1, 5, 2), (2, 4, 3), (1, 6, 4), (0, 6, 5)];
+        let forward = HQMBlackboard::new();
+        for &(slot, step, value) in writes.iter() {
+            write(&forward, slot, 1, step, "role", value);
+        }
+        let backward = HQMBlackboard::new();
+        for &(slot, step, value) in writes.iter().rev() {
+            write(&backward, slot, 1, step, "role", value);
+        }
+        assert_eq!(forward.latest(HQMTeam::Red), backward.latest(HQMTeam::Red));
+        assert_eq!(forward.latest(HQMTeam::Red)["role"].value, Some(HQMBlackboardValue::Int(4)));
+
+        for board in [forward, backward].iter() {
+            let reader = board.slot(3);
+            reader.begin_tick(HQMTeam::Red, 1, 6);
+            assert_eq!(reader.get("role"), Some(HQMBlackboardValue::Int(2)));
+        }
+    }
+
+    #[test]
+    fn games_are_kept_apart() {
+        let board = HQMBlackboard::new();
+        write(&board, 0, 1, 5000, "target", 7);
+        let reader = board.slot(1);
+        reader.begin_tick(HQMTeam::Red, 2, 10);
+        assert_eq!(reader.get("target"), None);
+        write(&board, 0, 2, 5, "target", 8);
+        assert_eq!(reader.get("target"), Some(HQMBlackboardValue::Int(8)));
+    }
+
+    #[test]
+    fn old_writes_are_dropped() {
+        let board = HQMBlackboard::new();
+        let a = board.slot(0);
+        let b = board.slot(1);
+        for step in 0..1000 {
+            a.begin_tick(HQMTeam::Red, 1, step);
+            b.begin_tick(HQMTeam::Red, 1, step);
+            a.set("pass", HQMBlackboardValue::Int(step as i64));
+            b.set("pass", HQMBlackboardValue::Int(-(step as i64)));
+        }
+        assert!(board.state.lock().unwrap().entries[&HQMTeam::Red]["pass"].len() <= 3);
+        assert_eq!(a.entry("pass").unwrap().step, 999);
+
+        // A slot that has left doesn't hold anything back
+        b.leave();
+        a.begin_tick(HQMTeam::Red, 1, 1000);
+        a.set("pass", HQMBlackboardValue::Int(1000));
+        assert!(board.state.lock().unwrap().entries[&HQMTeam::Red]["pass"].len() <= 2);
+    }
+
+    #[test]
+    fn teams_are_kept_apart() {
+        let board = HQMBlackboard::new();
+        let red = board.slot(0);
+        let blue = board.slot(1);
+        red.begin_tick(HQMTeam::Red, 1, 10);
+        blue.begin_tick(HQMTeam::Blue, 1, 10);
+        red.set("attacker", HQMBlackboardValue::Index(0));
+        blue.set("attacker", HQMBlackboardValue::Index(1));
+        red.begin_tick(HQMTeam::Red, 1, 11);
+        blue.begin_tick(HQMTeam::Blue, 1, 11);
+        assert_eq!(red.get("attacker"), Some(HQMBlackboardValue::Index(0)));
+        assert_eq!(blue.get("attacker"), Some(HQMBlackboardValue::Index(1)));
+        assert_eq!(board.latest(HQMTeam::Blue)["attacker"].slot, 1);
+
+        // A bot switching sides sees its new team's keys only
+        red.begin_tick(HQMTeam::Blue, 1, 12);
+        assert_eq!(red.get("attacker"), Some(HQMBlackboardValue::Index(1)));
+        // Without a team there is nothing to read or write
+        red.leave();
+        red.set("attacker", HQMBlackboardValue::Index(0));
+        assert_eq!(red.get("attacker"), None);
+        assert_eq!(board.latest(HQMTeam::Blue)["attacker"].slot, 1);
+    }
+}
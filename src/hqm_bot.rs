@@ -1,427 +1,719 @@
-use crate::hqm_parse::{HQMMessageWriter, HQMObjectPacket, HQMMessageReader, HQMSkaterPacket, HQMPuckPacket};
-use crate::hqm_game::{HQMMessage, HQMPlayerInput, HQMTeam, HQMGameStateObject, HQMGameState, HQMPlayer, HQMGameStatePuck, HQMGameStateSkater};
-use std::collections::HashMap;
+use crate::hqm_parse::{ClientPacket, HQMClientUpdatePacket, ProtocolError};
+use crate::hqm_game::{HQMMessage, HQMPlayerInput, HQMGameState};
+use std::collections::VecDeque;
 use tokio::net::UdpSocket;
 use std::sync::Arc;
-use nalgebra::Point3;
 use crate::hqm_parse;
+use crate::hqm_events::GameEvent;
+use crate::hqm_replay::{HQMReplayRecordKind, HQMReplayWriter};
+use crate::hqm_tracker::{HQMGameTracker, HQMTrackerUpdate};
+use std::fs::File;
+use std::io::BufWriter;
 use std::net::SocketAddr;
-use bytes::BytesMut;
-
-const GAME_HEADER: &[u8] = b"Hock";
+use std::path::PathBuf;
+use std::time::Duration;
+use bytes::{Bytes, BytesMut};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HQMConnectionState {
+    /// The join message has been sent, but nothing has been heard from the server yet
+    Connecting,
+    Joined,
+    /// The server went silent for longer than the receive timeout
+    Lost,
+    /// Re-sending the join message, backing off between attempts
+    Reconnecting,
+}
 
 pub trait HQMBotLogic {
     fn new_game(& mut self);
     fn tick(& mut self, state: &HQMGameState, messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>);
+    fn connection_state_changed(& mut self, _state: HQMConnectionState) {}
+    /// Called before `tick` with the events derived from the new state, if there are any.
+    /// Events such as `PossessionChanged` come here rather than in the `HQMMessage` list given to
+    /// `tick`, as they are worked out by the bot and not sent by the server.
+    fn game_events(& mut self, _state: &HQMGameState, _events: &[GameEvent]) {}
+    /// Called when a chat message from `tick` was given up on, as the server never echoed it back
+    fn chat_dropped(& mut self, _message: &str) {}
+}
+
+impl<T: HQMBotLogic + ?Sized> HQMBotLogic for Box<T> {
+    fn new_game(& mut self) {
+        (**self).new_game()
+    }
+
+    fn tick(& mut self, state: &HQMGameState, messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+        (**self).tick(state, messages)
+    }
+
+    fn connection_state_changed(& mut self, state: HQMConnectionState) {
+        (**self).connection_state_changed(state)
+    }
+
+    fn game_events(& mut self, state: &HQMGameState, events: &[GameEvent]) {
+        (**self).game_events(state, events)
+    }
+
+    fn chat_dropped(& mut self, message: &str) {
+        (**self).chat_dropped(message)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HQMSessionConfig {
+    /// How long the server may stay silent before the connection is considered lost
+    pub receive_timeout: Duration,
+    /// Delay before the first re-sent join message. Doubles after every attempt.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// File to record everything sent and received to, see `hqm_replay`. Every call to `start`
+    /// adds a session to the end of the file.
+    pub replay_path: Option<PathBuf>,
+}
+
+impl Default for HQMSessionConfig {
+    fn default() -> Self {
+        HQMSessionConfig {
+            receive_timeout: Duration::from_secs(3),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            replay_path: None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HQMExitReason {
+    /// Stopped through a `HQMShutdownHandle`
+    Stopped,
+    /// The task reading from the socket went away
+    ReceiveTaskEnded,
+}
+
+#[derive(Debug, Clone)]
+pub struct HQMSessionSummary {
+    pub packets_received: u64,
+    pub games_played: u32,
+    pub exit_reason: HQMExitReason,
+}
+
+#[derive(Debug, Clone)]
+pub struct HQMShutdownHandle {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl HQMShutdownHandle {
+    pub(crate) fn new(sender: Arc<watch::Sender<bool>>) -> Self {
+        HQMShutdownHandle { sender }
+    }
+
+    /// Makes the session tell the server it is leaving and return from `start`.
+    /// A stopped session stays stopped.
+    pub fn stop(&self) {
+        let _ = self.sender.send(true);
+    }
+}
+
+/// How many updates a chat message is repeated in without the server echoing it back,
+/// before it is given up on and `HQMBotLogic::chat_dropped` is called
+const MAX_CHAT_ATTEMPTS: u32 = 500;
+
+/// Server commands such as /login are never echoed back. They are repeated in this many updates,
+/// which the server skips as repeats, and then taken as delivered.
+const COMMAND_REPEATS: u32 = 10;
+
+struct HQMPendingChat {
+    rep: u32,
+    message: String,
+    attempts: u32,
+    command: bool,
 }
 
 pub struct HQMBotSession<T: HQMBotLogic> {
     name: String,
-    current_game: u32,
-    known_packet: u32,
+    config: HQMSessionConfig,
+    connection_state: HQMConnectionState,
+    shutdown_sender: Arc<watch::Sender<bool>>,
+    shutdown_receiver: watch::Receiver<bool>,
+    packets_received: u64,
+    games_played: u32,
     chat_rep: u32,
-    known_msgpos: u16,
-    players: HashMap<usize, HQMPlayer>,
-    saved_packets: HashMap<u32, Vec<HQMObjectPacket>>,
+    chat_outbox: VecDeque<String>,
+    pending_chat: Option<HQMPendingChat>,
+    tracker: HQMGameTracker,
+    recorder: Option<HQMReplayWriter<BufWriter<File>>>,
     logic: T
 }
 
 impl<T: HQMBotLogic> HQMBotSession<T> {
 
     pub fn new (name: String, logic: T) -> Self {
+        Self::with_config(name, logic, HQMSessionConfig::default())
+    }
+
+    pub fn with_config (name: String, logic: T, config: HQMSessionConfig) -> Self {
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
         HQMBotSession {
             name,
-            current_game: u32::MAX,
-            known_packet: u32::MAX,
+            config,
+            connection_state: HQMConnectionState::Connecting,
+            shutdown_sender: Arc::new(shutdown_sender),
+            shutdown_receiver,
+            packets_received: 0,
+            games_played: 0,
             chat_rep: 0,
-            known_msgpos: 0,
-            players: HashMap::new(),
-            saved_packets: HashMap::new(),
+            chat_outbox: VecDeque::new(),
+            pending_chat: None,
+            tracker: HQMGameTracker::default(),
+            recorder: None,
             logic
         }
     }
 
-    pub async fn start (& mut self, server_address: SocketAddr) -> std::io::Result<()> {
+    /// Returns a handle that can be used to stop the session from another task.
+    pub fn shutdown_handle (&self) -> HQMShutdownHandle {
+        HQMShutdownHandle::new(self.shutdown_sender.clone())
+    }
+
+    pub async fn start (& mut self, server_address: SocketAddr) -> std::io::Result<HQMSessionSummary> {
         let local_addr: SocketAddr = SocketAddr::from(([0, 0, 0, 0], 0));
 
         let socket = Arc::new(UdpSocket::bind(local_addr).await?);
         socket.connect(server_address).await?;
 
-
+        if let Some(path) = &self.config.replay_path {
+            self.recorder = Some(HQMReplayWriter::append(path)?);
+        }
 
         let (msg_sender, mut msg_receiver) = tokio::sync::mpsc::channel(64);
-        {
+        let receive_task = {
             let socket = socket.clone();
             tokio::spawn(async move {
                 loop {
                     let mut buf = BytesMut::new();
                     buf.resize(2048, 0u8);
 
-                    match socket.recv(&mut buf).await {
-                        Ok(size) => {
-                            buf.truncate(size);
-                            let _ = msg_sender.send(buf.freeze()).await;
+                    if let Ok(size) = socket.recv(&mut buf).await {
+                        buf.truncate(size);
+                        if msg_sender.send(buf.freeze()).await.is_err() {
+                            break;
                         }
-                        Err(_) => {}
                     }
                 }
-            });
+            })
         };
 
+        self.packets_received = 0;
+        self.games_played = 0;
+        let exit_reason = self.run(&socket, &mut msg_receiver).await;
+        receive_task.abort();
 
-        self.send_join_message(&socket).await?;
-        loop {
-            if let Some(x) = msg_receiver.recv().await {
-                self.handle_message (x.as_ref(), &socket).await;
+        if exit_reason == HQMExitReason::Stopped {
+            if let Err(e) = self.send_exit_message(&socket).await {
+                eprintln!("Could not send exit message: {}", e);
             }
         }
-    }
+        self.reset_game();
 
+        if let Some(mut recorder) = self.recorder.take() {
+            if let Err(e) = recorder.flush() {
+                eprintln!("Could not write replay: {}", e);
+            }
+        }
 
-    async fn handle_message (& mut self, msg: &[u8], socket: &UdpSocket) -> std::io::Result<()> {
-        let mut parser = HQMMessageReader::new(&msg);
+        Ok(HQMSessionSummary {
+            packets_received: self.packets_received,
+            games_played: self.games_played,
+            exit_reason
+        })
+    }
 
-        let header = parser.read_bytes_aligned(4);
-        if header != GAME_HEADER {
-            return Ok(());
+    async fn run (& mut self, socket: &UdpSocket, msg_receiver: &mut Receiver<Bytes>) -> HQMExitReason {
+        let mut shutdown = self.shutdown_receiver.clone();
+        if *shutdown.borrow() {
+            return HQMExitReason::Stopped;
         }
 
-        let command = parser.read_byte_aligned();
-        let mut input = Default::default();
-        let mut chat = None;
-        if command == 5 {
-
-            let game_id = parser.read_u32_aligned();
-            let step = parser.read_u32_aligned();
-            let game_over = parser.read_bits(1) == 1;
-            let red_score = parser.read_bits(8);
-            let blue_score = parser.read_bits(8);
-
-            let time = parser.read_bits(16);
-            let goal_time = parser.read_bits(16);
-
-            let period = parser.read_bits(8);
-
-            let own_player_id = parser.read_bits (8) as usize;
-
-            let packet = parser.read_u32_aligned();
-            let known_packet = parser.read_u32_aligned();
-
-            let old_packet = self.saved_packets.get(&(known_packet & 0xff));
-            let mut new_packet:Vec<HQMObjectPacket> = Vec::new ();
-            for i in 0..32 {
-
-                let exists = parser.read_bits(1) == 1;
-                let new_object = if exists {
-                    let obj_type = parser.read_bits(2);
-
-                    if obj_type == 0 {
-                        let old_packet_object = old_packet.and_then(|x| match &x[i] {
-                            HQMObjectPacket::Skater(skater) => Some(skater),
-                            _ => None
-                        });
-                        let old_pos = old_packet_object.map (|x| x.pos);
-                        let old_rot = old_packet_object.map (|x| x.rot);
-
-                        let x = parser.read_pos(17, old_pos.map(|x| x.0));
-                        let y = parser.read_pos(17, old_pos.map(|x| x.1));
-                        let z = parser.read_pos(17, old_pos.map(|x| x.2));
-                        let r1 = parser.read_pos(31, old_rot.map(|x| x.0));
-                        let r2 = parser.read_pos(31, old_rot.map(|x| x.1));
-
-                        let stick_x = parser.read_pos(13, old_packet_object.map(|x| x.stick_pos.0));
-                        let stick_y = parser.read_pos(13, old_packet_object.map(|x| x.stick_pos.1));
-                        let stick_z = parser.read_pos(13, old_packet_object.map(|x| x.stick_pos.2));
-
-                        let stick_r1 = parser.read_pos(25, old_packet_object.map(|x| x.stick_rot.0));
-                        let stick_r2 = parser.read_pos(25, old_packet_object.map(|x| x.stick_rot.1));
-
-                        let head_rot = parser.read_pos(16, old_packet_object.map(|x| x.head_rot));
-                        let body_rot = parser.read_pos(16, old_packet_object.map(|x| x.body_rot));
-
-                        HQMObjectPacket::Skater(HQMSkaterPacket {
-                            pos: (x, y, z),
-                            rot: (r1, r2),
-                            stick_pos: (stick_x, stick_y, stick_z),
-                            stick_rot: (stick_r1, stick_r2),
-                            head_rot,
-                            body_rot,
-                        })
-                    } else if obj_type == 1 {
-                        let old_packet_object = old_packet.and_then(|x| match &x[i] {
-                            HQMObjectPacket::Puck(puck) => Some(puck),
-                            _ => None
-                        });
-
-                        let old_pos = old_packet_object.map (|x| x.pos);
-                        let old_rot = old_packet_object.map (|x| x.rot);
-
-                        let x = parser.read_pos(17, old_pos.map(|x| x.0));
-                        let y = parser.read_pos(17, old_pos.map(|x| x.1));
-                        let z = parser.read_pos(17, old_pos.map(|x| x.2));
-                        let r1 = parser.read_pos(31, old_rot.map(|x| x.0));
-                        let r2 = parser.read_pos(31, old_rot.map(|x| x.1));
-
-                        HQMObjectPacket::Puck(HQMPuckPacket {
-                            pos: (x, y, z),
-                            rot: (r1, r2),
-                        })
-                    } else {
-                        panic!()
-                    }
-                } else {
-                    HQMObjectPacket::None
-                };
-                new_packet.push(new_object);
-            }
+        self.set_connection_state(HQMConnectionState::Connecting);
+        if let Err(e) = self.send_join_message(socket).await {
+            eprintln!("Could not send join message: {}", e);
+        }
 
-            let game_state_objects: Vec<HQMGameStateObject> = new_packet.iter().map(|x| match x {
-                HQMObjectPacket::None => HQMGameStateObject::None,
-                HQMObjectPacket::Puck(packet) => {
-                    let pos = Point3::new(packet.pos.0 as f32 / 1024.0,
-                                          packet.pos.1 as f32 / 1024.0,
-                                          packet.pos.2 as f32 / 1024.0);
-                    let rot = hqm_parse::convert_matrix_from_network(31, packet.rot.0, packet.rot.1);
-                    HQMGameStateObject::Puck(HQMGameStatePuck {
-                        pos,
-                        rot
-                    })
-                }
-                HQMObjectPacket::Skater(packet) => {
-                    let pos = Point3::new(packet.pos.0 as f32 / 1024.0,
-                                          packet.pos.1 as f32 / 1024.0,
-                                          packet.pos.2 as f32 / 1024.0);
-                    let rot = hqm_parse::convert_matrix_from_network(31, packet.rot.0, packet.rot.1);
-                    let stick_pos = Point3::new(
-                        (packet.stick_pos.0 as f32 / 1024.0) + pos.x - 4.0,
-                        (packet.stick_pos.1 as f32 / 1024.0) + pos.y - 4.0,
-                        (packet.stick_pos.2 as f32 / 1024.0) + pos.z - 4.0
-                    );
-                    let stick_rot = hqm_parse::convert_matrix_from_network(25, packet.stick_rot.0, packet.stick_rot.1);
-                    HQMGameStateObject::Skater(HQMGameStateSkater {
-                        pos,
-                        rot,
-                        stick_pos,
-                        stick_rot,
-                        head_rot: (packet.head_rot as f32 - 16384.0) / 8192.0,
-                        body_rot: (packet.body_rot as f32 - 16384.0) / 8192.0
-                    })
+        // Only packets that decode keep the connection alive, stray datagrams don't
+        let mut deadline = Instant::now() + self.config.receive_timeout;
+        let mut backoff = self.config.initial_backoff;
+        loop {
+            let msg = tokio::select! {
+                _ = shutdown.changed() => {
+                    return HQMExitReason::Stopped;
                 }
-            }).collect();
-
-            let message_num = parser.read_bits (4);
-            let known_msg_pos = parser.read_bits(16);
-            let mut messages = vec![];
-            for i in known_msg_pos..known_msg_pos+message_num {
-                let message_type = parser.read_bits(6);
-                if message_type == 0 {
-                    // Player update
-                    let player_index = parser.read_bits(6) as usize;
-                    let is_online = parser.read_bits(1) == 1;
-                    let team = match parser.read_bits(2) {
-                        0 => Some(HQMTeam::Red),
-                        1 => Some(HQMTeam::Blue),
-                        _ => None
-                    };
-                    let object_index = match parser.read_bits(6) {
-                        63 => None,
-                        x => Some(x as usize)
-                    };
-                    let object_index = object_index.zip(team);
-                    let mut bytes = vec![];
-                    for _ in 0..31 {
-                        bytes.push(parser.read_bits(7) as u8);
-                    }
-                    if let Ok(s) = String::from_utf8(bytes) {
-                        let s = s.trim_matches(char::from(0)).to_string();
-
-                        if i >= self.known_msgpos as u32 {
-                            if is_online {
-                                self.players.insert(player_index, HQMPlayer {
-                                    name: s.clone (),
-                                    index: player_index,
-                                    object_index
-                                });
-                            } else {
-                                self.players.remove(&player_index);
-                            }
-
-                            messages.push(HQMMessage::PlayerUpdate {
-                                player_name: s,
-                                object: object_index,
-                                player_index,
-                                in_server: is_online
-                            });
-                        }
-
-                    }
-                } else if message_type == 1 {
-                    // Goal
-                    let team = match parser.read_bits(2) {
-                        0 => HQMTeam::Red,
-                        _ => HQMTeam::Blue,
-                    };
-                    let goal_player_index = match parser.read_bits(6) {
-                        63 => None,
-                        x => Some(x as usize)
-                    };
-                    let assist_player_index = match parser.read_bits(6) {
-                        63 => None,
-                        x => Some(x as usize)
-                    };
-                    if i >= self.known_msgpos as u32 {
-                        messages.push(HQMMessage::Goal {
-                            team,
-                            goal_player_index,
-                            assist_player_index
-                        });
+                msg = tokio::time::timeout_at(deadline, msg_receiver.recv()) => msg
+            };
+            match msg {
+                Ok(Some(x)) => {
+                    self.packets_received += 1;
+                    self.record(HQMReplayRecordKind::Received, x.as_ref());
+                    if self.handle_message (x.as_ref(), socket).await {
+                        deadline = Instant::now() + self.config.receive_timeout;
+                        backoff = self.config.initial_backoff;
                     }
-                } else if message_type == 2 {
-                    let player_index = match parser.read_bits(6) {
-                        63 => None,
-                        x => Some(x as usize)
-                    };
-                    let size = parser.read_bits(6);
-                    let mut bytes = vec![];
-                    for _ in 0..size {
-                        bytes.push(parser.read_bits(7) as u8);
+                }
+                Ok(None) => return HQMExitReason::ReceiveTaskEnded,
+                Err(_) => {
+                    if self.connection_state == HQMConnectionState::Joined {
+                        self.set_connection_state(HQMConnectionState::Lost);
+                        self.reset_game();
                     }
-                    if let Ok(s) = String::from_utf8(bytes) {
-                        let s = s.trim_matches(char::from(0)).to_string();
-                        if i >= self.known_msgpos as u32 {
-                            messages.push(HQMMessage::Chat {
-                                player_index,
-                                message: s
-                            });
-                        }
+                    self.set_connection_state(HQMConnectionState::Reconnecting);
+                    // The server may well be unreachable right now, so this is not fatal
+                    if let Err(e) = self.send_join_message(socket).await {
+                        eprintln!("Could not send join message: {}", e);
                     }
+                    deadline = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(self.config.max_backoff);
                 }
             }
-            let game_state = HQMGameState {
-                game_id,
-                step,
-                red_score,
-                blue_score,
-                time,
-                period,
-                game_over,
-                goal_interruption: goal_time > 0,
-                objects: game_state_objects,
-                yourself: own_player_id,
-                players: self.players.clone(),
-            };
+        }
+    }
 
-            if self.known_msgpos < (known_msg_pos+message_num) as u16{
-                self.known_msgpos = (known_msg_pos+message_num) as u16;
-            }
+    fn set_connection_state (& mut self, state: HQMConnectionState) {
+        if self.connection_state != state {
+            self.connection_state = state;
+            self.logic.connection_state_changed(state);
+        }
+    }
 
-            self.saved_packets.insert(packet & 0xff, new_packet);
+    fn reset_game (& mut self) {
+        self.tracker.reset();
+        self.record(HQMReplayRecordKind::Reset, &[]);
+        self.reset_chat();
+    }
 
-            if self.known_packet == u32::MAX || self.known_packet < packet {
-                self.known_packet = packet;
-            }
+    fn reset_chat (& mut self) {
+        self.chat_rep = 0;
+        // The new game won't know about the chat_rep it was sent with, so start over
+        if let Some(pending) = self.pending_chat.take() {
+            self.chat_outbox.push_front(pending.message);
+        }
+    }
 
-            let (new_input, new_chat) = self.logic.tick (&game_state, &messages);
-            input = new_input;
-            chat = new_chat;
-        } else if command == 6 {
-            let game = parser.read_u32_aligned();
-            if self.current_game != game {
-                self.current_game = game;
-                self.known_packet = u32::MAX;
-                self.known_msgpos = 0;
-                self.chat_rep = 0;
-                self.saved_packets.clear();
-                self.players.clear();
-                self.logic.new_game();
+    /// Writes to the replay file, if there is one. Recording stops at the first error.
+    fn record (& mut self, kind: HQMReplayRecordKind, data: &[u8]) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(kind, data) {
+                eprintln!("Could not write replay, recording stopped: {}", e);
+                self.recorder = None;
             }
-
         }
+    }
 
-        self.send_update (input, chat, socket).await
-    }
-
-    async fn send_update (& mut self, input: HQMPlayerInput, chat: Option<String>, socket: &UdpSocket) -> std::io::Result<()> {
-        let mut buf = [0u8;128];
-        let mut writer = HQMMessageWriter::new(& mut buf);
-        writer.write_bytes_aligned(GAME_HEADER);
-        writer.write_byte_aligned(4);
-        writer.write_u32_aligned(self.current_game);
-        writer.write_f32_aligned(input.stick_angle);
-        writer.write_f32_aligned(input.turn);
-        writer.write_f32_aligned(input.unknown);
-        writer.write_f32_aligned(input.fwbw);
-        writer.write_f32_aligned(input.stick[0]);
-        writer.write_f32_aligned(input.stick[1]);
-        writer.write_f32_aligned(input.head_rot);
-        writer.write_f32_aligned(input.body_rot);
-
-        writer.write_u32_aligned({
-            let mut x = 0;
-            if input.jump {
-                x |= 1;
+    /// Returns whether the message was a game packet
+    async fn handle_message (& mut self, msg: &[u8], socket: &UdpSocket) -> bool {
+        match self.handle_packet(msg) {
+            Ok((input, chat)) => {
+                self.set_connection_state(HQMConnectionState::Joined);
+                if let Some(chat) = chat {
+                    self.chat_outbox.push_back(chat);
+                }
+                if let Err(e) = self.send_update (input, socket).await {
+                    eprintln!("Could not send update: {}", e);
+                }
+                true
             }
-            if input.crouch {
-                x |= 2;
+            // Anything that isn't a game packet at all is ignored without a word
+            Err(ProtocolError::BadHeader) => false,
+            Err(e) => {
+                eprintln!("Skipping bad packet: {}", e);
+                false
             }
-            if input.join_red {
-                x |= 4;
+        }
+    }
+
+    fn handle_packet (& mut self, msg: &[u8]) -> Result<(HQMPlayerInput, Option<String>), ProtocolError> {
+        match self.tracker.handle_packet(msg)? {
+            HQMTrackerUpdate::Tick(tick) => {
+                self.check_chat_ack(tick.state.yourself, &tick.messages);
+                if !tick.events.is_empty() {
+                    self.logic.game_events(&tick.state, &tick.events);
+                }
+                Ok(self.logic.tick (&tick.state, &tick.messages))
             }
-            if input.join_blue {
-                x |= 8;
+            HQMTrackerUpdate::NewGame { started, .. } => {
+                if started {
+                    self.reset_chat();
+                    self.games_played += 1;
+                    self.logic.new_game();
+                }
+                Ok((Default::default(), None))
             }
-            if input.shift_rotate {
-                x |= 0x10;
+        }
+    }
+
+    fn check_chat_ack (& mut self, own_player_index: usize, messages: &[HQMMessage]) {
+        if let Some(pending) = self.pending_chat.as_ref().filter(|x| !x.command) {
+            let acked = messages.iter().any(|message| match message {
+                HQMMessage::Chat { player_index: Some(player_index), message } => {
+                    *player_index == own_player_index && is_chat_echo(&pending.message, message)
+                },
+                _ => false
+            });
+            if acked {
+                self.pending_chat = None;
+            }
+        }
+    }
+
+    /// Picks the chat message to send with the next update. The server ignores repeats with the same
+    /// chat_rep, so it is safe to keep sending a message until it shows up in the chat.
+    fn next_chat (& mut self) -> Option<(u32, String)> {
+        if self.pending_chat.is_none() {
+            if let Some(message) = self.chat_outbox.pop_front() {
+                let rep = self.chat_rep;
+                self.chat_rep += 1;
+                self.chat_rep &= 7;
+                self.pending_chat = Some(HQMPendingChat {
+                    rep,
+                    command: message.starts_with('/'),
+                    message,
+                    attempts: 0
+                });
             }
-            if input.spectate {
-                x |= 0x20;
+        }
+        let pending = self.pending_chat.as_mut()?;
+        pending.attempts += 1;
+        let chat = (pending.rep, pending.message.clone());
+        if pending.command && pending.attempts >= COMMAND_REPEATS {
+            self.pending_chat = None;
+        } else if pending.attempts >= MAX_CHAT_ATTEMPTS {
+            if let Some(pending) = self.pending_chat.take() {
+                self.logic.chat_dropped(&pending.message);
             }
-            x
+        }
+        Some(chat)
+    }
+
+    async fn send_update (& mut self, input: HQMPlayerInput, socket: &UdpSocket) -> std::io::Result<()> {
+        let chat = self.next_chat();
+        let packet = ClientPacket::Update(HQMClientUpdatePacket {
+            game_id: self.tracker.current_game(),
+            input,
+            known_packet: self.tracker.known_packet(),
+            known_msgpos: self.tracker.known_msgpos(),
+            chat
         });
+        self.send(&packet, socket).await
+    }
+
+    async fn send_exit_message (& mut self, socket: &UdpSocket) -> std::io::Result<()> {
+        self.send(&ClientPacket::Exit, socket).await
+    }
+
+    async fn send_join_message (& mut self, socket: &UdpSocket) -> std::io::Result<()> {
+        let packet = ClientPacket::Join {
+            version: hqm_parse::CLIENT_VERSION,
+            name: self.name.clone()
+        };
+        self.send(&packet, socket).await
+    }
+
+    async fn send (& mut self, packet: &ClientPacket, socket: &UdpSocket) -> std::io::Result<()> {
+        let data = hqm_parse::encode_client_packet(packet);
+        self.record(HQMReplayRecordKind::Sent, &data);
+        socket.send(&data).await?;
+        Ok(())
+    }
+}
+
+/// Whether `echo` is how the server shows `message` in the chat: at most 63 characters,
+/// sent as 7-bit characters
+pub(crate) fn is_chat_echo(message: &str, echo: &str) -> bool {
+    let mut expected = hqm_parse::to_wire_string(message);
+    expected.truncate(63);
+    echo.as_bytes() == expected.as_slice()
+}
+
+/// Helpers for running sessions in tests, without a game server
+#[cfg(test)]
+pub(crate) mod testing {
+    use crate::hqm_game::HQMMessage;
+    use crate::hqm_parse::{self, ClientPacket, DeltaBaselineCache, HQMGameUpdatePacket, HQMObjectPacket, ServerPacket};
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use tokio::task::JoinHandle;
+
+    /// Polls `condition` until it holds, failing the test if it takes longer than `timeout`
+    pub async fn wait_until<F: FnMut() -> bool>(timeout: Duration, what: &str, mut condition: F) {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !condition() {
+            assert!(tokio::time::Instant::now() < deadline, "timed out waiting for {}", what);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    /// Answers every datagram it receives with the next of its packets, and goes silent
+    /// once they run out
+    pub struct ScriptedServer {
+        addr: SocketAddr,
+        received: Arc<Mutex<Vec<ClientPacket>>>,
+        task: JoinHandle<()>,
+    }
+
+    impl ScriptedServer {
+        pub async fn start<I>(packets: I) -> io::Result<ScriptedServer>
+            where I: IntoIterator<Item = Vec<u8>>, I::IntoIter: Send + 'static {
+            Self::start_on(SocketAddr::from(([127, 0, 0, 1], 0)), packets).await
+        }
+
+        /// Starts on `addr`, for example where a stopped server used to be
+        pub async fn start_on<I>(addr: SocketAddr, packets: I) -> io::Result<ScriptedServer>
+            where I: IntoIterator<Item = Vec<u8>>, I::IntoIter: Send + 'static {
+            let socket = UdpSocket::bind(addr).await?;
+            let addr = socket.local_addr()?;
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let mut packets = packets.into_iter();
+            let task = {
+                let received = received.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 2048];
+                    while let Ok((size, from)) = socket.recv_from(&mut buf).await {
+                        if let Ok(packet) = hqm_parse::decode_client_packet(&buf[..size]) {
+                            received.lock().unwrap().push(packet);
+                        }
+                        if let Some(packet) = packets.next() {
+                            let _ = socket.send_to(&packet, from).await;
+                        }
+                    }
+                })
+            };
+            Ok(ScriptedServer { addr, received, task })
+        }
 
+        pub fn addr(&self) -> SocketAddr {
+            self.addr
+        }
 
-        writer.write_u32_aligned(self.known_packet);
-        writer.write_u16_aligned (self.known_msgpos);
-        if let Some(chat) = chat {
-            let chat_rep = self.chat_rep;
-            self.chat_rep += 1;
-            self.chat_rep &= 7;
-            writer.write_bits(1, 1);
-            writer.write_bits(3, chat_rep);
-            let bytes = chat.into_bytes();
-            let size = bytes.len().min(255);
-            writer.write_bits(8, size as u32);
-            writer.write_bytes_aligned(&bytes[0..size])
-        } else {
-            writer.write_bits(1, 0);
+        /// Every client packet received so far
+        pub fn received(&self) -> Vec<ClientPacket> {
+            self.received.lock().unwrap().clone()
         }
 
+        /// Closes the socket
+        pub async fn stop(self) {
+            self.task.abort();
+            let _ = self.task.await;
+        }
+    }
 
-        let bytes_written = writer.get_bytes_written();
+    pub fn new_game(game_id: u32) -> Vec<u8> {
+        hqm_parse::encode_server_packet(&ServerPacket::NewGame { game_id }, &DeltaBaselineCache::new())
+    }
 
-        let slice = &buf[0..bytes_written];
-        socket.send(slice).await?;
-        Ok(())
+    /// A game update for player 0 in the first period, without delta compression
+    pub fn game_update(game_id: u32, packet: u32, objects: Vec<HQMObjectPacket>, messages: Vec<HQMMessage>) -> Vec<u8> {
+        hqm_parse::encode_server_packet(&ServerPacket::GameUpdate(HQMGameUpdatePacket {
+            game_id,
+            step: packet,
+            game_over: false,
+            red_score: 0,
+            blue_score: 0,
+            time: 30000,
+            goal_time: 0,
+            period: 1,
+            own_player_index: 0,
+            packet,
+            known_packet: u32::MAX,
+            objects,
+            message_pos: 0,
+            messages
+        }), &DeltaBaselineCache::new())
+    }
+
+    /// A new game followed by empty game updates, for as long as they are asked for
+    pub fn empty_game(game_id: u32) -> impl Iterator<Item = Vec<u8>> {
+        std::iter::once(new_game(game_id)).chain((0..).map(move |packet| game_update(game_id, packet, Vec::new(), Vec::new())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::{empty_game, wait_until, ScriptedServer};
+    use std::sync::Mutex;
 
+    /// Joins red and writes down every connection state it goes through
+    struct Watcher {
+        states: Arc<Mutex<Vec<HQMConnectionState>>>,
     }
 
-    async fn send_join_message (& self, socket: &UdpSocket) -> std::io::Result<()> {
-        let mut buf = [0u8;64];
-        let mut writer = HQMMessageWriter::new(& mut buf);
-        writer.write_bytes_aligned(GAME_HEADER);
-        writer.write_byte_aligned(2);
-        writer.write_byte_aligned(55);
-        let mut name_bytes = Vec::from(self.name.as_bytes());
-        name_bytes.truncate(32);
-        while name_bytes.len() < 32 {
-            name_bytes.push(0);
+    impl HQMBotLogic for Watcher {
+        fn new_game(&mut self) {}
+
+        fn tick(&mut self, state: &HQMGameState, _messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+            (HQMPlayerInput { join_red: state.me().is_none(), ..Default::default() }, None)
         }
-        writer.write_bytes_aligned(&name_bytes);
-        let bytes_written = writer.get_bytes_written();
 
-        let slice = &buf[0..bytes_written];
-        socket.send(slice).await?;
-        Ok(())
+        fn connection_state_changed(&mut self, state: HQMConnectionState) {
+            self.states.lock().unwrap().push(state);
+        }
+    }
+
+    /// Says nothing by itself, chat is queued directly on the session
+    #[derive(Default)]
+    struct Quiet {
+        dropped: Vec<String>,
+    }
+
+    impl HQMBotLogic for Quiet {
+        fn new_game(&mut self) {}
+
+        fn tick(&mut self, _state: &HQMGameState, _messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+            (HQMPlayerInput::default(), None)
+        }
+
+        fn chat_dropped(&mut self, message: &str) {
+            self.dropped.push(message.to_owned());
+        }
+    }
+
+    fn echo(player_index: usize, message: &str) -> Vec<HQMMessage> {
+        vec![HQMMessage::Chat { player_index: Some(player_index), message: message.to_owned() }]
+    }
+
+    #[test]
+    fn chat_is_repeated_until_echoed() {
+        let mut session = HQMBotSession::new("Bot".to_owned(), Quiet::default());
+        assert_eq!(session.next_chat(), None);
+        session.chat_outbox.push_back("first".to_owned());
+        session.chat_outbox.push_back("second".to_owned());
+        for _ in 0..5 {
+            assert_eq!(session.next_chat(), Some((0, "first".to_owned())));
+        }
+        // Someone else saying the same thing, or another message of ours, is not an echo
+        session.check_chat_ack(2, &echo(3, "first"));
+        session.check_chat_ack(2, &echo(2, "firs"));
+        assert_eq!(session.next_chat(), Some((0, "first".to_owned())));
+
+        session.check_chat_ack(2, &echo(2, "first"));
+        assert_eq!(session.next_chat(), Some((1, "second".to_owned())));
+        session.check_chat_ack(2, &echo(2, "second"));
+        assert_eq!(session.next_chat(), None);
+
+        // Long messages are echoed cut short
+        let long = "x".repeat(80);
+        session.chat_outbox.push_back(long.clone());
+        assert_eq!(session.next_chat(), Some((2, long)));
+        session.check_chat_ack(2, &echo(2, &"x".repeat(63)));
+        assert_eq!(session.next_chat(), None);
+
+        // So are characters that don't fit in 7 bits
+        session.chat_outbox.push_back("Hej då".to_owned());
+        assert_eq!(session.next_chat(), Some((3, "Hej då".to_owned())));
+        session.check_chat_ack(2, &echo(2, "Hej d?"));
+        assert_eq!(session.next_chat(), None);
+        assert!(session.logic.dropped.is_empty());
+    }
+
+    #[test]
+    fn commands_do_not_wait_for_an_echo() {
+        let mut session = HQMBotSession::new("Bot".to_owned(), Quiet::default());
+        session.chat_outbox.push_back("/login secret".to_owned());
+        session.chat_outbox.push_back("hi".to_owned());
+        for _ in 0..COMMAND_REPEATS {
+            assert_eq!(session.next_chat(), Some((0, "/login secret".to_owned())));
+        }
+        assert_eq!(session.next_chat(), Some((1, "hi".to_owned())));
+        assert!(session.logic.dropped.is_empty());
+    }
+
+    #[test]
+    fn unechoed_chat_is_dropped_and_reported() {
+        let mut session = HQMBotSession::new("Bot".to_owned(), Quiet::default());
+        session.chat_outbox.push_back("muted".to_owned());
+        session.chat_outbox.push_back("next".to_owned());
+        for _ in 0..MAX_CHAT_ATTEMPTS {
+            assert_eq!(session.next_chat(), Some((0, "muted".to_owned())));
+        }
+        assert_eq!(session.logic.dropped, vec!["muted".to_owned()]);
+        assert_eq!(session.next_chat(), Some((1, "next".to_owned())));
+
+        // A new game starts the pending message over
+        session.reset_chat();
+        assert_eq!(session.next_chat(), Some((0, "next".to_owned())));
+    }
+
+    fn fast_reconnect() -> HQMSessionConfig {
+        HQMSessionConfig {
+            receive_timeout: Duration::from_millis(100),
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(40),
+            ..Default::default()
+        }
+    }
+
+    fn start_watcher(server: SocketAddr) -> (Arc<Mutex<Vec<HQMConnectionState>>>, HQMShutdownHandle, tokio::task::JoinHandle<HQMSessionSummary>) {
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let mut session = HQMBotSession::with_config("Watcher".to_owned(), Watcher { states: states.clone() }, fast_reconnect());
+        let shutdown = session.shutdown_handle();
+        let task = tokio::spawn(async move { session.start(server).await.unwrap() });
+        (states, shutdown, task)
+    }
+
+    #[tokio::test]
+    async fn session_reconnects_after_server_restart() {
+        let timeout = Duration::from_secs(5);
+        let server = ScriptedServer::start(empty_game(1)).await.unwrap();
+        let addr = server.addr();
+        let (states, shutdown, task) = start_watcher(addr);
+        wait_until(timeout, "join", || states.lock().unwrap().contains(&HQMConnectionState::Joined)).await;
+
+        server.stop().await;
+        wait_until(timeout, "reconnect", || states.lock().unwrap().contains(&HQMConnectionState::Reconnecting)).await;
+
+        let server = ScriptedServer::start_on(addr, empty_game(1)).await.unwrap();
+        wait_until(timeout, "rejoin", || states.lock().unwrap().len() == 4).await;
+        assert_eq!(*states.lock().unwrap(), vec![
+            HQMConnectionState::Joined,
+            HQMConnectionState::Lost,
+            HQMConnectionState::Reconnecting,
+            HQMConnectionState::Joined
+        ]);
+        wait_until(timeout, "the bot to play again", || {
+            server.received().iter().any(|x| matches!(x, ClientPacket::Update(_)))
+        }).await;
+
+        shutdown.stop();
+        assert_eq!(task.await.unwrap().exit_reason, HQMExitReason::Stopped);
+        server.stop().await;
+    }
 
+    #[tokio::test]
+    async fn garbage_does_not_keep_connection_alive() {
+        // Answers everything with a datagram that isn't a game packet
+        let socket = tokio::net::UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let echo = tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((_, from)) = socket.recv_from(&mut buf).await {
+                for _ in 0..20 {
+                    let _ = socket.send_to(b"junk", from).await;
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        });
+        let (states, shutdown, task) = start_watcher(addr);
+        wait_until(Duration::from_secs(5), "reconnect", || !states.lock().unwrap().is_empty()).await;
+        // Never joined, so it goes straight from connecting to reconnecting
+        assert_eq!(states.lock().unwrap()[0], HQMConnectionState::Reconnecting);
+
+        shutdown.stop();
+        let summary = task.await.unwrap();
+        assert!(summary.packets_received > 0);
+        echo.abort();
     }
-}
\ No newline at end of file
+}
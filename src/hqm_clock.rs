@@ -0,0 +1,111 @@
+use std::fmt;
+use std::time::Duration;
+
+pub const STEPS_PER_SECOND: u32 = 100;
+pub const REGULATION_PERIODS: u32 = 3;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HQMPeriodKind {
+    Warmup,
+    /// Regulation period, starting at 1
+    Regulation(u32),
+    /// Overtime period, starting at 1
+    Overtime(u32),
+}
+
+/// The game clock as sent by the server. All values are in steps, there are 100 steps per second.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct GameClock {
+    /// Steps left of the current period
+    pub time: u32,
+    pub period: u32,
+    /// Steps left of the break after a goal, zero while the game is running
+    pub goal_time: u32,
+}
+
+impl GameClock {
+    pub fn new(time: u32, period: u32, goal_time: u32) -> Self {
+        GameClock { time, period, goal_time }
+    }
+
+    pub fn period_kind(&self) -> HQMPeriodKind {
+        match self.period {
+            0 => HQMPeriodKind::Warmup,
+            p if p <= REGULATION_PERIODS => HQMPeriodKind::Regulation(p),
+            p => HQMPeriodKind::Overtime(p - REGULATION_PERIODS),
+        }
+    }
+
+    pub fn remaining(&self) -> Duration {
+        steps_to_duration(self.time)
+    }
+
+    /// Time remaining as shown on a game clock, rounded up to whole seconds
+    pub fn minutes_seconds(&self) -> (u32, u32) {
+        let seconds = self.time.div_ceil(STEPS_PER_SECOND);
+        (seconds / 60, seconds % 60)
+    }
+
+    pub fn in_goal_break(&self) -> bool {
+        self.goal_time > 0
+    }
+
+    /// Time left of the goal celebration, None while the game is running
+    pub fn goal_break_remaining(&self) -> Option<Duration> {
+        if self.in_goal_break() {
+            Some(steps_to_duration(self.goal_time))
+        } else {
+            None
+        }
+    }
+
+    /// Predicted wall-clock time until the period ends. The clock is stopped during
+    /// the goal break, so whatever is left of it is added to the time remaining.
+    pub fn until_period_end(&self) -> Duration {
+        steps_to_duration(self.time + self.goal_time)
+    }
+}
+
+impl fmt::Display for GameClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (minutes, seconds) = self.minutes_seconds();
+        match self.period_kind() {
+            HQMPeriodKind::Warmup => write!(f, "Warmup {}:{:02}", minutes, seconds),
+            HQMPeriodKind::Regulation(p) => write!(f, "P{} {}:{:02}", p, minutes, seconds),
+            HQMPeriodKind::Overtime(p) => write!(f, "OT{} {}:{:02}", p, minutes, seconds),
+        }
+    }
+}
+
+fn steps_to_duration(steps: u32) -> Duration {
+    Duration::from_millis(steps as u64 * 1000 / STEPS_PER_SECOND as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_display_and_periods() {
+        let clock = GameClock::new(30000, 1, 0);
+        assert_eq!(clock.minutes_seconds(), (5, 0));
+        assert_eq!(clock.to_string(), "P1 5:00");
+        assert_eq!(GameClock::new(6001, 3, 0).to_string(), "P3 1:01");
+        assert_eq!(GameClock::new(0, 3, 0).minutes_seconds(), (0, 0));
+        assert_eq!(GameClock::new(1999, 0, 0).to_string(), "Warmup 0:20");
+        assert_eq!(GameClock::new(50, 4, 0).period_kind(), HQMPeriodKind::Overtime(1));
+        assert_eq!(GameClock::new(50, 4, 0).to_string(), "OT1 0:01");
+    }
+
+    #[test]
+    fn goal_break_pauses_the_clock() {
+        let running = GameClock::new(12345, 2, 0);
+        assert!(!running.in_goal_break());
+        assert_eq!(running.goal_break_remaining(), None);
+        assert_eq!(running.until_period_end(), Duration::from_millis(123450));
+
+        let celebrating = GameClock::new(12345, 2, 650);
+        assert_eq!(celebrating.goal_break_remaining(), Some(Duration::from_millis(6500)));
+        assert_eq!(celebrating.until_period_end(), Duration::from_millis(129950));
+    }
+}
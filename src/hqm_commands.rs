@@ -0,0 +1,441 @@
+use crate::hqm_bot::{is_chat_echo, HQMBotLogic, HQMConnectionState};
+use crate::hqm_events::GameEvent;
+use crate::hqm_game::{HQMGameState, HQMMessage, HQMPlayer, HQMPlayerInput, HQMTeam};
+use std::collections::{HashSet, VecDeque};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HQMCommandArgType {
+    /// A player in the server, by name, unique name prefix or player index
+    Player,
+    Team,
+    Integer,
+    Number,
+    Word,
+    /// The rest of the message, only valid as the last argument
+    Text,
+}
+
+impl HQMCommandArgType {
+    fn usage(self) -> &'static str {
+        match self {
+            HQMCommandArgType::Player => "<player>",
+            HQMCommandArgType::Team => "<red|blue>",
+            HQMCommandArgType::Integer => "<integer>",
+            HQMCommandArgType::Number => "<number>",
+            HQMCommandArgType::Word => "<word>",
+            HQMCommandArgType::Text => "<text>",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HQMCommandArg {
+    Player(usize),
+    Team(HQMTeam),
+    Integer(i64),
+    Number(f32),
+    Word(String),
+    Text(String),
+}
+
+pub struct HQMCommandContext<'a> {
+    pub state: &'a HQMGameState,
+    pub sender: &'a HQMPlayer,
+    pub args: Vec<HQMCommandArg>,
+}
+
+impl<'a> HQMCommandContext<'a> {
+    pub fn player(&self, i: usize) -> Option<&'a HQMPlayer> {
+        match self.args.get(i) {
+            Some(HQMCommandArg::Player(index)) => self.state.players.get(index),
+            _ => None
+        }
+    }
+
+    pub fn team(&self, i: usize) -> Option<HQMTeam> {
+        match self.args.get(i) {
+            Some(HQMCommandArg::Team(team)) => Some(*team),
+            _ => None
+        }
+    }
+
+    pub fn integer(&self, i: usize) -> Option<i64> {
+        match self.args.get(i) {
+            Some(HQMCommandArg::Integer(x)) => Some(*x),
+            _ => None
+        }
+    }
+
+    pub fn number(&self, i: usize) -> Option<f32> {
+        match self.args.get(i) {
+            Some(HQMCommandArg::Number(x)) => Some(*x),
+            _ => None
+        }
+    }
+
+    pub fn text(&self, i: usize) -> Option<&str> {
+        match self.args.get(i) {
+            Some(HQMCommandArg::Word(s)) | Some(HQMCommandArg::Text(s)) => Some(s.as_str()),
+            _ => None
+        }
+    }
+}
+
+type HQMCommandHandler<T> = Box<dyn FnMut(&mut T, &HQMCommandContext) -> Option<String> + Send>;
+
+struct HQMCommand<T> {
+    name: String,
+    args: Vec<HQMCommandArgType>,
+    handler: HQMCommandHandler<T>,
+}
+
+impl<T> HQMCommand<T> {
+    fn usage(&self, prefix: char) -> String {
+        let mut usage = format!("Usage: {}{}", prefix, self.name);
+        for arg in self.args.iter() {
+            usage.push(' ');
+            usage.push_str(arg.usage());
+        }
+        usage
+    }
+}
+
+/// Wraps a `HQMBotLogic` and handles chat commands like `!follow <player>` before the
+/// wrapped logic gets to see the tick. Replies from command handlers are sent whenever
+/// the wrapped logic has nothing to say itself. Replies the server never echoed are
+/// dropped quietly, the wrapped logic only hears about its own dropped chat.
+pub struct HQMCommandBot<T: HQMBotLogic> {
+    logic: T,
+    prefix: char,
+    commands: Vec<HQMCommand<T>>,
+    allowed_players: Option<HashSet<String>>,
+    replies: VecDeque<String>,
+    /// Replies given to the session that haven't been echoed or dropped yet
+    sent_replies: VecDeque<String>,
+}
+
+impl<T: HQMBotLogic> HQMCommandBot<T> {
+    pub fn new(logic: T) -> Self {
+        HQMCommandBot {
+            logic,
+            prefix: '!',
+            commands: Vec::new(),
+            allowed_players: None,
+            replies: VecDeque::new(),
+            sent_replies: VecDeque::new()
+        }
+    }
+
+    pub fn set_prefix(&mut self, prefix: char) {
+        self.prefix = prefix;
+    }
+
+    /// Only accept commands from players with one of these names
+    pub fn restrict_to<I, S>(&mut self, names: I)
+        where I: IntoIterator<Item = S>, S: Into<String> {
+        self.allowed_players = Some(names.into_iter().map(|x| x.into()).collect());
+    }
+
+    /// Registers a command. `Text` may only be used as the last argument.
+    pub fn register<F>(&mut self, name: &str, args: &[HQMCommandArgType], handler: F)
+        where F: FnMut(&mut T, &HQMCommandContext) -> Option<String> + Send + 'static {
+        let text_pos = args.iter().position(|x| *x == HQMCommandArgType::Text);
+        assert!(text_pos.is_none() || text_pos == Some(args.len() - 1), "Text must be the last argument");
+        self.commands.push(HQMCommand {
+            name: name.to_lowercase(),
+            args: args.to_vec(),
+            handler: Box::new(handler)
+        });
+    }
+
+    pub fn logic(&self) -> &T {
+        &self.logic
+    }
+
+    pub fn logic_mut(&mut self) -> &mut T {
+        &mut self.logic
+    }
+
+    fn handle_chat(&mut self, state: &HQMGameState, player_index: usize, message: &str) {
+        let sender = match state.players.get(&player_index) {
+            Some(sender) => sender,
+            None => return
+        };
+        if let Some(allowed_players) = &self.allowed_players {
+            if !allowed_players.contains(&sender.name) {
+                return;
+            }
+        }
+        let line = match message.trim().strip_prefix(self.prefix) {
+            Some(line) => line,
+            None => return
+        };
+        let (name, rest) = split_word(line);
+        let name = name.to_lowercase();
+        let command = match self.commands.iter_mut().find(|x| x.name == name) {
+            Some(command) => command,
+            None => return
+        };
+        let reply = match parse_args(&command.args, rest, state) {
+            Ok(args) => {
+                let context = HQMCommandContext {
+                    state,
+                    sender,
+                    args
+                };
+                (command.handler)(&mut self.logic, &context)
+            }
+            Err(HQMArgError::Usage) => Some(command.usage(self.prefix)),
+            Err(HQMArgError::Invalid(e)) => Some(e)
+        };
+        if let Some(reply) = reply {
+            self.replies.push_back(reply);
+        }
+    }
+
+    /// Removes the oldest sent reply that matches, returning whether there was one
+    fn forget_reply<F: Fn(&str) -> bool>(&mut self, matches: F) -> bool {
+        match self.sent_replies.iter().position(|reply| matches(reply)) {
+            Some(pos) => {
+                self.sent_replies.remove(pos);
+                true
+            }
+            None => false
+        }
+    }
+}
+
+impl<T: HQMBotLogic> HQMBotLogic for HQMCommandBot<T> {
+    fn new_game(&mut self) {
+        self.replies.clear();
+        self.sent_replies.clear();
+        self.logic.new_game();
+    }
+
+    fn tick(&mut self, state: &HQMGameState, messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+        for message in messages {
+            if let HQMMessage::Chat { player_index: Some(player_index), message } = message {
+                if *player_index == state.yourself {
+                    self.forget_reply(|reply| is_chat_echo(reply, message));
+                } else {
+                    self.handle_chat(state, *player_index, message);
+                }
+            }
+        }
+        let (input, chat) = self.logic.tick(state, messages);
+        let chat = chat.or_else(|| {
+            let reply = self.replies.pop_front()?;
+            // Server commands are never echoed, so they are never dropped either
+            if !reply.starts_with('/') {
+                self.sent_replies.push_back(reply.clone());
+            }
+            Some(reply)
+        });
+        (input, chat)
+    }
+
+    fn connection_state_changed(&mut self, state: HQMConnectionState) {
+        self.logic.connection_state_changed(state);
+    }
+
+    fn game_events(&mut self, state: &HQMGameState, events: &[GameEvent]) {
+        self.logic.game_events(state, events);
+    }
+
+    fn chat_dropped(&mut self, message: &str) {
+        if !self.forget_reply(|reply| reply == message) {
+            self.logic.chat_dropped(message);
+        }
+    }
+}
+
+enum HQMArgError {
+    Usage,
+    Invalid(String),
+}
+
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(pos) => (&s[0..pos], s[pos..].trim_start()),
+        None => (s, "")
+    }
+}
+
+fn parse_args(types: &[HQMCommandArgType], mut rest: &str, state: &HQMGameState) -> Result<Vec<HQMCommandArg>, HQMArgError> {
+    let mut args = Vec::with_capacity(types.len());
+    for arg_type in types {
+        if *arg_type == HQMCommandArgType::Text {
+            if rest.is_empty() {
+                return Err(HQMArgError::Usage);
+            }
+            args.push(HQMCommandArg::Text(rest.to_owned()));
+            rest = "";
+            continue;
+        }
+        let (word, remaining) = split_word(rest);
+        if word.is_empty() {
+            return Err(HQMArgError::Usage);
+        }
+        rest = remaining;
+        let arg = match arg_type {
+            HQMCommandArgType::Player => HQMCommandArg::Player(resolve_player(word, state)?),
+            HQMCommandArgType::Team => match word.to_lowercase().as_str() {
+                "red" | "r" => HQMCommandArg::Team(HQMTeam::Red),
+                "blue" | "b" => HQMCommandArg::Team(HQMTeam::Blue),
+                _ => return Err(HQMArgError::Usage)
+            },
+            HQMCommandArgType::Integer => HQMCommandArg::Integer(word.parse().map_err(|_| HQMArgError::Usage)?),
+            HQMCommandArgType::Number => HQMCommandArg::Number(word.parse().map_err(|_| HQMArgError::Usage)?),
+            HQMCommandArgType::Word | HQMCommandArgType::Text => HQMCommandArg::Word(word.to_owned()),
+        };
+        args.push(arg);
+    }
+    if !rest.is_empty() {
+        return Err(HQMArgError::Usage);
+    }
+    Ok(args)
+}
+
+/// Resolves a player by exact name, then by unique name prefix, ignoring case.
+/// A plain number is taken as a player index.
+fn resolve_player(word: &str, state: &HQMGameState) -> Result<usize, HQMArgError> {
+    let lower = word.to_lowercase();
+    if let Some(player) = state.players.values().find(|x| x.name.to_lowercase() == lower) {
+        return Ok(player.index);
+    }
+    let mut matches: Vec<&HQMPlayer> = state.players.values()
+        .filter(|x| x.name.to_lowercase().starts_with(&lower))
+        .collect();
+    match matches.len() {
+        1 => Ok(matches[0].index),
+        0 => match word.parse::<usize>() {
+            Ok(index) if state.players.contains_key(&index) => Ok(index),
+            _ => Err(HQMArgError::Invalid(format!("No player named {}", word)))
+        },
+        _ => {
+            matches.sort_by_key(|x| x.index);
+            let names: Vec<&str> = matches.iter().map(|x| x.name.as_str()).collect();
+            Err(HQMArgError::Invalid(format!("{} could be any of {}", word, names.join(", "))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::hqm_game::testing::HQMGameStateBuilder;
+
+    #[derive(Default)]
+    struct RecordingBot {
+        followed: Option<usize>,
+        team: Option<HQMTeam>,
+        dropped: Vec<String>,
+    }
+
+    impl HQMBotLogic for RecordingBot {
+        fn new_game(&mut self) {}
+
+        fn tick(&mut self, _state: &HQMGameState, _messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+            (HQMPlayerInput::default(), None)
+        }
+
+        fn chat_dropped(&mut self, message: &str) {
+            self.dropped.push(message.to_owned());
+        }
+    }
+
+    fn state_with_players(names: &[&str]) -> HQMGameState {
+        names.iter().enumerate()
+            .fold(HQMGameStateBuilder::new(), |builder, (index, name)| builder.player(index, name, None))
+            .build()
+    }
+
+    fn chat(player_index: usize, message: &str) -> HQMMessage {
+        HQMMessage::Chat {
+            player_index: Some(player_index),
+            message: message.to_owned()
+        }
+    }
+
+    fn command_bot() -> HQMCommandBot<RecordingBot> {
+        let mut bot = HQMCommandBot::new(RecordingBot::default());
+        bot.register("follow", &[HQMCommandArgType::Player], |logic, context| {
+            let player = context.player(0)?;
+            logic.followed = Some(player.index);
+            Some(format!("Following {}", player.name))
+        });
+        bot.register("team", &[HQMCommandArgType::Team], |logic, context| {
+            logic.team = context.team(0);
+            None
+        });
+        bot
+    }
+
+    #[test]
+    fn resolves_players_by_prefix_and_replies() {
+        let state = state_with_players(&["Bot", "Alice", "Albert", "Bob"]);
+        let mut bot = command_bot();
+
+        let (_, reply) = bot.tick(&state, &[chat(1, "!follow bo")]);
+        assert_eq!(bot.logic().followed, None);
+        assert_eq!(reply.as_deref(), Some("bo could be any of Bot, Bob"));
+
+        let (_, reply) = bot.tick(&state, &[chat(1, "!FOLLOW alb")]);
+        assert_eq!(bot.logic().followed, Some(2));
+        assert_eq!(reply.as_deref(), Some("Following Albert"));
+
+        let (_, reply) = bot.tick(&state, &[chat(1, "!follow")]);
+        assert_eq!(reply.as_deref(), Some("Usage: !follow <player>"));
+
+        let (_, reply) = bot.tick(&state, &[chat(1, "!team blue")]);
+        assert_eq!(bot.logic().team, Some(HQMTeam::Blue));
+        assert_eq!(reply, None);
+    }
+
+    #[test]
+    fn ignores_unknown_and_restricted_senders() {
+        let state = state_with_players(&["Bot", "Alice", "Mallory"]);
+        let mut bot = command_bot();
+        bot.restrict_to(vec!["Alice"]);
+
+        let calls = Arc::new(Mutex::new(0));
+        {
+            let calls = calls.clone();
+            bot.register("ping", &[HQMCommandArgType::Text], move |_, context| {
+                *calls.lock().unwrap() += 1;
+                Some(context.text(0)?.to_owned())
+            });
+        }
+
+        let (_, reply) = bot.tick(&state, &[chat(2, "!ping hello there")]);
+        assert_eq!(reply, None);
+        let (_, reply) = bot.tick(&state, &[chat(0, "!ping from myself")]);
+        assert_eq!(reply, None);
+        let (_, reply) = bot.tick(&state, &[chat(1, "!unknown")]);
+        assert_eq!(reply, None);
+        let (_, reply) = bot.tick(&state, &[chat(1, "!ping hello there")]);
+        assert_eq!(reply.as_deref(), Some("hello there"));
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn only_drops_of_the_wrapped_logic_are_forwarded() {
+        let state = state_with_players(&["Bot", "Alice", "Albert"]);
+        let mut bot = command_bot();
+
+        let (_, reply) = bot.tick(&state, &[chat(1, "!follow alice")]);
+        assert_eq!(reply.as_deref(), Some("Following Alice"));
+        bot.chat_dropped("Following Alice");
+        assert!(bot.logic().dropped.is_empty());
+
+        // Once echoed, the reply is no longer the command bot's to drop
+        let (_, reply) = bot.tick(&state, &[chat(1, "!follow albert")]);
+        assert_eq!(reply.as_deref(), Some("Following Albert"));
+        bot.tick(&state, &[chat(0, "Following Albert")]);
+        bot.chat_dropped("Following Albert");
+        bot.chat_dropped("Something else");
+        assert_eq!(bot.logic().dropped, vec!["Following Albert", "Something else"]);
+    }
+}
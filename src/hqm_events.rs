@@ -0,0 +1,374 @@
+use crate::hqm_game::{HQMGameState, HQMTeam};
+use crate::hqm_possession::HQMPuckTouch;
+use crate::hqm_predict;
+use nalgebra::Point3;
+use std::collections::HashMap;
+
+/// How far ahead the puck path is predicted when looking for shots on goal
+const SHOT_PREDICTION_STEPS: usize = 200;
+/// Updates in a row the prediction has to agree on before a shot starts or ends,
+/// so noise from one update to the next doesn't make shots come and go
+const SHOT_DEBOUNCE_UPDATES: u32 = 3;
+
+/// Events derived by comparing consecutive game states, as opposed to the
+/// messages sent by the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameEvent {
+    /// The puck changed holder. `None` means the puck is loose.
+    PossessionChanged {
+        previous: Option<HQMPuckTouch>,
+        current: Option<HQMPuckTouch>,
+    },
+    PeriodStart {
+        period: u32,
+    },
+    PeriodEnd {
+        period: u32,
+    },
+    /// Play resumes after a goal
+    Faceoff,
+    GameOver {
+        red_score: u32,
+        blue_score: u32,
+    },
+    /// The puck is predicted to go in for `team` within `steps` steps.
+    /// `shooter` is the last player who touched the puck.
+    ShotOnGoal {
+        team: HQMTeam,
+        shooter: Option<HQMPuckTouch>,
+        steps: usize,
+    },
+    /// A shot on the net defended by `team` was stopped, by a defending player touching the puck
+    /// or the puck turning back from the net. `player` is the defending player who touched the
+    /// puck last, if the puck was stopped by a stick. Shots that just stop going in, for example
+    /// off the post, are not saves.
+    Save {
+        team: HQMTeam,
+        player: Option<HQMPuckTouch>,
+    },
+    PuckLeftRink {
+        pos: Point3<f32>,
+    },
+    PlayerJoinedTeam {
+        player_index: usize,
+        team: HQMTeam,
+    },
+    PlayerLeftTeam {
+        player_index: usize,
+        team: HQMTeam,
+    },
+}
+
+#[derive(Debug)]
+struct HQMEventSnapshot {
+    step: u32,
+    period: u32,
+    goal_interruption: bool,
+    game_over: bool,
+    puck_in_rink: Option<bool>,
+    teams: HashMap<usize, HQMTeam>,
+    last_touch: Option<HQMPuckTouch>,
+}
+
+/// A shot the puck is currently predicted to go in from
+#[derive(Debug, Copy, Clone)]
+struct HQMPendingShot {
+    team: HQMTeam,
+    /// Last touch when the shot was seen
+    touch_at_shot: Option<HQMPuckTouch>,
+    /// Sign of the puck's z velocity towards the net
+    direction: f32,
+}
+
+/// Detects `GameEvent`s between consecutive game states. The first state seen is only
+/// used as a baseline, and states older than the latest one seen are ignored.
+#[derive(Debug, Default)]
+pub struct HQMEventDetector {
+    previous: Option<HQMEventSnapshot>,
+    pending_shot: Option<HQMPendingShot>,
+    /// Updates in a row the prediction has disagreed with `pending_shot`
+    shot_streak: u32,
+}
+
+impl HQMEventDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Events since the last state. `state.possession` should already be up to date.
+    pub fn update(&mut self, state: &HQMGameState) -> Vec<GameEvent> {
+        let snapshot = snapshot(state);
+        let mut events = vec![];
+        let previous = match self.previous.take() {
+            Some(previous) if previous.step >= state.step => {
+                self.previous = Some(previous);
+                return events;
+            }
+            Some(previous) => previous,
+            None => {
+                self.pending_shot = predicted_goal(state).map(|(_, team)| pending_shot(state, team, &snapshot));
+                self.previous = Some(snapshot);
+                return events;
+            }
+        };
+
+        if previous.period != snapshot.period {
+            events.push(GameEvent::PeriodEnd { period: previous.period });
+            events.push(GameEvent::PeriodStart { period: snapshot.period });
+        }
+        if previous.goal_interruption && !snapshot.goal_interruption {
+            events.push(GameEvent::Faceoff);
+        }
+        if !previous.game_over && snapshot.game_over {
+            events.push(GameEvent::GameOver {
+                red_score: state.red_score,
+                blue_score: state.blue_score
+            });
+        }
+
+        self.detect_shots(state, &snapshot, &mut events);
+
+        if let (Some(true), Some(false)) = (previous.puck_in_rink, snapshot.puck_in_rink) {
+            if let Some(puck) = state.puck() {
+                events.push(GameEvent::PuckLeftRink { pos: puck.pos });
+            }
+        }
+
+        let mut player_indices: Vec<usize> = previous.teams.keys().chain(snapshot.teams.keys()).copied().collect();
+        player_indices.sort_unstable();
+        player_indices.dedup();
+        for player_index in player_indices {
+            let old = previous.teams.get(&player_index);
+            let new = snapshot.teams.get(&player_index);
+            if old == new {
+                continue;
+            }
+            if let Some(&team) = old {
+                events.push(GameEvent::PlayerLeftTeam { player_index, team });
+            }
+            if let Some(&team) = new {
+                events.push(GameEvent::PlayerJoinedTeam { player_index, team });
+            }
+        }
+
+        self.previous = Some(snapshot);
+        events
+    }
+
+    fn detect_shots(&mut self, state: &HQMGameState, snapshot: &HQMEventSnapshot, events: &mut Vec<GameEvent>) {
+        if snapshot.goal_interruption || snapshot.game_over {
+            // The puck went in, or play is stopped
+            self.pending_shot = None;
+            self.shot_streak = 0;
+            return;
+        }
+        let goal = predicted_goal(state);
+        match (&mut self.pending_shot, goal) {
+            (None, Some((steps, team))) => {
+                self.shot_streak += 1;
+                if self.shot_streak >= SHOT_DEBOUNCE_UPDATES {
+                    events.push(GameEvent::ShotOnGoal {
+                        team,
+                        shooter: snapshot.last_touch,
+                        steps
+                    });
+                    self.pending_shot = Some(pending_shot(state, team, snapshot));
+                    self.shot_streak = 0;
+                }
+            }
+            (Some(shot), None) => {
+                self.shot_streak += 1;
+                if self.shot_streak >= SHOT_DEBOUNCE_UPDATES {
+                    let defending_team = other_team(shot.team);
+                    let player = snapshot.last_touch
+                        .filter(|touch| touch.team == defending_team && snapshot.last_touch != shot.touch_at_shot);
+                    let turned_back = state.puck().is_some_and(|puck| puck.vel.z * shot.direction < 0.0);
+                    if player.is_some() || turned_back {
+                        events.push(GameEvent::Save {
+                            team: defending_team,
+                            player
+                        });
+                    }
+                    self.pending_shot = None;
+                    self.shot_streak = 0;
+                }
+            }
+            (Some(shot), Some((_, team))) => {
+                self.shot_streak = 0;
+                if shot.team != team {
+                    *shot = pending_shot(state, team, snapshot);
+                }
+            }
+            (None, None) => {
+                self.shot_streak = 0;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+fn snapshot(state: &HQMGameState) -> HQMEventSnapshot {
+    HQMEventSnapshot {
+        step: state.step,
+        period: state.period,
+        goal_interruption: state.goal_interruption,
+        game_over: state.game_over,
+        puck_in_rink: state.puck().map(|puck| puck.pos.y >= 0.0 && state.rink.distance_to_boards(&puck.pos) >= 0.0),
+        teams: state.players.values()
+            .filter_map(|player| player.object_index.map(|(_, team)| (player.index, team)))
+            .collect(),
+        last_touch: state.possession.last_touch,
+    }
+}
+
+fn pending_shot(state: &HQMGameState, team: HQMTeam, snapshot: &HQMEventSnapshot) -> HQMPendingShot {
+    HQMPendingShot {
+        team,
+        touch_at_shot: snapshot.last_touch,
+        direction: state.puck().map_or(0.0, |puck| puck.vel.z.signum())
+    }
+}
+
+fn predicted_goal(state: &HQMGameState) -> Option<(usize, HQMTeam)> {
+    let puck = state.puck()?;
+    hqm_predict::predict_puck_from(&state.rink, puck.pos, puck.vel, SHOT_PREDICTION_STEPS).goal
+}
+
+pub(crate) fn other_team(team: HQMTeam) -> HQMTeam {
+    match team {
+        HQMTeam::Red => HQMTeam::Blue,
+        HQMTeam::Blue => HQMTeam::Red,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_game::HQMPlayer;
+    use crate::hqm_game::testing::{puck, HQMGameStateBuilder};
+    use nalgebra::Vector3;
+
+    fn state(step: u32, puck_pos: Point3<f32>, puck_vel: Vector3<f32>) -> HQMGameState {
+        HQMGameStateBuilder::new()
+            .objects(vec![puck(puck_pos, puck_vel)])
+            .step(step)
+            .build()
+    }
+
+    fn still(step: u32) -> HQMGameState {
+        state(step, Point3::new(15.0, 0.02, 30.5), Vector3::zeros())
+    }
+
+    #[test]
+    fn state_changes_become_events() {
+        let mut detector = HQMEventDetector::new();
+        let mut s = still(1);
+        s.period = 0;
+        s.players.insert(3, HQMPlayer { name: "A".to_owned(), index: 3, object_index: Some((0, HQMTeam::Red)) });
+        assert!(detector.update(&s).is_empty());
+
+        let mut s = still(2);
+        s.period = 1;
+        s.goal_interruption = true;
+        s.players.insert(3, HQMPlayer { name: "A".to_owned(), index: 3, object_index: Some((0, HQMTeam::Blue)) });
+        assert_eq!(detector.update(&s), vec![
+            GameEvent::PeriodEnd { period: 0 },
+            GameEvent::PeriodStart { period: 1 },
+            GameEvent::PlayerLeftTeam { player_index: 3, team: HQMTeam::Red },
+            GameEvent::PlayerJoinedTeam { player_index: 3, team: HQMTeam::Blue },
+        ]);
+
+        // Older states are ignored
+        assert!(detector.update(&still(1)).is_empty());
+
+        let mut s = still(3);
+        s.game_over = true;
+        s.blue_score = 2;
+        assert_eq!(detector.update(&s), vec![
+            GameEvent::Faceoff,
+            GameEvent::GameOver { red_score: 0, blue_score: 2 },
+            GameEvent::PlayerLeftTeam { player_index: 3, team: HQMTeam::Blue },
+        ]);
+    }
+
+    fn shot(step: u32) -> HQMGameState {
+        state(step, Point3::new(15.2, 0.3, 12.0 - 0.4 * step as f32), Vector3::new(0.0, 0.0, -0.4))
+    }
+
+    /// Feeds `states` and returns all events
+    fn events(detector: &mut HQMEventDetector, states: impl IntoIterator<Item=HQMGameState>) -> Vec<GameEvent> {
+        states.into_iter().flat_map(|s| detector.update(&s)).collect()
+    }
+
+    #[test]
+    fn shot_then_save() {
+        let mut detector = HQMEventDetector::new();
+        let goalie = HQMPuckTouch { player_index: 1, team: HQMTeam::Blue };
+        assert!(detector.update(&still(1)).is_empty());
+
+        // The shot has to be predicted a few updates in a row
+        assert!(events(&mut detector, (2..4).map(shot)).is_empty());
+        match detector.update(&shot(4)).as_slice() {
+            [GameEvent::ShotOnGoal { team: HQMTeam::Red, shooter: None, steps }] => assert!(*steps > 10),
+            x => panic!("unexpected events {:?}", x)
+        }
+        assert!(detector.update(&shot(5)).is_empty());
+
+        let saved = |step| {
+            let mut saved = state(step, Point3::new(15.2, 0.1, 6.0), Vector3::new(0.0, 0.0, 0.1));
+            saved.possession.holder = Some(goalie);
+            saved.possession.last_touch = Some(goalie);
+            saved
+        };
+        assert_eq!(events(&mut detector, (6..9).map(saved)), vec![GameEvent::Save { team: HQMTeam::Blue, player: Some(goalie) }]);
+    }
+
+    #[test]
+    fn puck_turning_back_is_a_save() {
+        let mut detector = HQMEventDetector::new();
+        detector.update(&still(1));
+        events(&mut detector, (2..5).map(shot));
+        // Off the pads, nobody's stick
+        let rebound = |step| state(step, Point3::new(15.2, 0.1, 6.0), Vector3::new(0.0, 0.0, 0.1));
+        assert_eq!(events(&mut detector, (5..8).map(rebound)), vec![GameEvent::Save { team: HQMTeam::Blue, player: None }]);
+    }
+
+    #[test]
+    fn shot_that_stops_going_in_is_not_a_save() {
+        let mut detector = HQMEventDetector::new();
+        detector.update(&still(1));
+        events(&mut detector, (2..5).map(shot));
+        // Still heading for the end boards, but wide of the net
+        let wide = |step| state(step, Point3::new(5.0, 0.3, 10.0), Vector3::new(0.0, 0.0, -0.4));
+        assert!(events(&mut detector, (5..10).map(wide)).is_empty());
+    }
+
+    #[test]
+    fn flickering_prediction_is_one_shot() {
+        let mut detector = HQMEventDetector::new();
+        detector.update(&still(1));
+        let flicker = |step: u32| if step & 1 == 0 {
+            shot(step)
+        } else {
+            state(step, Point3::new(5.0, 0.3, 10.0), Vector3::new(0.0, 0.0, -0.4))
+        };
+        // Never three updates in a row, so no shot at all
+        assert!(events(&mut detector, (2..10).map(flicker)).is_empty());
+
+        assert!(matches!(events(&mut detector, (10..13).map(shot)).as_slice(), [GameEvent::ShotOnGoal { .. }]));
+        let all = events(&mut detector, (13..30).map(flicker));
+        assert!(all.is_empty(), "{:?}", all);
+    }
+
+    #[test]
+    fn puck_leaving_the_rink() {
+        let mut detector = HQMEventDetector::new();
+        assert!(detector.update(&still(1)).is_empty());
+        let out = state(2, Point3::new(-0.5, 1.5, 30.0), Vector3::new(-0.1, 0.0, 0.0));
+        assert_eq!(detector.update(&out), vec![GameEvent::PuckLeftRink { pos: Point3::new(-0.5, 1.5, 30.0) }]);
+        assert!(detector.update(&state(3, Point3::new(-0.6, 1.5, 30.0), Vector3::zeros())).is_empty());
+    }
+}
@@ -0,0 +1,639 @@
+use crate::hqm_bot::{HQMBotLogic, HQMConnectionState};
+use crate::hqm_events::GameEvent;
+use crate::hqm_game::{HQMGameState, HQMGameStateObject, HQMMessage, HQMPlayer, HQMPlayerInput, HQMTeam};
+use crate::hqm_hrp::HQMHrpReader;
+use crate::hqm_replay::{HQMReplayEntry, HQMReplayReader};
+use nalgebra::{Matrix3, Point3, Rotation3, UnitQuaternion, Vector3};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HQMExportFormat {
+    /// One JSON object per game state
+    JsonLines,
+    /// One row per object per game state, and one row per message
+    Csv,
+}
+
+const CSV_HEADER: &str = "game_id,step,period,time,red_score,blue_score,kind,object_index,player_index,player_name,team,\
+pos_x,pos_y,pos_z,rot_w,rot_x,rot_y,rot_z,vel_x,vel_y,vel_z,\
+stick_pos_x,stick_pos_y,stick_pos_z,stick_rot_w,stick_rot_x,stick_rot_y,stick_rot_z,head_rot,body_rot,text";
+
+/// Writes game states for analysis outside of Rust. Rotations are written as unit
+/// quaternions in w, x, y, z order.
+pub struct HQMExporter<W: Write> {
+    writer: W,
+    format: HQMExportFormat,
+    wrote_header: bool,
+}
+
+impl HQMExporter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, format: HQMExportFormat) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?), format))
+    }
+}
+
+impl<W: Write> HQMExporter<W> {
+    pub fn new(writer: W, format: HQMExportFormat) -> Self {
+        HQMExporter {
+            writer,
+            format,
+            wrote_header: false
+        }
+    }
+
+    pub fn write_state(&mut self, state: &HQMGameState, messages: &[HQMMessage]) -> io::Result<()> {
+        let text = match self.format {
+            HQMExportFormat::JsonLines => json_line(state, messages),
+            HQMExportFormat::Csv => {
+                let mut text = String::new();
+                if !self.wrote_header {
+                    text.push_str(CSV_HEADER);
+                    text.push('\n');
+                }
+                text.push_str(&csv_rows(state, messages));
+                text
+            }
+        };
+        self.writer.write_all(text.as_bytes())?;
+        self.wrote_header = true;
+        Ok(())
+    }
+
+    /// Exports every game state in a replay recorded by `HQMBotSession`
+    pub fn write_replay<R: Read>(&mut self, reader: HQMReplayReader<R>) -> io::Result<()> {
+        for entry in reader.entries() {
+            if let HQMReplayEntry::Tick { tick, .. } = entry? {
+                self.write_state(&tick.state, &tick.messages)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Exports every game state in a `.hrp` replay
+    pub fn write_hrp(&mut self, reader: HQMHrpReader) -> io::Result<()> {
+        for tick in reader.ticks() {
+            let tick = tick.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.write_state(&tick.state, &tick.messages)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Bot logic wrapper that exports every state the inner logic sees
+pub struct HQMExportBot<T: HQMBotLogic, W: Write> {
+    logic: T,
+    exporter: Option<HQMExporter<W>>,
+}
+
+impl<T: HQMBotLogic, W: Write> HQMExportBot<T, W> {
+    pub fn new(logic: T, exporter: HQMExporter<W>) -> Self {
+        HQMExportBot {
+            logic,
+            exporter: Some(exporter)
+        }
+    }
+
+    pub fn logic(&self) -> &T {
+        &self.logic
+    }
+
+    pub fn logic_mut(&mut self) -> &mut T {
+        &mut self.logic
+    }
+
+    /// The exporter, or None if it has failed
+    pub fn exporter_mut(&mut self) -> Option<&mut HQMExporter<W>> {
+        self.exporter.as_mut()
+    }
+}
+
+impl<T: HQMBotLogic, W: Write> HQMBotLogic for HQMExportBot<T, W> {
+    fn new_game(&mut self) {
+        self.logic.new_game();
+    }
+
+    fn tick(&mut self, state: &HQMGameState, messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+        if let Some(exporter) = &mut self.exporter {
+            if let Err(e) = exporter.write_state(state, messages) {
+                eprintln!("Could not export game state, export stopped: {}", e);
+                self.exporter = None;
+            }
+        }
+        self.logic.tick(state, messages)
+    }
+
+    fn connection_state_changed(&mut self, state: HQMConnectionState) {
+        if state != HQMConnectionState::Joined {
+            if let Some(exporter) = &mut self.exporter {
+                if let Err(e) = exporter.flush() {
+                    eprintln!("Could not export game state: {}", e);
+                }
+            }
+        }
+        self.logic.connection_state_changed(state);
+    }
+
+    fn game_events(&mut self, state: &HQMGameState, events: &[GameEvent]) {
+        self.logic.game_events(state, events);
+    }
+
+    fn chat_dropped(&mut self, message: &str) {
+        self.logic.chat_dropped(message);
+    }
+}
+
+fn team_name(team: HQMTeam) -> &'static str {
+    match team {
+        HQMTeam::Red => "red",
+        HQMTeam::Blue => "blue",
+    }
+}
+
+fn quaternion(rot: &Matrix3<f32>) -> [f32; 4] {
+    let q = UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(*rot));
+    [q.w, q.i, q.j, q.k]
+}
+
+/// Player controlling the object at `object_index`
+fn object_player(state: &HQMGameState, object_index: usize) -> Option<&HQMPlayer> {
+    state.players.values().find(|x| x.object_index.map(|(i, _)| i) == Some(object_index))
+}
+
+fn sorted_players(state: &HQMGameState) -> Vec<&HQMPlayer> {
+    let mut players: Vec<&HQMPlayer> = state.players.values().collect();
+    players.sort_by_key(|x| x.index);
+    players
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+}
+
+fn json_number(out: &mut String, v: f32) {
+    if v.is_finite() {
+        let _ = write!(out, "{}", v);
+    } else {
+        out.push_str("null");
+    }
+}
+
+fn json_array(out: &mut String, values: &[f32]) {
+    out.push('[');
+    for (i, v) in values.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json_number(out, *v);
+    }
+    out.push(']');
+}
+
+fn json_index(out: &mut String, index: Option<usize>) {
+    match index {
+        Some(index) => {
+            let _ = write!(out, "{}", index);
+        }
+        None => out.push_str("null")
+    }
+}
+
+fn json_team(out: &mut String, team: Option<HQMTeam>) {
+    match team {
+        Some(team) => json_string(out, team_name(team)),
+        None => out.push_str("null")
+    }
+}
+
+fn json_line(state: &HQMGameState, messages: &[HQMMessage]) -> String {
+    let mut out = String::new();
+    let _ = write!(out, "{{\"game_id\":{},\"step\":{},\"period\":{},\"time\":{},\"goal_time\":{},\"red_score\":{},\"blue_score\":{},\"game_over\":{},\"yourself\":",
+                   state.game_id, state.step, state.period, state.time, state.clock.goal_time,
+                   state.red_score, state.blue_score, state.game_over);
+    json_index(&mut out, state.players.get(&state.yourself).map(|x| x.index));
+
+    out.push_str(",\"players\":[");
+    for (i, player) in sorted_players(state).into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{{\"index\":{},\"name\":", player.index);
+        json_string(&mut out, &player.name);
+        out.push_str(",\"team\":");
+        json_team(&mut out, player.object_index.map(|(_, team)| team));
+        out.push_str(",\"object\":");
+        json_index(&mut out, player.object_index.map(|(object, _)| object));
+        out.push('}');
+    }
+
+    out.push_str("],\"objects\":[");
+    let mut first = true;
+    for (object_index, object) in state.objects.iter().enumerate() {
+        let (kind, pos, rot, vel) = match object {
+            HQMGameStateObject::None => continue,
+            HQMGameStateObject::Puck(puck) => ("puck", puck.pos, puck.rot, puck.vel),
+            HQMGameStateObject::Skater(skater) => ("skater", skater.pos, skater.rot, skater.vel),
+        };
+        if !first {
+            out.push(',');
+        }
+        first = false;
+        let _ = write!(out, "{{\"index\":{},\"type\":\"{}\",\"pos\":", object_index, kind);
+        json_array(&mut out, pos.coords.as_slice());
+        out.push_str(",\"rot\":");
+        json_array(&mut out, &quaternion(&rot));
+        out.push_str(",\"vel\":");
+        json_array(&mut out, vel.as_slice());
+        if let HQMGameStateObject::Skater(skater) = object {
+            let player = object_player(state, object_index);
+            out.push_str(",\"player\":");
+            json_index(&mut out, player.map(|x| x.index));
+            out.push_str(",\"team\":");
+            json_team(&mut out, player.and_then(|x| x.object_index).map(|(_, team)| team));
+            out.push_str(",\"stick_pos\":");
+            json_array(&mut out, skater.stick_pos.coords.as_slice());
+            out.push_str(",\"stick_rot\":");
+            json_array(&mut out, &quaternion(&skater.stick_rot));
+            out.push_str(",\"head_rot\":");
+            json_number(&mut out, skater.head_rot);
+            out.push_str(",\"body_rot\":");
+            json_number(&mut out, skater.body_rot);
+        }
+        out.push('}');
+    }
+
+    out.push_str("],\"messages\":[");
+    for (i, message) in messages.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        match message {
+            HQMMessage::PlayerUpdate { player_name, object, player_index, in_server } => {
+                let _ = write!(out, "{{\"type\":\"player_update\",\"player\":{},\"name\":", player_index);
+                json_string(&mut out, player_name);
+                out.push_str(",\"team\":");
+                json_team(&mut out, object.map(|(_, team)| team));
+                out.push_str(",\"object\":");
+                json_index(&mut out, object.map(|(object, _)| object));
+                let _ = write!(out, ",\"in_server\":{}}}", in_server);
+            }
+            HQMMessage::Goal { team, goal_player_index, assist_player_index } => {
+                out.push_str("{\"type\":\"goal\",\"team\":");
+                json_team(&mut out, Some(*team));
+                out.push_str(",\"goal\":");
+                json_index(&mut out, *goal_player_index);
+                out.push_str(",\"assist\":");
+                json_index(&mut out, *assist_player_index);
+                out.push('}');
+            }
+            HQMMessage::Chat { player_index, message } => {
+                out.push_str("{\"type\":\"chat\",\"player\":");
+                json_index(&mut out, *player_index);
+                out.push_str(",\"message\":");
+                json_string(&mut out, message);
+                out.push('}');
+            }
+        }
+    }
+    out.push_str("]}\n");
+    out
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+fn csv_numbers(fields: &mut Vec<String>, values: &[f32]) {
+    fields.extend(values.iter().map(|v| v.to_string()));
+}
+
+fn csv_blank(fields: &mut Vec<String>, n: usize) {
+    fields.resize(fields.len() + n, String::new());
+}
+
+/// Fields for the player columns: player_index, player_name, team
+fn csv_player(fields: &mut Vec<String>, state: &HQMGameState, player_index: Option<usize>, team: Option<HQMTeam>) {
+    fields.push(player_index.map(|x| x.to_string()).unwrap_or_default());
+    let name = player_index.and_then(|x| state.players.get(&x)).map(|x| x.name.as_str()).unwrap_or("");
+    fields.push(csv_field(name));
+    fields.push(team.map(team_name).unwrap_or("").to_owned());
+}
+
+fn csv_pose(fields: &mut Vec<String>, pos: &Point3<f32>, rot: &Matrix3<f32>, vel: &Vector3<f32>) {
+    csv_numbers(fields, pos.coords.as_slice());
+    csv_numbers(fields, &quaternion(rot));
+    csv_numbers(fields, vel.as_slice());
+}
+
+fn csv_rows(state: &HQMGameState, messages: &[HQMMessage]) -> String {
+    let mut out = String::new();
+    let prefix = format!("{},{},{},{},{},{}", state.game_id, state.step, state.period, state.time, state.red_score, state.blue_score);
+    let mut row = |fields: Vec<String>| {
+        out.push_str(&prefix);
+        for field in fields {
+            out.push(',');
+            out.push_str(&field);
+        }
+        out.push('\n');
+    };
+
+    for (object_index, object) in state.objects.iter().enumerate() {
+        let mut fields = vec![];
+        match object {
+            HQMGameStateObject::None => continue,
+            HQMGameStateObject::Puck(puck) => {
+                fields.push("puck".to_owned());
+                fields.push(object_index.to_string());
+                csv_player(&mut fields, state, None, None);
+                csv_pose(&mut fields, &puck.pos, &puck.rot, &puck.vel);
+                csv_blank(&mut fields, 10);
+            }
+            HQMGameStateObject::Skater(skater) => {
+                fields.push("skater".to_owned());
+                fields.push(object_index.to_string());
+                let player = object_player(state, object_index);
+                csv_player(&mut fields, state, player.map(|x| x.index), player.and_then(|x| x.object_index).map(|(_, team)| team));
+                csv_pose(&mut fields, &skater.pos, &skater.rot, &skater.vel);
+                csv_numbers(&mut fields, skater.stick_pos.coords.as_slice());
+                csv_numbers(&mut fields, &quaternion(&skater.stick_rot));
+                csv_numbers(&mut fields, &[skater.head_rot, skater.body_rot]);
+                fields.push(String::new());
+            }
+        }
+        row(fields);
+    }
+
+    for message in messages {
+        let mut fields = vec![];
+        // Messages use the player columns and put anything else in the text column
+        let text = match message {
+            HQMMessage::PlayerUpdate { player_name, object, player_index, in_server } => {
+                fields.push("player_update".to_owned());
+                fields.push(object.map(|(object, _)| object.to_string()).unwrap_or_default());
+                fields.push(player_index.to_string());
+                fields.push(csv_field(player_name));
+                fields.push(object.map(|(_, team)| team_name(team)).unwrap_or("").to_owned());
+                if *in_server { "joined" } else { "left" }.to_owned()
+            }
+            HQMMessage::Goal { team, goal_player_index, assist_player_index } => {
+                fields.push("goal".to_owned());
+                fields.push(String::new());
+                csv_player(&mut fields, state, *goal_player_index, Some(*team));
+                assist_player_index.and_then(|x| state.players.get(&x)).map(|x| x.name.clone()).unwrap_or_default()
+            }
+            HQMMessage::Chat { player_index, message } => {
+                fields.push("chat".to_owned());
+                fields.push(String::new());
+                let team = player_index.and_then(|x| state.players.get(&x)).and_then(|x| x.object_index).map(|(_, team)| team);
+                csv_player(&mut fields, state, *player_index, team);
+                message.clone()
+            }
+        };
+        csv_blank(&mut fields, 19);
+        fields.push(csv_field(&text));
+        row(fields);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_bot::testing::{game_update, new_game};
+    use crate::hqm_game::HQMGameStateSkater;
+    use crate::hqm_game::testing::{puck, HQMGameStateBuilder};
+    use crate::hqm_replay::{HQMReplayRecordKind, HQMReplayWriter};
+    use std::time::Duration;
+
+    fn state() -> HQMGameState {
+        let rot = Rotation3::from_axis_angle(&Vector3::y_axis(), std::f32::consts::FRAC_PI_2).into_inner();
+        HQMGameStateBuilder::new()
+            .game_id(4)
+            .step(777)
+            .score(1, 0)
+            .clock(2, 12345)
+            .player(2, "Quote \"Q\", Esq.", Some((1, HQMTeam::Blue)))
+            .player(0, "Spectator", None)
+            .objects(vec![
+                puck(Point3::new(15.0, 0.0625, 30.5), Vector3::new(0.5, 0.0, -0.25)),
+                HQMGameStateObject::Skater(HQMGameStateSkater {
+                    pos: Point3::new(10.0, 1.5, 20.0),
+                    rot,
+                    stick_pos: Point3::new(10.5, 0.1, 19.0),
+                    stick_rot: Matrix3::identity(),
+                    head_rot: 0.0,
+                    body_rot: 0.5,
+                    vel: Vector3::zeros(),
+                    angular_vel: Vector3::zeros(),
+                    acc: Vector3::zeros(),
+                    stick_vel: Vector3::zeros()
+                }),
+                HQMGameStateObject::None
+            ])
+            .yourself(2)
+            .build()
+    }
+
+    fn messages() -> Vec<HQMMessage> {
+        vec![
+            HQMMessage::Chat { player_index: Some(2), message: "line\nbreak".to_owned() },
+            HQMMessage::Goal { team: HQMTeam::Red, goal_player_index: None, assist_player_index: None },
+        ]
+    }
+
+    #[test]
+    fn json_lines_export() {
+        let mut exporter = HQMExporter::new(Vec::new(), HQMExportFormat::JsonLines);
+        exporter.write_state(&state(), &messages()).unwrap();
+        exporter.write_state(&state(), &[]).unwrap();
+        let text = String::from_utf8(exporter.into_inner()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let line = lines[0];
+        assert!(line.starts_with("{\"game_id\":4,\"step\":777,\"period\":2,\"time\":12345,"));
+        assert!(line.contains("\"yourself\":2,"));
+        assert!(line.contains("{\"index\":0,\"name\":\"Spectator\",\"team\":null,\"object\":null},{\"index\":2,\"name\":\"Quote \\\"Q\\\", Esq.\",\"team\":\"blue\",\"object\":1}"));
+        assert!(line.contains("{\"index\":0,\"type\":\"puck\",\"pos\":[15,0.0625,30.5],\"rot\":[1,0,0,0],\"vel\":[0.5,0,-0.25]}"));
+        assert!(line.contains("\"type\":\"skater\""));
+        assert!(line.contains("\"player\":2,\"team\":\"blue\",\"stick_pos\":[10.5,0.1,19],\"stick_rot\":[1,0,0,0],\"head_rot\":0,\"body_rot\":0.5}"));
+        assert!(line.contains("{\"type\":\"chat\",\"player\":2,\"message\":\"line\\nbreak\"}"));
+        assert!(line.contains("{\"type\":\"goal\",\"team\":\"red\",\"goal\":null,\"assist\":null}"));
+        assert!(lines[1].ends_with("\"messages\":[]}"));
+    }
+
+    #[test]
+    fn csv_export() {
+        let mut exporter = HQMExporter::new(Vec::new(), HQMExportFormat::Csv);
+        exporter.write_state(&state(), &messages()).unwrap();
+        exporter.write_state(&state(), &[]).unwrap();
+        let text = String::from_utf8(exporter.into_inner()).unwrap();
+        let columns = CSV_HEADER.split(',').count();
+        assert_eq!(columns, 31);
+
+        let mut rows = vec![];
+        let mut current = String::new();
+        let mut quoted = false;
+        for c in text.chars() {
+            if c == '"' {
+                quoted = !quoted;
+            }
+            if c == '\n' && !quoted {
+                rows.push(std::mem::take(&mut current));
+            } else {
+                current.push(c);
+            }
+        }
+        // Header, two objects and two messages, then two objects
+        assert_eq!(rows.len(), 7);
+        assert_eq!(rows[0], CSV_HEADER);
+        assert!(rows[1].starts_with("4,777,2,12345,1,0,puck,0,,,,15,0.0625,30.5,1,0,0,0,0.5,0,-0.25,"));
+        assert!(rows[2].starts_with("4,777,2,12345,1,0,skater,1,2,\"Quote \"\"Q\"\", Esq.\",blue,10,1.5,20,"));
+        let w = std::f32::consts::FRAC_1_SQRT_2;
+        assert!(rows[2].contains(&format!(",{},0,{},0,", w, w)));
+        assert!(rows[3].starts_with("4,777,2,12345,1,0,chat,,2,\"Quote"));
+        assert!(rows[3].ends_with(",\"line\nbreak\""));
+        assert!(rows[4].starts_with("4,777,2,12345,1,0,goal,,,,red,"));
+        assert!(rows[5].contains(",puck,"));
+    }
+
+    #[test]
+    fn replay_export() {
+        let mut writer = HQMReplayWriter::new(Vec::new()).unwrap();
+        let mut record = |time: u64, kind, data: &[u8]| {
+            writer.record_at(Duration::from_millis(time), kind, data).unwrap();
+        };
+        record(0, HQMReplayRecordKind::SessionStart, &[]);
+        record(5, HQMReplayRecordKind::Received, &new_game(3));
+        record(10, HQMReplayRecordKind::Received, &game_update(3, 0, Vec::new(), messages()));
+        record(11, HQMReplayRecordKind::Sent, b"not a game state");
+        record(20, HQMReplayRecordKind::Received, &game_update(3, 1, Vec::new(), Vec::new()));
+        let data = writer.into_inner();
+        let replay = HQMReplayReader::new(data.as_slice()).unwrap();
+
+        let mut exporter = HQMExporter::new(Vec::new(), HQMExportFormat::JsonLines);
+        exporter.write_replay(replay).unwrap();
+        let text = String::from_utf8(exporter.into_inner()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"game_id\":3,\"step\":0,"));
+        assert!(lines[0].contains("{\"type\":\"chat\",\"player\":2,\"message\":\"line\\nbreak\"}"));
+        assert!(lines[1].starts_with("{\"game_id\":3,\"step\":1,"));
+        assert!(lines[1].ends_with("\"messages\":[]}"));
+    }
+
+    #[derive(Default)]
+    struct RecordingBot {
+        ticks: usize,
+        connection_states: Vec<HQMConnectionState>,
+        dropped: Vec<String>,
+    }
+
+    impl HQMBotLogic for RecordingBot {
+        fn new_game(&mut self) {}
+
+        fn tick(&mut self, _state: &HQMGameState, _messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+            self.ticks += 1;
+            (HQMPlayerInput::default(), None)
+        }
+
+        fn connection_state_changed(&mut self, state: HQMConnectionState) {
+            self.connection_states.push(state);
+        }
+
+        fn chat_dropped(&mut self, message: &str) {
+            self.dropped.push(message.to_owned());
+        }
+    }
+
+    #[test]
+    fn export_bot_exports_what_the_logic_sees() {
+        let exporter = HQMExporter::new(Vec::new(), HQMExportFormat::JsonLines);
+        let mut bot = HQMExportBot::new(RecordingBot::default(), exporter);
+        bot.tick(&state(), &messages());
+        bot.tick(&state(), &[]);
+        bot.chat_dropped("hello");
+
+        let text = String::from_utf8(bot.exporter_mut().unwrap().get_ref().clone()).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.contains("\"type\":\"goal\""));
+        assert_eq!(bot.logic().ticks, 2);
+        assert_eq!(bot.logic().dropped, vec!["hello"]);
+    }
+
+    /// Fails every write after the first `writes`, and counts flushes
+    struct FailingWriter {
+        writes: usize,
+        flushes: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.writes == 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "reader went away"));
+            }
+            self.writes -= 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn export_bot_flushes_and_stops_after_a_write_error() {
+        let exporter = HQMExporter::new(FailingWriter { writes: 1, flushes: 0 }, HQMExportFormat::Csv);
+        let mut bot = HQMExportBot::new(RecordingBot::default(), exporter);
+        bot.connection_state_changed(HQMConnectionState::Joined);
+        bot.tick(&state(), &[]);
+        assert_eq!(bot.exporter_mut().unwrap().get_ref().flushes, 0);
+        // Losing the connection may be the last the bot hears of the game
+        bot.connection_state_changed(HQMConnectionState::Lost);
+        assert_eq!(bot.exporter_mut().unwrap().get_ref().flushes, 1);
+
+        bot.tick(&state(), &[]);
+        assert!(bot.exporter_mut().is_none());
+        // The logic keeps playing without the export
+        bot.tick(&state(), &[]);
+        bot.connection_state_changed(HQMConnectionState::Reconnecting);
+        assert_eq!(bot.logic().ticks, 3);
+        assert_eq!(bot.logic().connection_states, vec![
+            HQMConnectionState::Joined,
+            HQMConnectionState::Lost,
+            HQMConnectionState::Reconnecting,
+        ]);
+    }
+}
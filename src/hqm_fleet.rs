@@ -0,0 +1,568 @@
+use crate::hqm_blackboard::{HQMBlackboard, HQMBlackboardSlot};
+use crate::hqm_bot::{HQMBotLogic, HQMBotSession, HQMConnectionState, HQMExitReason, HQMSessionConfig, HQMShutdownHandle};
+use crate::hqm_events::GameEvent;
+use crate::hqm_game::{HQMGameState, HQMMessage, HQMPlayerInput};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Makes a fresh logic for a bot, when it starts and every time it is restarted.
+/// The logic gets the bot's slot on the blackboard of its server, where it shares keys with the
+/// other bots on its team.
+pub type HQMLogicFactory = Box<dyn Fn(HQMBlackboardSlot) -> Box<dyn HQMBotLogic + Send> + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct HQMFleetConfig {
+    /// Whether bots whose session failed are started again
+    pub restart: bool,
+    /// Restarts allowed per bot, None for no limit
+    pub max_restarts: Option<u32>,
+    /// Delay before the first restart. Doubles after every restart of the same bot, and
+    /// starts over after a session that got into a game.
+    pub restart_delay: Duration,
+    pub max_restart_delay: Duration,
+}
+
+impl Default for HQMFleetConfig {
+    fn default() -> Self {
+        HQMFleetConfig {
+            restart: true,
+            max_restarts: None,
+            restart_delay: Duration::from_secs(1),
+            max_restart_delay: Duration::from_secs(60)
+        }
+    }
+}
+
+impl HQMFleetConfig {
+    /// Delay before restarting a bot, given the delay before its last restart, if any,
+    /// and whether the session that just ended got into a game
+    fn next_restart_delay(&self, previous: Option<Duration>, joined: bool) -> Duration {
+        match previous {
+            // A session that got into a game was working, so this is a new failure rather than another try
+            Some(previous) if !joined => (previous * 2).min(self.max_restart_delay),
+            _ => self.restart_delay
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HQMBotState {
+    /// Not started yet, or starting a new session
+    Starting,
+    Running(HQMConnectionState),
+    /// The session failed, waiting to start it again
+    Restarting {
+        error: String
+    },
+    Stopped,
+    /// The session failed and won't be restarted
+    Failed {
+        error: String
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct HQMBotStatus {
+    pub name: String,
+    pub server: SocketAddr,
+    pub state: HQMBotState,
+    pub restarts: u32,
+    pub games_played: u32,
+    /// Packets received by sessions that have ended
+    pub packets_received: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct HQMFleetStatus {
+    pub bots: Vec<HQMBotStatus>,
+}
+
+impl HQMFleetStatus {
+    /// Number of bots currently in a game
+    pub fn joined(&self) -> usize {
+        self.bots.iter().filter(|x| x.state == HQMBotState::Running(HQMConnectionState::Joined)).count()
+    }
+
+    /// Number of bots whose lifecycle has ended, stopped or failed
+    pub fn finished(&self) -> usize {
+        self.bots.iter().filter(|x| matches!(x.state, HQMBotState::Stopped | HQMBotState::Failed { .. })).count()
+    }
+}
+
+impl fmt::Display for HQMFleetStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} bots joined", self.joined(), self.bots.len())?;
+        for bot in self.bots.iter() {
+            let state = match &bot.state {
+                HQMBotState::Starting => "starting".to_owned(),
+                HQMBotState::Running(state) => format!("{:?}", state).to_lowercase(),
+                HQMBotState::Restarting { error } => format!("restarting after: {}", error),
+                HQMBotState::Stopped => "stopped".to_owned(),
+                HQMBotState::Failed { error } => format!("failed: {}", error),
+            };
+            write!(f, "\n  {} on {}: {}, {} games, {} restarts", bot.name, bot.server, state, bot.games_played, bot.restarts)?;
+        }
+        Ok(())
+    }
+}
+
+/// Gives a view of the fleet status while it runs
+#[derive(Clone)]
+pub struct HQMFleetStatusHandle {
+    status: Arc<Mutex<Vec<HQMBotStatus>>>,
+}
+
+impl HQMFleetStatusHandle {
+    pub fn status(&self) -> HQMFleetStatus {
+        HQMFleetStatus { bots: self.status.lock().unwrap().clone() }
+    }
+}
+
+struct HQMFleetBot {
+    name: String,
+    server: SocketAddr,
+    session_config: HQMSessionConfig,
+    factory: Arc<HQMLogicFactory>,
+    blackboard: HQMBlackboardSlot,
+}
+
+/// Runs several bots in the same process, each with its own session, name and logic.
+/// Bots whose session fails are restarted with a fresh logic.
+pub struct BotFleet {
+    config: HQMFleetConfig,
+    bots: Vec<HQMFleetBot>,
+    blackboards: HashMap<SocketAddr, HQMBlackboard>,
+    status: Arc<Mutex<Vec<HQMBotStatus>>>,
+    shutdown_sender: Arc<watch::Sender<bool>>,
+    shutdown_receiver: watch::Receiver<bool>,
+}
+
+impl BotFleet {
+    pub fn new(config: HQMFleetConfig) -> Self {
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        BotFleet {
+            config,
+            bots: Vec::new(),
+            blackboards: HashMap::new(),
+            status: Arc::new(Mutex::new(Vec::new())),
+            shutdown_sender: Arc::new(shutdown_sender),
+            shutdown_receiver
+        }
+    }
+
+    /// Adds a bot that joins `server` as `name` when the fleet runs. Returns its index in the status,
+    /// which is also its slot on the blackboard. A `replay_path` in the session config gets the index
+    /// and name added to the file name, so every bot records to its own file.
+    pub fn add_bot(&mut self, name: String, server: SocketAddr, mut session_config: HQMSessionConfig, factory: HQMLogicFactory) -> usize {
+        self.status.lock().unwrap().push(HQMBotStatus {
+            name: name.clone(),
+            server,
+            state: HQMBotState::Starting,
+            restarts: 0,
+            games_played: 0,
+            packets_received: 0
+        });
+        let index = self.bots.len();
+        if let Some(path) = &session_config.replay_path {
+            session_config.replay_path = Some(bot_replay_path(path, index, &name));
+        }
+        let blackboard = self.blackboards.entry(server).or_default().slot(index);
+        self.bots.push(HQMFleetBot {
+            name,
+            server,
+            session_config,
+            factory: Arc::new(factory),
+            blackboard
+        });
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.bots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bots.is_empty()
+    }
+
+    /// Stops every bot, see `HQMBotSession::shutdown_handle`
+    pub fn shutdown_handle(&self) -> HQMShutdownHandle {
+        HQMShutdownHandle::new(self.shutdown_sender.clone())
+    }
+
+    /// The blackboard of the bots on `server`, to watch what each team is up to
+    pub fn blackboard(&mut self, server: SocketAddr) -> HQMBlackboard {
+        self.blackboards.entry(server).or_default().clone()
+    }
+
+    pub fn status_handle(&self) -> HQMFleetStatusHandle {
+        HQMFleetStatusHandle { status: self.status.clone() }
+    }
+
+    pub fn status(&self) -> HQMFleetStatus {
+        self.status_handle().status()
+    }
+
+    /// Runs every bot until they are stopped through the shutdown handle or have failed for good
+    pub async fn run(&mut self) -> HQMFleetStatus {
+        let tasks: Vec<_> = self.bots.iter().enumerate().map(|(index, bot)| {
+            let lifecycle = HQMBotLifecycle {
+                index,
+                name: bot.name.clone(),
+                server: bot.server,
+                session_config: bot.session_config.clone(),
+                factory: bot.factory.clone(),
+                blackboard: bot.blackboard.clone(),
+                config: self.config.clone(),
+                status: self.status.clone(),
+                shutdown: self.shutdown_receiver.clone(),
+                joined: Arc::new(AtomicBool::new(false))
+            };
+            tokio::spawn(lifecycle.run())
+        }).collect();
+        for task in tasks {
+            let _ = task.await;
+        }
+        self.status()
+    }
+}
+
+/// `replays/game.hqmr` becomes `replays/game-2-Name.hqmr` for bot 2
+fn bot_replay_path(path: &Path, index: usize, name: &str) -> PathBuf {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    let stem = path.file_stem().map_or_else(Default::default, |x| x.to_string_lossy());
+    let mut file_name = format!("{}-{}-{}", stem, index, name);
+    if let Some(extension) = path.extension() {
+        file_name.push('.');
+        file_name.push_str(&extension.to_string_lossy());
+    }
+    path.with_file_name(file_name)
+}
+
+struct HQMBotLifecycle {
+    index: usize,
+    name: String,
+    server: SocketAddr,
+    session_config: HQMSessionConfig,
+    factory: Arc<HQMLogicFactory>,
+    blackboard: HQMBlackboardSlot,
+    config: HQMFleetConfig,
+    status: Arc<Mutex<Vec<HQMBotStatus>>>,
+    shutdown: watch::Receiver<bool>,
+    /// Whether the current session has joined a game
+    joined: Arc<AtomicBool>,
+}
+
+impl HQMBotLifecycle {
+    fn update<F: FnOnce(&mut HQMBotStatus)>(&self, f: F) {
+        f(&mut self.status.lock().unwrap()[self.index]);
+    }
+
+    async fn run(mut self) {
+        let mut delay = None;
+        loop {
+            if *self.shutdown.borrow() {
+                break;
+            }
+            self.update(|x| x.state = HQMBotState::Starting);
+            self.joined.store(false, Ordering::SeqCst);
+            let error = match self.run_session().await {
+                Ok(HQMExitReason::Stopped) => break,
+                Ok(HQMExitReason::ReceiveTaskEnded) => "receive task ended".to_owned(),
+                Err(e) => e
+            };
+            eprintln!("Bot {} failed: {}", self.name, error);
+
+            let restarts = self.status.lock().unwrap()[self.index].restarts;
+            if !self.config.restart || self.config.max_restarts.is_some_and(|max| restarts >= max) {
+                self.update(|x| x.state = HQMBotState::Failed { error });
+                return;
+            }
+            self.update(|x| {
+                x.state = HQMBotState::Restarting { error };
+                x.restarts += 1;
+            });
+            let next = self.config.next_restart_delay(delay, self.joined.load(Ordering::SeqCst));
+            delay = Some(next);
+            tokio::select! {
+                _ = tokio::time::sleep(next) => {}
+                _ = self.shutdown.changed() => {}
+            }
+        }
+        self.update(|x| x.state = HQMBotState::Stopped);
+    }
+
+    /// Runs one session on its own task, so a panicking logic only takes down this bot
+    async fn run_session(&self) -> Result<HQMExitReason, String> {
+        let logic = HQMFleetLogic {
+            logic: (self.factory)(self.blackboard.clone()),
+            index: self.index,
+            blackboard: self.blackboard.clone(),
+            status: self.status.clone(),
+            joined: self.joined.clone()
+        };
+        let mut session = HQMBotSession::with_config(self.name.clone(), logic, self.session_config.clone());
+        let session_shutdown = session.shutdown_handle();
+        let mut shutdown = self.shutdown.clone();
+        let forward = tokio::spawn(async move {
+            while !*shutdown.borrow() {
+                if shutdown.changed().await.is_err() {
+                    return;
+                }
+            }
+            session_shutdown.stop();
+        });
+        let server = self.server;
+        let result = tokio::spawn(async move { session.start(server).await }).await;
+        forward.abort();
+        self.blackboard.leave();
+        match result {
+            Ok(Ok(summary)) => {
+                self.update(|x| x.packets_received += summary.packets_received);
+                Ok(summary.exit_reason)
+            }
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(e) => Err(format!("session task failed: {}", e))
+        }
+    }
+}
+
+/// Keeps the status of one bot up to date, and moves its blackboard slot along with the game and team
+struct HQMFleetLogic {
+    logic: Box<dyn HQMBotLogic + Send>,
+    index: usize,
+    blackboard: HQMBlackboardSlot,
+    status: Arc<Mutex<Vec<HQMBotStatus>>>,
+    joined: Arc<AtomicBool>,
+}
+
+impl HQMBotLogic for HQMFleetLogic {
+    fn new_game(&mut self) {
+        self.status.lock().unwrap()[self.index].games_played += 1;
+        self.logic.new_game();
+    }
+
+    fn tick(&mut self, state: &HQMGameState, messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+        match state.my_team() {
+            Some(team) => self.blackboard.begin_tick(team, state.game_id, state.step),
+            None => self.blackboard.leave()
+        }
+        self.logic.tick(state, messages)
+    }
+
+    fn connection_state_changed(&mut self, state: HQMConnectionState) {
+        self.status.lock().unwrap()[self.index].state = HQMBotState::Running(state);
+        if state == HQMConnectionState::Joined {
+            self.joined.store(true, Ordering::SeqCst);
+        } else {
+            self.blackboard.leave();
+        }
+        self.logic.connection_state_changed(state);
+    }
+
+    fn game_events(&mut self, state: &HQMGameState, events: &[GameEvent]) {
+        self.logic.game_events(state, events);
+    }
+
+    fn chat_dropped(&mut self, message: &str) {
+        self.logic.chat_dropped(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_blackboard::HQMBlackboardValue;
+    use crate::hqm_bot::testing::wait_until;
+    use crate::hqm_mock_server::{MockServer, MockServerConfig};
+    use crate::hqm_game::{HQMGameStateObject, HQMTeam};
+    use crate::hqm_sim::HQMSimulator;
+    use std::sync::atomic::AtomicU32;
+
+    /// Joins the given team and stands still. Panics on its first tick if told to.
+    struct Player {
+        team: HQMTeam,
+        panic: bool,
+    }
+
+    impl HQMBotLogic for Player {
+        fn new_game(&mut self) {}
+
+        fn tick(&mut self, state: &HQMGameState, _messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+            if self.panic {
+                panic!("bad bot");
+            }
+            let join = state.me().is_none();
+            (HQMPlayerInput {
+                join_red: join && self.team == HQMTeam::Red,
+                join_blue: join && self.team == HQMTeam::Blue,
+                ..Default::default()
+            }, None)
+        }
+    }
+
+    /// Runs the fleet in the background, returning its handles and the task
+    fn spawn(mut fleet: BotFleet) -> (HQMShutdownHandle, HQMFleetStatusHandle, tokio::task::JoinHandle<HQMFleetStatus>) {
+        let shutdown = fleet.shutdown_handle();
+        let status = fleet.status_handle();
+        (shutdown, status, tokio::spawn(async move { fleet.run().await }))
+    }
+
+    fn skaters(sim: &mut HQMSimulator) -> usize {
+        sim.objects().iter().filter(|x| matches!(x, HQMGameStateObject::Skater(_))).count()
+    }
+
+    #[tokio::test]
+    async fn fleet_fills_both_teams() {
+        let server = MockServer::start(MockServerConfig::default()).await.unwrap();
+        let mut fleet = BotFleet::new(HQMFleetConfig::default());
+        for i in 0..4 {
+            let team = if i % 2 == 0 { HQMTeam::Red } else { HQMTeam::Blue };
+            fleet.add_bot(format!("Bot{}", i), server.addr(), HQMSessionConfig::default(),
+                          Box::new(move |_| Box::new(Player { team, panic: false })));
+        }
+        let (shutdown, status, task) = spawn(fleet);
+        wait_until(Duration::from_secs(5), "all bots on the ice", || {
+            status.status().joined() == 4 && server.with_sim(skaters) == 4
+        }).await;
+
+        shutdown.stop();
+        let after = task.await.unwrap();
+        assert_eq!(after.finished(), 4);
+        wait_until(Duration::from_secs(5), "the players to leave", || server.client_count() == 0).await;
+        assert!(after.bots.iter().all(|x| x.state == HQMBotState::Stopped && x.games_played == 1 && x.packets_received > 0));
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn panicking_bot_is_restarted() {
+        let server = MockServer::start(MockServerConfig::default()).await.unwrap();
+        let mut fleet = BotFleet::new(HQMFleetConfig { restart_delay: Duration::from_millis(10), ..Default::default() });
+        let started = Arc::new(AtomicU32::new(0));
+        let counter = started.clone();
+        fleet.add_bot("Flaky".to_owned(), server.addr(), HQMSessionConfig::default(), Box::new(move |_| {
+            let panic = counter.fetch_add(1, Ordering::SeqCst) == 0;
+            Box::new(Player { team: HQMTeam::Red, panic })
+        }));
+        let (shutdown, status, task) = spawn(fleet);
+        wait_until(Duration::from_secs(5), "the restarted bot to join", || status.status().joined() == 1).await;
+        let during = status.status();
+        shutdown.stop();
+        let after = task.await.unwrap();
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+        assert_eq!(during.joined(), 1, "{}", during);
+        assert_eq!(during.bots[0].restarts, 1);
+        assert_eq!(after.bots[0].state, HQMBotState::Stopped);
+
+        let mut fleet = BotFleet::new(HQMFleetConfig { restart: false, ..Default::default() });
+        fleet.add_bot("Flaky".to_owned(), server.addr(), HQMSessionConfig::default(),
+                      Box::new(|_| Box::new(Player { team: HQMTeam::Red, panic: true })));
+        let after = fleet.run().await;
+        assert!(matches!(after.bots[0].state, HQMBotState::Failed { .. }));
+        server.stop().await;
+    }
+
+    #[test]
+    fn restart_delay_starts_over_after_joining() {
+        let config = HQMFleetConfig {
+            restart_delay: Duration::from_millis(100),
+            max_restart_delay: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let mut delay = None;
+        let delays: Vec<u64> = [false, false, false, false, true, false].iter().map(|&joined| {
+            let next = config.next_restart_delay(delay, joined);
+            delay = Some(next);
+            next.as_millis() as u64
+        }).collect();
+        assert_eq!(delays, vec![100, 200, 400, 500, 100, 200]);
+        // The first restart never doubles, whether or not the session joined
+        assert_eq!(config.next_restart_delay(None, true), Duration::from_millis(100));
+    }
+
+    /// Claims the puck every tick and writes down who it saw claiming it
+    struct Claimer {
+        team: HQMTeam,
+        blackboard: HQMBlackboardSlot,
+        seen: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl HQMBotLogic for Claimer {
+        fn new_game(&mut self) {}
+
+        fn tick(&mut self, state: &HQMGameState, _messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+            if let Some(HQMBlackboardValue::Index(slot)) = self.blackboard.get("attacker") {
+                self.seen.lock().unwrap().push(slot);
+            }
+            self.blackboard.set("attacker", HQMBlackboardValue::Index(self.blackboard.slot()));
+            let join = state.me().is_none();
+            (HQMPlayerInput {
+                join_red: join && self.team == HQMTeam::Red,
+                join_blue: join && self.team == HQMTeam::Blue,
+                ..Default::default()
+            }, None)
+        }
+    }
+
+    #[tokio::test]
+    async fn bots_share_a_blackboard() {
+        let server = MockServer::start(MockServerConfig::default()).await.unwrap();
+        let mut fleet = BotFleet::new(HQMFleetConfig::default());
+        let seen: Vec<Arc<Mutex<Vec<usize>>>> = (0..2).map(|_| Arc::new(Mutex::new(Vec::new()))).collect();
+        for seen in seen.iter() {
+            let seen = seen.clone();
+            fleet.add_bot(format!("Claimer{}", fleet.len()), server.addr(), HQMSessionConfig::default(),
+                          Box::new(move |blackboard| Box::new(Claimer { team: HQMTeam::Red, blackboard, seen: seen.clone() })));
+        }
+        let blackboard = fleet.blackboard(server.addr());
+        let (shutdown, _, task) = spawn(fleet);
+        wait_until(Duration::from_secs(5), "both bots to see a claim", || {
+            seen.iter().all(|x| !x.lock().unwrap().is_empty()) && seen[0].lock().unwrap().contains(&1)
+        }).await;
+        shutdown.stop();
+        task.await.unwrap();
+
+        // Slot 1 wins when both claim in the same step
+        assert!(seen[0].lock().unwrap().contains(&1));
+        assert!(!seen[1].lock().unwrap().is_empty());
+        assert!(blackboard.latest(HQMTeam::Red).contains_key("attacker"));
+    }
+
+    #[tokio::test]
+    async fn teams_have_their_own_keys() {
+        let server = MockServer::start(MockServerConfig::default()).await.unwrap();
+        let mut fleet = BotFleet::new(HQMFleetConfig::default());
+        let seen: Vec<Arc<Mutex<Vec<usize>>>> = (0..2).map(|_| Arc::new(Mutex::new(Vec::new()))).collect();
+        for (seen, team) in seen.iter().zip([HQMTeam::Red, HQMTeam::Blue].iter().copied()) {
+            let seen = seen.clone();
+            fleet.add_bot(format!("Claimer{}", fleet.len()), server.addr(), HQMSessionConfig::default(),
+                          Box::new(move |blackboard| Box::new(Claimer { team, blackboard, seen: seen.clone() })));
+        }
+        let blackboard = fleet.blackboard(server.addr());
+        let (shutdown, _, task) = spawn(fleet);
+        wait_until(Duration::from_secs(5), "both bots to read the blackboard", || {
+            seen.iter().all(|x| x.lock().unwrap().len() > 10)
+        }).await;
+        shutdown.stop();
+        task.await.unwrap();
+
+        // Each bot only ever sees its own claim
+        assert!(seen[0].lock().unwrap().iter().all(|&slot| slot == 0));
+        assert!(seen[1].lock().unwrap().iter().all(|&slot| slot == 1));
+        assert_eq!(blackboard.latest(HQMTeam::Red)["attacker"].slot, 0);
+        assert_eq!(blackboard.latest(HQMTeam::Blue)["attacker"].slot, 1);
+        server.stop().await;
+    }
+
+    #[test]
+    fn bots_record_to_their_own_replay() {
+        assert_eq!(bot_replay_path(Path::new("replays/game.hqmr"), 2, "Bot 1/x"), PathBuf::from("replays/game-2-Bot_1_x.hqmr"));
+        assert_eq!(bot_replay_path(Path::new("game"), 0, "A"), PathBuf::from("game-0-A"));
+    }
+}
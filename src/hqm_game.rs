@@ -1,5 +1,9 @@
-use nalgebra::{Matrix3, Point3, Vector2};
-use std::collections::HashMap;
+use nalgebra::{Matrix3, Point3, Vector2, Vector3};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use crate::hqm_rink::Rink;
+use crate::hqm_possession::HQMPossession;
+use crate::hqm_clock::GameClock;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum HQMTeam {
@@ -7,7 +11,7 @@ pub enum HQMTeam {
     Blue,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct HQMPlayerInput {
     pub stick_angle: f32,
     pub turn: f32,
@@ -57,7 +61,84 @@ pub struct HQMGameState {
     pub players: HashMap<usize, HQMPlayer>,
 
     pub game_id: u32,
-    pub step: u32
+    pub step: u32,
+    pub rink: Arc<Rink>,
+    pub possession: HQMPossession,
+    pub clock: GameClock,
+}
+
+/// A player joined with the skater it controls
+#[derive(Debug, Copy, Clone)]
+pub struct HQMSkaterView<'a> {
+    pub player: &'a HQMPlayer,
+    pub team: HQMTeam,
+    pub object_index: usize,
+    pub skater: &'a HQMGameStateSkater,
+}
+
+impl HQMGameState {
+    /// Our own skater, if we are on the ice
+    pub fn me(&self) -> Option<HQMSkaterView<'_>> {
+        self.skater_for_player(self.yourself)
+    }
+
+    pub fn my_player(&self) -> Option<&HQMPlayer> {
+        self.players.get(&self.yourself)
+    }
+
+    /// None while spectating
+    pub fn my_team(&self) -> Option<HQMTeam> {
+        self.my_player()
+            .and_then(|player| player.object_index)
+            .map(|(_, team)| team)
+    }
+
+    pub fn skater_for_player(&self, index: usize) -> Option<HQMSkaterView<'_>> {
+        let player = self.players.get(&index)?;
+        let (object_index, team) = player.object_index?;
+        match self.objects.get(object_index) {
+            Some(HQMGameStateObject::Skater(skater)) => Some(HQMSkaterView {
+                player,
+                team,
+                object_index,
+                skater
+            }),
+            _ => None
+        }
+    }
+
+    /// All skaters on the ice, ordered by player index
+    pub fn skaters(&self) -> Vec<HQMSkaterView<'_>> {
+        let mut indices: Vec<usize> = self.players.keys().copied().collect();
+        indices.sort_unstable();
+        indices.into_iter().filter_map(|index| self.skater_for_player(index)).collect()
+    }
+
+    /// Skaters on our team, not including ourselves
+    pub fn teammates(&self) -> Vec<HQMSkaterView<'_>> {
+        match self.my_team() {
+            Some(team) => self.skaters().into_iter()
+                .filter(|x| x.team == team && x.player.index != self.yourself)
+                .collect(),
+            None => vec![]
+        }
+    }
+
+    pub fn opponents(&self) -> Vec<HQMSkaterView<'_>> {
+        match self.my_team() {
+            Some(team) => self.skaters().into_iter()
+                .filter(|x| x.team != team)
+                .collect(),
+            None => vec![]
+        }
+    }
+
+    pub fn puck(&self) -> Option<&HQMGameStatePuck> {
+        self.objects.iter().find_map(|x| match x {
+            HQMGameStateObject::Puck(puck) => Some(puck),
+            _ => None
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -67,6 +148,10 @@ pub enum HQMGameStateObject {
     Puck(HQMGameStatePuck)
 }
 
+// Velocities are in metres (or radians) per step, there are 100 steps per second, and
+// accelerations in metres per step per step. Velocities are zero until a second packet with
+// the same object has been received, accelerations until a third.
+
 #[derive(Debug, Clone)]
 pub struct HQMGameStateSkater {
     pub pos: Point3<f32>,
@@ -75,12 +160,126 @@ pub struct HQMGameStateSkater {
     pub stick_rot: Matrix3<f32>,
     pub head_rot: f32,
     pub body_rot: f32,
+    pub vel: Vector3<f32>,
+    pub angular_vel: Vector3<f32>,
+    pub acc: Vector3<f32>,
+    pub stick_vel: Vector3<f32>,
 }
 
 #[derive(Debug, Clone)]
 pub struct HQMGameStatePuck {
     pub pos: Point3<f32>,
     pub rot: Matrix3<f32>,
+    pub vel: Vector3<f32>,
+    pub angular_vel: Vector3<f32>,
+    pub acc: Vector3<f32>,
+}
+
+const MOTION_HISTORY_LEN: usize = 8;
+
+#[derive(Debug)]
+struct HQMMotionFrame {
+    step: u32,
+    objects: Vec<HQMGameStateObject>,
+    /// Player and team controlling each skater slot, a slot given to someone else is a new object
+    owners: Vec<Option<(usize, HQMTeam)>>,
+    /// Whether the velocity of each object could be computed
+    moving: Vec<bool>,
+}
+
+/// Object states from recently received packets, keyed on step. Packets can be lost or
+/// arrive out of order, so velocities are computed against the latest earlier step that
+/// was actually received.
+#[derive(Debug, Default)]
+pub struct HQMMotionHistory {
+    history: VecDeque<HQMMotionFrame>,
+}
+
+impl HQMMotionHistory {
+    pub fn new() -> Self {
+        HQMMotionHistory { history: VecDeque::new() }
+    }
+
+    /// Fills in the velocities and accelerations of `objects` and remembers them for later steps
+    pub fn update(&mut self, step: u32, objects: &mut [HQMGameStateObject], players: &HashMap<usize, HQMPlayer>) {
+        let mut owners = vec![None; objects.len()];
+        for player in players.values() {
+            if let Some((object_index, team)) = player.object_index {
+                if let Some(owner) = owners.get_mut(object_index) {
+                    *owner = Some((player.index, team));
+                }
+            }
+        }
+        let mut moving = vec![false; objects.len()];
+
+        let previous = self.history.iter()
+            .filter(|frame| frame.step < step)
+            .max_by_key(|frame| frame.step);
+        if let Some(previous) = previous {
+            let steps = (step - previous.step) as f32;
+            for (i, object) in objects.iter_mut().enumerate() {
+                if owners[i] != previous.owners.get(i).copied().flatten() {
+                    continue;
+                }
+                let was_moving = previous.moving.get(i).copied().unwrap_or(false);
+                match (object, previous.objects.get(i)) {
+                    (HQMGameStateObject::Skater(skater), Some(HQMGameStateObject::Skater(old))) => {
+                        skater.vel = (skater.pos - old.pos) / steps;
+                        skater.angular_vel = angular_velocity(&old.rot, &skater.rot, steps);
+                        skater.stick_vel = (skater.stick_pos - old.stick_pos) / steps;
+                        if was_moving {
+                            skater.acc = (skater.vel - old.vel) / steps;
+                        }
+                        moving[i] = true;
+                    }
+                    (HQMGameStateObject::Puck(puck), Some(HQMGameStateObject::Puck(old))) => {
+                        puck.vel = (puck.pos - old.pos) / steps;
+                        puck.angular_vel = angular_velocity(&old.rot, &puck.rot, steps);
+                        if was_moving {
+                            puck.acc = (puck.vel - old.vel) / steps;
+                        }
+                        moving[i] = true;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let frame = HQMMotionFrame {
+            step,
+            objects: objects.to_vec(),
+            owners,
+            moving
+        };
+        // A step seen again replaces the old frame, its velocities may have a closer step to go by now
+        match self.history.iter().position(|frame| frame.step >= step) {
+            Some(pos) if self.history[pos].step == step => self.history[pos] = frame,
+            Some(pos) => self.history.insert(pos, frame),
+            None => self.history.push_back(frame)
+        }
+        if self.history.len() > MOTION_HISTORY_LEN {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.history.clear();
+    }
+}
+
+/// World space rotation vector (axis times angle) per step taking `old` to `new`.
+/// Decoded rotations are only nearly orthonormal, so the angle is taken with atan2
+/// rather than acos, which would give NaN for a trace slightly out of range.
+fn angular_velocity(old: &Matrix3<f32>, new: &Matrix3<f32>, steps: f32) -> Vector3<f32> {
+    let m = new * old.transpose();
+    // Twice the sine of the angle times the axis
+    let axis = Vector3::new(m[(2, 1)] - m[(1, 2)], m[(0, 2)] - m[(2, 0)], m[(1, 0)] - m[(0, 1)]);
+    let sin = axis.norm() / 2.0;
+    if sin < 1e-7 {
+        return Vector3::zeros();
+    }
+    let cos = (m.trace() - 1.0) / 2.0;
+    axis.normalize() * sin.atan2(cos) / steps
 }
 
 #[derive(Debug, Clone)]
@@ -107,4 +306,273 @@ pub enum HQMMessage {
         player_index: Option<usize>,
         message: String,
     },
-}
\ No newline at end of file
+}
+
+/// Helpers for building game states in tests
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    /// A puck that isn't spinning
+    pub fn puck(pos: Point3<f32>, vel: Vector3<f32>) -> HQMGameStateObject {
+        HQMGameStateObject::Puck(HQMGameStatePuck {
+            pos,
+            rot: Matrix3::identity(),
+            vel,
+            angular_vel: Vector3::zeros(),
+            acc: Vector3::zeros()
+        })
+    }
+
+    /// Builds a game state that is the first step of the first period, with an empty rink
+    /// and no players, until told otherwise
+    pub struct HQMGameStateBuilder {
+        state: HQMGameState
+    }
+
+    impl HQMGameStateBuilder {
+        pub fn new() -> Self {
+            HQMGameStateBuilder {
+                state: HQMGameState {
+                    red_score: 0,
+                    blue_score: 0,
+                    time: 30000,
+                    period: 1,
+                    goal_interruption: false,
+                    game_over: false,
+                    objects: vec![],
+                    yourself: 0,
+                    players: HashMap::new(),
+                    game_id: 1,
+                    step: 0,
+                    rink: Arc::new(Rink::standard()),
+                    possession: HQMPossession::default(),
+                    clock: GameClock::default()
+                }
+            }
+        }
+
+        pub fn game_id(mut self, game_id: u32) -> Self {
+            self.state.game_id = game_id;
+            self
+        }
+
+        pub fn score(mut self, red_score: u32, blue_score: u32) -> Self {
+            self.state.red_score = red_score;
+            self.state.blue_score = blue_score;
+            self
+        }
+
+        pub fn clock(mut self, period: u32, time: u32) -> Self {
+            self.state.period = period;
+            self.state.time = time;
+            self
+        }
+
+        pub fn player(mut self, index: usize, name: &str, object_index: Option<(usize, HQMTeam)>) -> Self {
+            self.state.players.insert(index, HQMPlayer { name: name.to_owned(), index, object_index });
+            self
+        }
+
+        pub fn objects(mut self, objects: Vec<HQMGameStateObject>) -> Self {
+            self.state.objects = objects;
+            self
+        }
+
+        pub fn yourself(mut self, yourself: usize) -> Self {
+            self.state.yourself = yourself;
+            self
+        }
+
+        pub fn step(mut self, step: u32) -> Self {
+            self.state.step = step;
+            self
+        }
+
+        pub fn build(self) -> HQMGameState {
+            self.state
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::HQMGameStateBuilder;
+    use nalgebra::Rotation3;
+
+    fn puck(z: f32) -> HQMGameStateObject {
+        HQMGameStateObject::Puck(HQMGameStatePuck {
+            pos: Point3::new(15.0, 0.0, z),
+            rot: Matrix3::identity(),
+            vel: Vector3::zeros(),
+            angular_vel: Vector3::zeros(),
+            acc: Vector3::zeros()
+        })
+    }
+
+    fn skater(z: f32, rot: Matrix3<f32>) -> HQMGameStateObject {
+        HQMGameStateObject::Skater(HQMGameStateSkater {
+            pos: Point3::new(10.0, 1.0, z),
+            rot,
+            stick_pos: Point3::new(10.0, 0.0, z - 1.0),
+            stick_rot: Matrix3::identity(),
+            head_rot: 0.0,
+            body_rot: 0.0,
+            vel: Vector3::zeros(),
+            angular_vel: Vector3::zeros(),
+            acc: Vector3::zeros(),
+            stick_vel: Vector3::zeros()
+        })
+    }
+
+    fn players(owner: Option<(usize, HQMTeam)>) -> HashMap<usize, HQMPlayer> {
+        owner.into_iter().map(|(index, team)| (index, HQMPlayer {
+            name: format!("P{}", index),
+            index,
+            object_index: Some((1, team))
+        })).collect()
+    }
+
+    /// Puck velocity and acceleration along z after an update at `step`
+    fn update_puck(history: &mut HQMMotionHistory, step: u32, z: f32) -> (f32, f32) {
+        let mut objects = vec![puck(z)];
+        history.update(step, &mut objects, &HashMap::new());
+        match &objects[0] {
+            HQMGameStateObject::Puck(puck) => (puck.vel.z, puck.acc.z),
+            _ => unreachable!()
+        }
+    }
+
+    fn close(a: (f32, f32), b: (f32, f32)) -> bool {
+        (a.0 - b.0).abs() < 1e-5 && (a.1 - b.1).abs() < 1e-5
+    }
+
+    #[test]
+    fn velocities_use_the_real_step_gap() {
+        let mut history = HQMMotionHistory::new();
+        assert_eq!(update_puck(&mut history, 10, 0.0), (0.0, 0.0));
+        // Two lost packets
+        assert!(close(update_puck(&mut history, 13, 0.3), (0.1, 0.0)));
+        // Acceleration once there are two velocities to compare
+        assert!(close(update_puck(&mut history, 15, 0.8), (0.25, 0.075)));
+    }
+
+    #[test]
+    fn late_packets_use_the_step_before_them() {
+        let mut history = HQMMotionHistory::new();
+        update_puck(&mut history, 10, 0.0);
+        update_puck(&mut history, 14, 0.8);
+        // Step 12 arrives after 14 and is compared to 10, not 14
+        assert!(close(update_puck(&mut history, 12, 0.2), (0.1, 0.0)));
+        // A repeat of a step is compared to the one before it, not to itself, and later steps go by it
+        assert!(close(update_puck(&mut history, 14, 0.8), (0.3, 0.1)));
+        assert!(close(update_puck(&mut history, 15, 1.0), (0.2, -0.1)));
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut history = HQMMotionHistory::new();
+        update_puck(&mut history, 10, 0.0);
+        update_puck(&mut history, 11, 0.1);
+        history.clear();
+        // A new game starts the steps over
+        assert_eq!(update_puck(&mut history, 5, 20.0), (0.0, 0.0));
+        assert!(close(update_puck(&mut history, 6, 20.5), (0.5, 0.0)));
+    }
+
+    #[test]
+    fn reused_slot_is_a_new_object() {
+        let mut history = HQMMotionHistory::new();
+        let red = players(Some((3, HQMTeam::Red)));
+        let mut objects = vec![puck(0.0), skater(50.0, Matrix3::identity())];
+        history.update(1, &mut objects, &red);
+        let mut objects = vec![puck(0.0), skater(49.9, Matrix3::identity())];
+        history.update(2, &mut objects, &red);
+        match &objects[1] {
+            HQMGameStateObject::Skater(skater) => assert!((skater.vel.z + 0.1).abs() < 1e-5),
+            _ => unreachable!()
+        }
+
+        // Someone else gets the slot and shows up on the other side of the rink
+        let blue = players(Some((4, HQMTeam::Blue)));
+        let mut objects = vec![puck(0.0), skater(10.0, Matrix3::identity())];
+        history.update(3, &mut objects, &blue);
+        match &objects[1] {
+            HQMGameStateObject::Skater(skater) => assert_eq!(skater.vel, Vector3::zeros()),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn angular_velocity_survives_sloppy_rotations() {
+        let old = Matrix3::identity();
+        let new = Rotation3::from_axis_angle(&Vector3::y_axis(), 0.06).into_inner();
+        let vel = angular_velocity(&old, &new, 3.0);
+        assert!((vel - Vector3::new(0.0, 0.02, 0.0)).norm() < 1e-4, "{:?}", vel);
+
+        // Slightly too long columns, as decoded from the network, push the trace past 3
+        let sloppy = Matrix3::identity() * 1.0001;
+        let vel = angular_velocity(&old, &sloppy, 1.0);
+        assert!(vel.iter().all(|x| x.is_finite()), "{:?}", vel);
+        let vel = angular_velocity(&old, &(new * 1.0001), 3.0);
+        assert!((vel - Vector3::new(0.0, 0.02, 0.0)).norm() < 1e-4, "{:?}", vel);
+    }
+
+    /// Players 0 and 1 red, 2 blue, 3 spectating, and 4 blue but pointing at an empty slot
+    fn team_state(yourself: usize) -> HQMGameState {
+        HQMGameStateBuilder::new()
+            .player(0, "P0", Some((1, HQMTeam::Red)))
+            .player(1, "P1", Some((2, HQMTeam::Red)))
+            .player(2, "P2", Some((3, HQMTeam::Blue)))
+            .player(3, "P3", None)
+            .player(4, "P4", Some((5, HQMTeam::Blue)))
+            .objects(vec![puck(30.0), skater(20.0, Matrix3::identity()), skater(25.0, Matrix3::identity()),
+                          skater(40.0, Matrix3::identity()), HQMGameStateObject::None, HQMGameStateObject::None])
+            .yourself(yourself)
+            .build()
+    }
+
+    fn indices(views: Vec<HQMSkaterView<'_>>) -> Vec<usize> {
+        views.iter().map(|x| x.player.index).collect()
+    }
+
+    #[test]
+    fn team_helpers_join_players_and_skaters() {
+        let state = team_state(0);
+        let me = state.me().unwrap();
+        assert_eq!((me.object_index, me.team, me.player.name.as_str()), (1, HQMTeam::Red, "P0"));
+        assert_eq!(me.skater.pos.z, 20.0);
+        assert_eq!(state.my_team(), Some(HQMTeam::Red));
+        assert_eq!(indices(state.teammates()), vec![1]);
+        // Player 4's slot is empty, so it isn't on the ice
+        assert_eq!(indices(state.opponents()), vec![2]);
+        assert_eq!(indices(state.skaters()), vec![0, 1, 2]);
+        assert_eq!(state.puck().unwrap().pos.z, 30.0);
+
+        assert_eq!(state.skater_for_player(2).unwrap().object_index, 3);
+        assert!(state.skater_for_player(3).is_none());
+        assert!(state.skater_for_player(4).is_none());
+        assert!(state.skater_for_player(99).is_none());
+
+        let blue = team_state(2);
+        assert_eq!(blue.my_team(), Some(HQMTeam::Blue));
+        assert!(blue.teammates().is_empty());
+        assert_eq!(indices(blue.opponents()), vec![0, 1]);
+    }
+
+    #[test]
+    fn spectators_have_no_team() {
+        let state = team_state(3);
+        assert!(state.me().is_none());
+        assert_eq!(state.my_player().unwrap().name, "P3");
+        assert_eq!(state.my_team(), None);
+        assert!(state.teammates().is_empty());
+        assert!(state.opponents().is_empty());
+        assert_eq!(indices(state.skaters()), vec![0, 1, 2]);
+
+        let mut no_puck = team_state(0);
+        no_puck.objects[0] = HQMGameStateObject::None;
+        assert!(no_puck.puck().is_none());
+    }
+}
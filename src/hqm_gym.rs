@@ -0,0 +1,756 @@
+use crate::hqm_bot::{HQMBotLogic, HQMBotSession, HQMSessionSummary, HQMShutdownHandle};
+use crate::hqm_clock::{REGULATION_PERIODS, STEPS_PER_SECOND};
+use crate::hqm_events::{other_team, GameEvent};
+use crate::hqm_game::{HQMGameState, HQMMessage, HQMPlayerInput, HQMTeam};
+use crate::hqm_rink::Rink;
+use crate::hqm_sim::{HQMSimConfig, HQMSimObserver, HQMSimulator};
+use nalgebra::{Point3, Vector2, Vector3};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Actions are `ACTION_SIZE` numbers: forward/backward, turn, stick azimuth, stick inclination,
+/// stick angle, and shift, jump and crouch which are pressed above 0.5
+pub const ACTION_SIZE: usize = 8;
+pub const OBSERVATION_SIZE: usize = 69;
+const MAX_TEAMMATES: usize = 4;
+const MAX_OPPONENTS: usize = 5;
+/// Velocities are given in units of 10 metres per second
+const VELOCITY_SCALE: f32 = STEPS_PER_SECOND as f32 / 10.0;
+/// Shots from further away than this have no quality
+const SHOT_QUALITY_RANGE: f32 = 30.0;
+
+pub fn action_to_input(action: &[f32]) -> HQMPlayerInput {
+    let value = |i: usize| action.get(i).copied().filter(|x| x.is_finite()).unwrap_or(0.0);
+    HQMPlayerInput {
+        fwbw: value(0).clamp(-1.0, 1.0),
+        turn: value(1).clamp(-1.0, 1.0),
+        stick: Vector2::new(value(2), value(3)),
+        stick_angle: value(4).clamp(-1.0, 1.0),
+        shift_rotate: value(5) > 0.5,
+        jump: value(6) > 0.5,
+        crouch: value(7) > 0.5,
+        ..Default::default()
+    }
+}
+
+/// Coordinates as seen by one team. Blue's view is turned around so that every team
+/// attacks towards -z, the way red does.
+struct HQMTeamFrame<'a> {
+    rink: &'a Rink,
+    mirrored: bool,
+}
+
+impl<'a> HQMTeamFrame<'a> {
+    fn new(rink: &'a Rink, team: HQMTeam) -> Self {
+        HQMTeamFrame { rink, mirrored: team == HQMTeam::Blue }
+    }
+
+    fn pos(&self, pos: &Point3<f32>) -> Point3<f32> {
+        if self.mirrored {
+            Point3::new(self.rink.width - pos.x, pos.y, self.rink.length - pos.z)
+        } else {
+            *pos
+        }
+    }
+
+    fn dir(&self, dir: &Vector3<f32>) -> Vector3<f32> {
+        if self.mirrored {
+            Vector3::new(-dir.x, dir.y, -dir.z)
+        } else {
+            *dir
+        }
+    }
+}
+
+/// Fixed-size features of `state` from the point of view of our own player, `OBSERVATION_SIZE` long:
+///
+/// - our skater (11): position across and along the rink as fractions, height, velocity (2),
+///   facing direction (2), stick relative to the skater (3), 1 if on the ice
+/// - the puck (6): position relative to our skater (3), velocity (3)
+/// - possession (3): 1 if we, a teammate or an opponent has the puck
+/// - the game (4): minutes left of the period, 1 during a goal break, our goal difference, period
+/// - up to 4 teammates and 5 opponents (5 each): 1 if present, position relative to our skater (2), velocity (2)
+///
+/// Everything is zero for players that aren't there. Positions and velocities are mirrored for blue,
+/// so our team always attacks towards -z.
+pub fn observation(state: &HQMGameState) -> Vec<f32> {
+    let mut obs = Vec::with_capacity(OBSERVATION_SIZE);
+    let team = state.my_team().unwrap_or(HQMTeam::Red);
+    let frame = HQMTeamFrame::new(&state.rink, team);
+    let me = state.me();
+    let origin = me.map_or_else(|| state.rink.center(), |x| x.skater.pos);
+
+    match me {
+        Some(me) => {
+            let pos = frame.pos(&me.skater.pos);
+            let vel = frame.dir(&me.skater.vel) * VELOCITY_SCALE;
+            let forward = frame.dir(&(me.skater.rot * -Vector3::z()));
+            let stick = frame.dir(&(me.skater.stick_pos - me.skater.pos));
+            obs.extend_from_slice(&[pos.x / state.rink.width, pos.y, pos.z / state.rink.length, vel.x, vel.z]);
+            obs.extend_from_slice(&[forward.x, forward.z, stick.x, stick.y, stick.z, 1.0]);
+        }
+        None => obs.extend_from_slice(&[0.0; 11])
+    }
+
+    match state.puck() {
+        Some(puck) => {
+            let pos = frame.dir(&(puck.pos - origin));
+            let vel = frame.dir(&puck.vel) * VELOCITY_SCALE;
+            obs.extend_from_slice(&[pos.x, pos.y, pos.z, vel.x, vel.y, vel.z]);
+        }
+        None => obs.extend_from_slice(&[0.0; 6])
+    }
+
+    let holder = state.possession.holder;
+    let flag = |x: bool| if x { 1.0 } else { 0.0 };
+    obs.push(flag(holder.is_some_and(|x| x.player_index == state.yourself)));
+    obs.push(flag(holder.is_some_and(|x| x.player_index != state.yourself && x.team == team)));
+    obs.push(flag(holder.is_some_and(|x| x.team != team)));
+
+    let (ours, theirs) = match team {
+        HQMTeam::Red => (state.red_score, state.blue_score),
+        HQMTeam::Blue => (state.blue_score, state.red_score),
+    };
+    obs.push(state.clock.time as f32 / (60 * STEPS_PER_SECOND) as f32);
+    obs.push(flag(state.clock.in_goal_break()));
+    obs.push(ours as f32 - theirs as f32);
+    obs.push(state.clock.period as f32 / REGULATION_PERIODS as f32);
+
+    for (skaters, max) in [(state.teammates(), MAX_TEAMMATES), (state.opponents(), MAX_OPPONENTS)].iter() {
+        for i in 0..*max {
+            match skaters.get(i) {
+                Some(x) => {
+                    let pos = frame.dir(&(x.skater.pos - origin));
+                    let vel = frame.dir(&x.skater.vel) * VELOCITY_SCALE;
+                    obs.extend_from_slice(&[1.0, pos.x, pos.z, vel.x, vel.z]);
+                }
+                None => obs.extend_from_slice(&[0.0; 5])
+            }
+        }
+    }
+    debug_assert_eq!(obs.len(), OBSERVATION_SIZE);
+    obs
+}
+
+/// Weights of the things our team is rewarded or punished for
+#[derive(Debug, Clone)]
+pub struct HQMRewardConfig {
+    pub goal_for: f32,
+    pub goal_against: f32,
+    /// Per step our own player has the puck
+    pub possession: f32,
+    /// Per step a teammate has the puck
+    pub team_possession: f32,
+    /// Per step an opponent has the puck
+    pub opponent_possession: f32,
+    /// Per shot on goal, times the shot quality between 0 and 1
+    pub shot_on_goal: f32,
+    pub shot_against: f32,
+}
+
+impl Default for HQMRewardConfig {
+    fn default() -> Self {
+        HQMRewardConfig {
+            goal_for: 1.0,
+            goal_against: -1.0,
+            possession: 0.001,
+            team_possession: 0.0005,
+            opponent_possession: -0.0005,
+            shot_on_goal: 0.1,
+            shot_against: -0.1
+        }
+    }
+}
+
+impl HQMRewardConfig {
+    /// Reward for the step from `previous` to `state`, for the team our own player is on
+    pub fn reward(&self, previous: Option<&HQMGameState>, state: &HQMGameState, events: &[GameEvent]) -> f32 {
+        let team = match state.my_team().or_else(|| previous.and_then(|x| x.my_team())) {
+            Some(team) => team,
+            None => return 0.0
+        };
+        let mut reward = 0.0;
+
+        if let Some(previous) = previous.filter(|x| x.game_id == state.game_id) {
+            let red = state.red_score.saturating_sub(previous.red_score) as f32;
+            let blue = state.blue_score.saturating_sub(previous.blue_score) as f32;
+            let (scored, conceded) = match team {
+                HQMTeam::Red => (red, blue),
+                HQMTeam::Blue => (blue, red),
+            };
+            reward += scored * self.goal_for + conceded * self.goal_against;
+        }
+
+        if let Some(holder) = state.possession.holder {
+            reward += if holder.player_index == state.yourself {
+                self.possession
+            } else if holder.team == team {
+                self.team_possession
+            } else {
+                self.opponent_possession
+            };
+        }
+
+        for event in events {
+            if let GameEvent::ShotOnGoal { team: shooting_team, .. } = event {
+                let weight = if *shooting_team == team { self.shot_on_goal } else { self.shot_against };
+                reward += weight * shot_quality(state, *shooting_team);
+            }
+        }
+        reward
+    }
+}
+
+/// How good a shot from the current puck position is, from 1 right at the net to 0 from far away
+fn shot_quality(state: &HQMGameState, shooting_team: HQMTeam) -> f32 {
+    match state.puck() {
+        Some(puck) => {
+            let net = state.rink.net_center(other_team(shooting_team));
+            let distance = (puck.pos - net).xz().norm();
+            (1.0 - distance / SHOT_QUALITY_RANGE).max(0.0)
+        }
+        None => 0.0
+    }
+}
+
+/// Reinforcement learning environment with one player controlled by the trainer
+pub trait HQMEnv {
+    /// Starts a new episode and returns the first observation
+    fn reset(&mut self) -> Vec<f32>;
+    /// Plays `action` and returns the next observation, the reward and whether the episode is over
+    fn step(&mut self, action: &[f32]) -> (Vec<f32>, f32, bool);
+}
+
+#[derive(Debug, Clone)]
+pub struct HQMSimEnvConfig {
+    pub sim: HQMSimConfig,
+    pub team: HQMTeam,
+    pub reward: HQMRewardConfig,
+    /// Simulation steps each action is held for
+    pub action_repeat: u32,
+    /// Episode length in actions, zero to play until the game is over
+    pub max_actions: u32,
+}
+
+impl Default for HQMSimEnvConfig {
+    fn default() -> Self {
+        HQMSimEnvConfig {
+            sim: HQMSimConfig::default(),
+            team: HQMTeam::Red,
+            reward: HQMRewardConfig::default(),
+            action_repeat: 1,
+            max_actions: 0
+        }
+    }
+}
+
+struct HQMSimBot {
+    player_index: usize,
+    team: HQMTeam,
+    observer: HQMSimObserver,
+    logic: Box<dyn HQMBotLogic + Send>,
+}
+
+/// Environment over a local simulation. Other players are driven by bot logic.
+pub struct HQMSimEnv {
+    config: HQMSimEnvConfig,
+    sim: HQMSimulator,
+    player_index: usize,
+    observer: HQMSimObserver,
+    bots: Vec<HQMSimBot>,
+    previous: Option<HQMGameState>,
+    actions: u32,
+}
+
+impl HQMSimEnv {
+    pub fn new(config: HQMSimEnvConfig) -> Self {
+        let mut sim = HQMSimulator::new(config.sim.clone());
+        let player_index = sim.add_player("Agent").unwrap();
+        let observer = HQMSimObserver::new(&sim, player_index);
+        HQMSimEnv {
+            config,
+            sim,
+            player_index,
+            observer,
+            bots: Vec::new(),
+            previous: None,
+            actions: 0
+        }
+    }
+
+    /// Adds a player driven by `logic`. It goes on the ice at the next reset.
+    pub fn add_bot(&mut self, name: &str, team: HQMTeam, logic: Box<dyn HQMBotLogic + Send>) -> Option<usize> {
+        let player_index = self.sim.add_player(name)?;
+        self.bots.push(HQMSimBot {
+            player_index,
+            team,
+            observer: HQMSimObserver::new(&self.sim, player_index),
+            logic
+        });
+        Some(player_index)
+    }
+
+    pub fn simulator(&self) -> &HQMSimulator {
+        &self.sim
+    }
+
+    pub fn simulator_mut(&mut self) -> &mut HQMSimulator {
+        &mut self.sim
+    }
+
+    /// The state behind the latest observation
+    pub fn state(&self) -> Option<&HQMGameState> {
+        self.previous.as_ref()
+    }
+
+    fn step_bots(&mut self) {
+        for bot in self.bots.iter_mut() {
+            let tick = bot.observer.observe(&self.sim);
+            if !tick.events.is_empty() {
+                bot.logic.game_events(&tick.state, &tick.events);
+            }
+            let (input, chat) = bot.logic.tick(&tick.state, &tick.messages);
+            if let Some(chat) = chat {
+                self.sim.chat(Some(bot.player_index), &chat);
+            }
+            self.sim.set_input(bot.player_index, input);
+        }
+    }
+}
+
+impl HQMEnv for HQMSimEnv {
+    fn reset(&mut self) -> Vec<f32> {
+        self.sim.new_game();
+        let teams = std::iter::once((self.player_index, self.config.team))
+            .chain(self.bots.iter().map(|x| (x.player_index, x.team)));
+        for (player_index, team) in teams {
+            self.sim.set_input(player_index, HQMPlayerInput {
+                join_red: team == HQMTeam::Red,
+                join_blue: team == HQMTeam::Blue,
+                ..Default::default()
+            });
+        }
+        self.sim.step();
+        self.sim.set_input(self.player_index, HQMPlayerInput::default());
+        for bot in self.bots.iter_mut() {
+            bot.logic.new_game();
+            self.sim.set_input(bot.player_index, HQMPlayerInput::default());
+        }
+
+        let tick = self.observer.observe(&self.sim);
+        let obs = observation(&tick.state);
+        self.previous = Some(tick.state);
+        self.actions = 0;
+        obs
+    }
+
+    fn step(&mut self, action: &[f32]) -> (Vec<f32>, f32, bool) {
+        self.sim.set_input(self.player_index, action_to_input(action));
+        let mut reward = 0.0;
+        for _ in 0..self.config.action_repeat.max(1) {
+            self.step_bots();
+            self.sim.step();
+            let tick = self.observer.observe(&self.sim);
+            reward += self.config.reward.reward(self.previous.as_ref(), &tick.state, &tick.events);
+            self.previous = Some(tick.state);
+            if self.sim.game_over() {
+                break;
+            }
+        }
+        self.actions += 1;
+
+        let state = self.previous.as_ref().unwrap();
+        let done = state.game_over || (self.config.max_actions > 0 && self.actions >= self.config.max_actions);
+        (observation(state), reward, done)
+    }
+}
+
+/// Hands game updates to a `HQMLiveEnv` and waits for its answer
+struct HQMEnvBridgeBot {
+    team: HQMTeam,
+    updates: Sender<(HQMGameState, Vec<GameEvent>)>,
+    actions: Receiver<HQMPlayerInput>,
+    events: Vec<GameEvent>,
+}
+
+impl HQMBotLogic for HQMEnvBridgeBot {
+    fn new_game(&mut self) {}
+
+    fn game_events(&mut self, _state: &HQMGameState, events: &[GameEvent]) {
+        self.events.extend_from_slice(events);
+    }
+
+    fn tick(&mut self, state: &HQMGameState, _messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+        let events = std::mem::take(&mut self.events);
+        if state.me().is_none() {
+            let input = HQMPlayerInput {
+                join_red: self.team == HQMTeam::Red,
+                join_blue: self.team == HQMTeam::Blue,
+                ..Default::default()
+            };
+            return (input, None);
+        }
+        if self.updates.send((state.clone(), events)).is_err() {
+            return (HQMPlayerInput::default(), None);
+        }
+        (self.actions.recv().unwrap_or_default(), None)
+    }
+}
+
+/// Environment over a live server. The session runs on its own thread, in a current-thread
+/// runtime of its own, and waits for every action, so a trainer slower than the server's 100 updates per second makes the
+/// bot fall behind. There is no way to restart a live game, so `reset` only waits for
+/// the next update, and episodes end when the game does. If the session ends, `reset`
+/// gives an all-zero observation and `step` says the episode is over.
+pub struct HQMLiveEnv {
+    reward: HQMRewardConfig,
+    updates: Receiver<(HQMGameState, Vec<GameEvent>)>,
+    actions: Sender<HQMPlayerInput>,
+    shutdown: HQMShutdownHandle,
+    session: Option<JoinHandle<io::Result<HQMSessionSummary>>>,
+    previous: Option<HQMGameState>,
+    awaiting_action: bool,
+}
+
+impl HQMLiveEnv {
+    /// Joins `server` as `name` and plays for `team`
+    pub fn connect(name: String, server: SocketAddr, team: HQMTeam, reward: HQMRewardConfig) -> Self {
+        let (update_sender, updates) = mpsc::channel();
+        let (actions, action_receiver) = mpsc::channel();
+        let mut session = HQMBotSession::new(name, HQMEnvBridgeBot {
+            team,
+            updates: update_sender,
+            actions: action_receiver,
+            events: Vec::new()
+        });
+        let shutdown = session.shutdown_handle();
+        let session = thread::spawn(move || {
+            // The bot blocks while it waits for an action, which only holds up this runtime
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(session.start(server))
+        });
+        HQMLiveEnv {
+            reward,
+            updates,
+            actions,
+            shutdown,
+            session: Some(session),
+            previous: None,
+            awaiting_action: false
+        }
+    }
+
+    /// Leaves the server and waits for the session to end
+    pub fn close(mut self) -> io::Result<HQMSessionSummary> {
+        self.shutdown.stop();
+        // Unblocks the session if it is waiting for an action
+        let (actions, _) = mpsc::channel();
+        self.actions = actions;
+        match self.session.take().unwrap().join() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::other("session thread panicked"))
+        }
+    }
+
+    fn next_state(&mut self, action: HQMPlayerInput) -> Option<(HQMGameState, Vec<GameEvent>)> {
+        if self.awaiting_action {
+            self.awaiting_action = false;
+            self.actions.send(action).ok()?;
+        }
+        let update = self.updates.recv().ok()?;
+        self.awaiting_action = true;
+        Some(update)
+    }
+}
+
+impl HQMEnv for HQMLiveEnv {
+    fn reset(&mut self) -> Vec<f32> {
+        self.previous = self.next_state(HQMPlayerInput::default()).map(|(state, _)| state);
+        self.previous.as_ref().map_or_else(|| vec![0.0; OBSERVATION_SIZE], observation)
+    }
+
+    fn step(&mut self, action: &[f32]) -> (Vec<f32>, f32, bool) {
+        match self.next_state(action_to_input(action)) {
+            Some((state, events)) => {
+                let reward = self.reward.reward(self.previous.as_ref(), &state, &events);
+                let obs = observation(&state);
+                let done = state.game_over;
+                self.previous = Some(state);
+                (obs, reward, done)
+            }
+            None => (vec![0.0; OBSERVATION_SIZE], 0.0, true)
+        }
+    }
+}
+
+impl Drop for HQMLiveEnv {
+    fn drop(&mut self) {
+        self.shutdown.stop();
+    }
+}
+
+/// Several environments stepped in parallel, one thread each
+pub struct HQMVecEnv<E: HQMEnv + Send> {
+    envs: Vec<E>,
+}
+
+impl<E: HQMEnv + Send> HQMVecEnv<E> {
+    pub fn new(envs: Vec<E>) -> Self {
+        HQMVecEnv { envs }
+    }
+
+    pub fn len(&self) -> usize {
+        self.envs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+    pub fn envs(&self) -> &[E] {
+        &self.envs
+    }
+
+    pub fn envs_mut(&mut self) -> &mut [E] {
+        &mut self.envs
+    }
+
+    pub fn reset(&mut self) -> Vec<Vec<f32>> {
+        self.parallel(|_, env| env.reset())
+    }
+
+    /// Steps every environment with its own action. Environments whose episode ended
+    /// are reset, and give the first observation of the new episode.
+    pub fn step(&mut self, actions: &[Vec<f32>]) -> Vec<(Vec<f32>, f32, bool)> {
+        assert_eq!(actions.len(), self.envs.len(), "one action per environment");
+        self.parallel(|i, env| {
+            let (obs, reward, done) = env.step(&actions[i]);
+            if done {
+                (env.reset(), reward, done)
+            } else {
+                (obs, reward, done)
+            }
+        })
+    }
+
+    fn parallel<T: Send, F: Fn(usize, &mut E) -> T + Sync>(&mut self, f: F) -> Vec<T> {
+        if self.envs.len() == 1 {
+            return vec![f(0, &mut self.envs[0])];
+        }
+        let f = &f;
+        thread::scope(|scope| {
+            let threads: Vec<_> = self.envs.iter_mut().enumerate()
+                .map(|(i, env)| scope.spawn(move || f(i, env)))
+                .collect();
+            threads.into_iter().map(|x| x.join().unwrap()).collect()
+        })
+    }
+}
+
+/// Lets a trainer in another process drive `envs` over a line based text protocol.
+/// Every request and answer is one line of words separated by spaces:
+///
+/// - `spec` answers `ok <environments> <observation size> <action size>`
+/// - `reset` answers `ok` and the observations of every environment
+/// - `step` with the actions of every environment answers `ok`, the rewards, the done
+///   flags as 0 or 1 and the observations. Finished environments are reset.
+/// - `close` ends the bridge
+///
+/// A request that can't be handled is answered with `error` and the reason.
+pub fn run_bridge<E: HQMEnv + Send, R: BufRead, W: Write>(envs: &mut HQMVecEnv<E>, input: R, mut output: W) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let answer = match words.next() {
+            Some("spec") => format!("ok {} {} {}", envs.len(), OBSERVATION_SIZE, ACTION_SIZE),
+            Some("reset") => {
+                let mut answer = "ok".to_owned();
+                envs.reset().iter().flatten().for_each(|x| push_number(&mut answer, *x));
+                answer
+            }
+            Some("step") => match parse_actions(words, envs.len()) {
+                Ok(actions) => {
+                    let results = envs.step(&actions);
+                    let mut answer = "ok".to_owned();
+                    results.iter().for_each(|(_, reward, _)| push_number(&mut answer, *reward));
+                    results.iter().for_each(|(_, _, done)| answer.push_str(if *done { " 1" } else { " 0" }));
+                    results.iter().flat_map(|(obs, _, _)| obs).for_each(|x| push_number(&mut answer, *x));
+                    answer
+                }
+                Err(e) => format!("error {}", e)
+            },
+            Some("close") => return Ok(()),
+            Some(command) => format!("error unknown command {}", command),
+            None => continue
+        };
+        writeln!(output, "{}", answer)?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Waits for one trainer to connect to `addr` and runs the bridge over that connection
+pub fn serve_bridge<E: HQMEnv + Send>(envs: &mut HQMVecEnv<E>, addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    run_bridge(envs, BufReader::new(stream.try_clone()?), stream)
+}
+
+fn parse_actions<'a>(words: impl Iterator<Item = &'a str>, envs: usize) -> Result<Vec<Vec<f32>>, String> {
+    let values = words.map(|x| x.parse::<f32>().map_err(|_| format!("bad number {}", x)))
+        .collect::<Result<Vec<f32>, String>>()?;
+    if values.len() != envs * ACTION_SIZE {
+        return Err(format!("expected {} numbers, got {}", envs * ACTION_SIZE, values.len()));
+    }
+    Ok(values.chunks(ACTION_SIZE).map(|x| x.to_vec()).collect())
+}
+
+fn push_number(s: &mut String, v: f32) {
+    s.push(' ');
+    s.push_str(&v.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_bot::testing::{game_update, new_game, ScriptedServer};
+    use crate::hqm_parse::{ClientPacket, HQMObjectPacket, HQMSkaterPacket};
+
+    /// Skates straight ahead
+    struct Skater;
+
+    impl HQMBotLogic for Skater {
+        fn new_game(&mut self) {}
+
+        fn tick(&mut self, _state: &HQMGameState, _messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+            (HQMPlayerInput { fwbw: 1.0, ..Default::default() }, None)
+        }
+    }
+
+    #[test]
+    fn observations_are_the_same_for_both_teams() {
+        let mut red = HQMSimEnv::new(HQMSimEnvConfig::default());
+        let mut blue = HQMSimEnv::new(HQMSimEnvConfig { team: HQMTeam::Blue, ..Default::default() });
+        red.add_bot("Opponent", HQMTeam::Blue, Box::new(Skater));
+        blue.add_bot("Opponent", HQMTeam::Red, Box::new(Skater));
+        let red_obs = red.reset();
+        let blue_obs = blue.reset();
+        assert_eq!(red_obs.len(), OBSERVATION_SIZE);
+        assert_eq!(red.state().unwrap().opponents().len(), 1);
+        // On the ice, facing the other net
+        assert_eq!(red_obs[10], 1.0);
+        assert!(red_obs[6] < -0.99);
+        for (a, b) in red_obs.iter().zip(blue_obs.iter()) {
+            assert!((a - b).abs() < 0.01, "{:?} {:?}", red_obs, blue_obs);
+        }
+
+        let action = [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        for _ in 0..50 {
+            red.step(&action);
+            blue.step(&action);
+        }
+        let (red_obs, _, _) = red.step(&action);
+        let (blue_obs, _, _) = blue.step(&action);
+        assert!(red_obs[4] < -0.05);
+        for (a, b) in red_obs.iter().zip(blue_obs.iter()) {
+            assert!((a - b).abs() < 0.01, "{:?} {:?}", red_obs, blue_obs);
+        }
+    }
+
+    #[test]
+    fn goals_and_shots_are_rewarded() {
+        let mut env = HQMSimEnv::new(HQMSimEnvConfig { action_repeat: 10, ..Default::default() });
+        env.reset();
+        // Red attacks the net at low z
+        env.simulator_mut().place_puck(Point3::new(15.0, 0.0206, 8.0), Vector3::new(0.0, 0.0, -0.1));
+        let mut total = 0.0;
+        for _ in 0..10 {
+            let (_, reward, done) = env.step(&[0.0; ACTION_SIZE]);
+            assert!(!done);
+            total += reward;
+        }
+        assert_eq!(env.state().unwrap().red_score, 1);
+        assert!(total > 1.05, "{}", total);
+
+        let mut env = HQMSimEnv::new(HQMSimEnvConfig { team: HQMTeam::Blue, ..Default::default() });
+        env.reset();
+        env.simulator_mut().place_puck(Point3::new(15.0, 0.0206, 8.0), Vector3::new(0.0, 0.0, -0.1));
+        let total: f32 = (0..100).map(|_| env.step(&[0.0; ACTION_SIZE]).1).sum();
+        assert!(total < -1.05, "{}", total);
+    }
+
+    #[test]
+    fn bridge_drives_batched_environments() {
+        let config = HQMSimEnvConfig { max_actions: 2, ..Default::default() };
+        let mut envs = HQMVecEnv::new(vec![HQMSimEnv::new(config.clone()), HQMSimEnv::new(config)]);
+        let zeros = vec!["0"; 2 * ACTION_SIZE].join(" ");
+        let input = format!("spec\nreset\nstep {}\nstep 1 2\njump\nstep {}\nclose\nspec\n", zeros, zeros);
+        let mut output = Vec::new();
+        run_bridge(&mut envs, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], format!("ok 2 {} {}", OBSERVATION_SIZE, ACTION_SIZE));
+        assert_eq!(lines[1].split(' ').count(), 1 + 2 * OBSERVATION_SIZE);
+        let step: Vec<&str> = lines[2].split(' ').collect();
+        assert_eq!(step.len(), 1 + 2 + 2 + 2 * OBSERVATION_SIZE);
+        assert_eq!(&step[3..5], &["0", "0"]);
+        assert!(lines[3].starts_with("error expected"));
+        assert_eq!(lines[4], "error unknown command jump");
+        let step: Vec<&str> = lines[5].split(' ').collect();
+        assert_eq!(&step[3..5], &["1", "1"]);
+    }
+
+    /// A game where the trainer's player watches for a few updates and is then on the ice for blue
+    fn live_game() -> impl Iterator<Item = Vec<u8>> {
+        let player = |object| HQMMessage::PlayerUpdate {
+            player_name: "Trainer".to_owned(),
+            object,
+            player_index: 0,
+            in_server: true
+        };
+        std::iter::once(new_game(1)).chain((0..).map(move |packet| {
+            if packet < 3 {
+                return game_update(1, packet, Vec::new(), vec![player(None)]);
+            }
+            let skater = HQMObjectPacket::Skater(HQMSkaterPacket {
+                pos: (15 * 1024, 1024, 30 * 1024),
+                rot: (0, 0),
+                stick_pos: (4 * 1024, 4 * 1024, 4 * 1024),
+                stick_rot: (0, 0),
+                head_rot: 16384,
+                body_rot: 16384
+            });
+            game_update(1, packet, vec![skater], vec![player(None), player(Some((0, HQMTeam::Blue)))])
+        }))
+    }
+
+    #[tokio::test]
+    async fn live_env_plays_on_a_server() {
+        let server = ScriptedServer::start(live_game()).await.unwrap();
+        let addr = server.addr();
+        // The env blocks its caller like a trainer would, so it gets a thread of its own
+        let summary = tokio::task::spawn_blocking(move || {
+            let mut env = HQMLiveEnv::connect("Trainer".to_owned(), addr, HQMTeam::Blue, HQMRewardConfig::default());
+            assert_eq!(env.reset().len(), OBSERVATION_SIZE);
+            let mut action = [0.0; ACTION_SIZE];
+            action[0] = 1.0;
+            for _ in 0..20 {
+                let (obs, _, done) = env.step(&action);
+                assert_eq!(obs.len(), OBSERVATION_SIZE);
+                assert!(!done);
+            }
+            env.close().unwrap()
+        }).await.unwrap();
+        assert!(summary.packets_received > 20);
+        let inputs: Vec<HQMPlayerInput> = server.received().into_iter().filter_map(|x| match x {
+            ClientPacket::Update(update) => Some(update.input),
+            _ => None
+        }).collect();
+        assert!(inputs.iter().any(|x| x.join_blue));
+        assert_eq!(inputs.iter().filter(|x| x.fwbw == 1.0).count(), 20);
+        server.stop().await;
+    }
+}
@@ -0,0 +1,201 @@
+//! Reading the `.hrp` replay files saved by the HQM server
+
+use crate::hqm_parse::{self, DeltaBaselineCache, HQMGameUpdatePacket, HQMMessageReader, ProtocolError};
+use crate::hqm_tracker::{HQMGameTracker, HQMTick};
+use std::io;
+use std::path::Path;
+
+/// Version of the .hrp replay files saved by the HQM server
+pub const HRP_VERSION: u32 = 0;
+
+/// Reads `.hrp` replay files. After a header with the version and the size of the data,
+/// a replay has one byte-aligned tick per server step. A tick is a game update without
+/// the game id, step and own player index, and with room for more messages.
+pub struct HQMHrpReader {
+    data: Vec<u8>,
+    pos: usize,
+    baselines: DeltaBaselineCache,
+}
+
+impl HQMHrpReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let data = std::fs::read(path)?;
+        Self::new(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn new(data: Vec<u8>) -> Result<Self, ProtocolError> {
+        let mut parser = HQMMessageReader::new(&data);
+        let version = parser.read_u32_aligned()?;
+        if version != HRP_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+        let size = parser.read_u32_aligned()? as usize;
+        let start = parser.get_pos();
+        if data.len() < start + size {
+            return Err(ProtocolError::TruncatedPacket);
+        }
+        let mut data = data;
+        data.truncate(start + size);
+        Ok(HQMHrpReader {
+            data,
+            pos: start,
+            baselines: DeltaBaselineCache::new()
+        })
+    }
+
+    /// Decodes the next tick. `own_player_index` is `usize::MAX` and `step` is the packet number.
+    pub fn next_update(&mut self) -> Result<Option<HQMGameUpdatePacket>, ProtocolError> {
+        if self.pos >= self.data.len() {
+            return Ok(None);
+        }
+        let mut parser = HQMMessageReader::new(&self.data[self.pos..]);
+        let command = parser.read_byte_aligned()?;
+        if command != 5 {
+            return Err(ProtocolError::UnknownCommand(command));
+        }
+        let update = decode_hrp_tick(&mut parser, &self.baselines)?;
+        parser.align();
+        self.pos += parser.get_pos();
+        self.baselines.insert(update.packet, update.objects.clone());
+        Ok(Some(update))
+    }
+
+    /// Turns the ticks into the same states and messages a bot watching the game would have seen
+    pub fn ticks(self) -> HQMHrpTicks {
+        HQMHrpTicks {
+            reader: self,
+            tracker: HQMGameTracker::default(),
+            failed: false
+        }
+    }
+}
+
+fn decode_hrp_tick(parser: &mut HQMMessageReader, baselines: &DeltaBaselineCache) -> Result<HQMGameUpdatePacket, ProtocolError> {
+    let game_over = parser.read_bits(1)? == 1;
+    let red_score = parser.read_bits(8)?;
+    let blue_score = parser.read_bits(8)?;
+    let time = parser.read_bits(16)?;
+    let goal_time = parser.read_bits(16)?;
+    let period = parser.read_bits(8)?;
+
+    let packet = parser.read_u32_aligned()?;
+    let known_packet = parser.read_u32_aligned()?;
+
+    let objects = hqm_parse::decode_objects(parser, baselines.get(known_packet))?;
+
+    let message_num = parser.read_bits(16)?;
+    let message_pos = parser.read_bits(16)?;
+    let mut messages = Vec::with_capacity(message_num as usize);
+    for _ in 0..message_num {
+        messages.push(hqm_parse::decode_message(parser)?);
+    }
+
+    Ok(HQMGameUpdatePacket {
+        game_id: 0,
+        step: packet,
+        game_over,
+        red_score,
+        blue_score,
+        time,
+        goal_time,
+        period,
+        own_player_index: usize::MAX,
+        packet,
+        known_packet,
+        objects,
+        message_pos,
+        messages
+    })
+}
+
+/// Game states from a `.hrp` replay. Stops after the first tick that can't be decoded.
+pub struct HQMHrpTicks {
+    reader: HQMHrpReader,
+    tracker: HQMGameTracker,
+    failed: bool,
+}
+
+impl Iterator for HQMHrpTicks {
+    type Item = Result<HQMTick, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.reader.next_update() {
+            Ok(Some(update)) => Some(Ok(self.tracker.handle_game_update(update))),
+            Ok(None) => None,
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_game::HQMMessage;
+
+    /// Two ticks as the server saves them. Player 4, "Al", is in the server and scores
+    /// for blue in the first tick and says "gg" in the second, while the puck moves
+    /// 10/1024 along z. Bits are packed from the least significant end of each byte.
+    const HRP: [u8; 114] = [
+        // Version 0 and the size of the ticks
+        0x00, 0x00, 0x00, 0x00, 0x6a, 0x00, 0x00, 0x00,
+
+        // Command 5, then the header bits: not over, 0-1, 12000 left, no goal, period 2
+        0x05, 0x00, 0x02, 0xc0, 0x5d, 0x00, 0x00, 0x04, 0x00,
+        // Packet 50, no known packet
+        0x32, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff,
+        // A puck in slot 0 at (15360, 200, 30000) with rotation (1, 2), written in full,
+        // 31 empty slots, 2 messages from 0, a player update for "Al" without an object
+        // and a goal for blue by player 4 without an assist
+        0x1b, 0x80, 0xc7, 0xc8, 0x00, 0x86, 0xa9, 0x73, 0x00, 0x00, 0x00, 0x60, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x20, 0xfe, 0x07, 0xd9, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x22, 0x7e,
+
+        // Command 5, then the header bits with 11999 left
+        0x05, 0x00, 0x02, 0xbe, 0x5d, 0x00, 0x00, 0x04, 0x00,
+        // Packet 51, known packet 50
+        0x33, 0x00, 0x00, 0x00, 0x32, 0x00, 0x00, 0x00,
+        // The puck as deltas against packet 50, 31 empty slots, 1 message from 2, chat "gg" from player 4
+        0x03, 0x20, 0x05, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x80, 0x00, 0x80, 0x40, 0x08, 0xe7, 0x33,
+    ];
+
+    #[test]
+    fn hrp_ticks_become_game_states() {
+        let file = HRP.to_vec();
+        let ticks: Vec<HQMTick> = HQMHrpReader::new(file.clone()).unwrap().ticks().collect::<Result<_, _>>().unwrap();
+        assert_eq!(ticks.len(), 2);
+        assert_eq!(ticks[0].messages.len(), 2);
+        assert!(matches!(&ticks[0].messages[1], HQMMessage::Goal { goal_player_index: Some(4), assist_player_index: None, .. }));
+        assert_eq!((ticks[0].state.red_score, ticks[0].state.blue_score, ticks[0].state.time), (0, 1, 12000));
+        assert_eq!(ticks[0].state.players[&4].name, "Al");
+        assert!(ticks[0].state.me().is_none());
+        assert_eq!(ticks[1].state.step, 51);
+        assert_eq!(ticks[1].state.clock.period, 2);
+        assert_eq!(ticks[1].state.players[&4].name, "Al");
+        match &ticks[1].messages[..] {
+            [HQMMessage::Chat { player_index: Some(4), message }] => assert_eq!(message, "gg"),
+            x => panic!("unexpected messages {:?}", x)
+        }
+        let puck = ticks[1].state.puck().unwrap();
+        assert_eq!(puck.pos.z, 30010.0 / 1024.0);
+        assert_eq!(puck.vel.z, 10.0 / 1024.0);
+
+        assert_eq!(HQMHrpReader::new(file[0..6].to_vec()).err(), Some(ProtocolError::TruncatedPacket));
+        let mut wrong_version = file.clone();
+        wrong_version[0] = 3;
+        assert_eq!(HQMHrpReader::new(wrong_version).err(), Some(ProtocolError::UnsupportedVersion(3)));
+        // A tick cut short ends the iteration with an error
+        let mut cut = file[0..file.len() - 3].to_vec();
+        let size = (cut.len() - 8) as u32;
+        cut[4..8].copy_from_slice(&size.to_le_bytes());
+        let ticks: Vec<_> = HQMHrpReader::new(cut).unwrap().ticks().collect();
+        assert_eq!(ticks.len(), 2);
+        assert_eq!(ticks[1].as_ref().err(), Some(&ProtocolError::TruncatedPacket));
+    }
+}
@@ -0,0 +1,416 @@
+//! A local stand-in for an HQM server, for testing `HQMBotSession` end to end.
+//! The game itself is run by `HQMSimulator`.
+
+use crate::hqm_game::HQMPlayerInput;
+use crate::hqm_parse::{self, ClientPacket, DeltaBaselineCache, ServerPacket};
+use crate::hqm_sim::{HQMSimConfig, HQMSimulator};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Baselines older than this many packets can't be told apart on the wire
+const BASELINE_WINDOW: u32 = 256;
+
+#[derive(Debug, Clone)]
+pub struct MockServerConfig {
+    pub sim: HQMSimConfig,
+    /// Time between game updates
+    pub step_interval: Duration,
+    /// Share of outgoing packets that are dropped, between 0 and 1
+    pub loss: f64,
+    /// Share of outgoing packets held back and sent after the next one
+    pub reorder: f64,
+    /// Seed for picking the packets to drop or reorder
+    pub seed: u64,
+}
+
+impl Default for MockServerConfig {
+    fn default() -> Self {
+        MockServerConfig {
+            sim: HQMSimConfig::default(),
+            step_interval: Duration::from_millis(1),
+            loss: 0.0,
+            reorder: 0.0,
+            seed: 1
+        }
+    }
+}
+
+/// An update received from a client
+#[derive(Debug, Clone)]
+pub struct HQMMockInput {
+    pub player_index: usize,
+    /// Simulation step when the update arrived
+    pub step: u32,
+    pub game_id: u32,
+    pub input: HQMPlayerInput,
+    pub known_packet: u32,
+    pub known_msgpos: u16,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HQMMockStats {
+    pub packets_sent: u64,
+    pub packets_dropped: u64,
+    pub packets_reordered: u64,
+}
+
+#[derive(Debug)]
+struct HQMMockClient {
+    player_index: usize,
+    game_id: u32,
+    next_packet: u32,
+    known_packet: u32,
+    known_msgpos: u16,
+    chat_rep: Option<u32>,
+    baselines: DeltaBaselineCache,
+}
+
+#[derive(Debug)]
+struct HQMMockState {
+    sim: HQMSimulator,
+    clients: HashMap<SocketAddr, HQMMockClient>,
+    inputs: Vec<HQMMockInput>,
+    chats: Vec<(usize, String)>,
+    stats: HQMMockStats,
+}
+
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<HQMMockState>>,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Binds to a free local port and starts the game loop
+    pub async fn start(config: MockServerConfig) -> io::Result<MockServer> {
+        let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let addr = socket.local_addr()?;
+        let state = Arc::new(Mutex::new(HQMMockState {
+            sim: HQMSimulator::new(config.sim.clone()),
+            clients: HashMap::new(),
+            inputs: Vec::new(),
+            chats: Vec::new(),
+            stats: HQMMockStats::default()
+        }));
+        let (stop, stop_receiver) = watch::channel(false);
+        let task = tokio::spawn(run(socket, state.clone(), config, stop_receiver));
+        Ok(MockServer { addr, state, stop, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Runs `f` on the game between two steps, to script what happens
+    pub fn with_sim<R, F: FnOnce(&mut HQMSimulator) -> R>(&self, f: F) -> R {
+        f(&mut self.state.lock().unwrap().sim)
+    }
+
+    /// Every update received from the clients so far
+    pub fn inputs(&self) -> Vec<HQMMockInput> {
+        self.state.lock().unwrap().inputs.clone()
+    }
+
+    /// Chat messages received, once each, with the player index of the sender
+    pub fn chats(&self) -> Vec<(usize, String)> {
+        self.state.lock().unwrap().chats.clone()
+    }
+
+    pub fn stats(&self) -> HQMMockStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.state.lock().unwrap().clients.len()
+    }
+
+    pub async fn stop(self) {
+        let _ = self.stop.send(true);
+        let _ = self.task.await;
+    }
+}
+
+/// Decides which packets to drop or reorder, the same way for the same seed
+struct HQMLinkShaper {
+    rng: u64,
+    loss: f64,
+    reorder: f64,
+    held: Option<(SocketAddr, Vec<u8>)>,
+}
+
+impl HQMLinkShaper {
+    fn chance(&mut self, p: f64) -> bool {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        p > 0.0 && ((self.rng >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    async fn send(&mut self, socket: &UdpSocket, addr: SocketAddr, data: Vec<u8>, stats: &mut HQMMockStats) {
+        if self.chance(self.loss) {
+            stats.packets_dropped += 1;
+            return;
+        }
+        if self.held.is_none() && self.chance(self.reorder) {
+            stats.packets_reordered += 1;
+            self.held = Some((addr, data));
+            return;
+        }
+        stats.packets_sent += 1;
+        let _ = socket.send_to(&data, addr).await;
+        if let Some((addr, data)) = self.held.take() {
+            stats.packets_sent += 1;
+            let _ = socket.send_to(&data, addr).await;
+        }
+    }
+}
+
+async fn run(socket: UdpSocket, state: Arc<Mutex<HQMMockState>>, config: MockServerConfig, mut stop: watch::Receiver<bool>) {
+    let mut interval = tokio::time::interval(config.step_interval);
+    let mut shaper = HQMLinkShaper {
+        rng: config.seed.max(1),
+        loss: config.loss,
+        reorder: config.reorder,
+        held: None
+    };
+    let mut buf = [0u8; 1024];
+    loop {
+        tokio::select! {
+            _ = stop.changed() => break,
+            received = socket.recv_from(&mut buf) => {
+                if let Ok((size, addr)) = received {
+                    handle_client_packet(&mut state.lock().unwrap(), addr, &buf[0..size]);
+                }
+            }
+            _ = interval.tick() => {
+                let packets = step(&mut state.lock().unwrap());
+                for (addr, data) in packets {
+                    let mut stats = HQMMockStats::default();
+                    shaper.send(&socket, addr, data, &mut stats).await;
+                    let mut state = state.lock().unwrap();
+                    state.stats.packets_sent += stats.packets_sent;
+                    state.stats.packets_dropped += stats.packets_dropped;
+                    state.stats.packets_reordered += stats.packets_reordered;
+                }
+            }
+        }
+    }
+}
+
+fn handle_client_packet(state: &mut HQMMockState, addr: SocketAddr, data: &[u8]) {
+    let packet = match hqm_parse::decode_client_packet(data) {
+        Ok(packet) => packet,
+        Err(_) => return
+    };
+    match packet {
+        ClientPacket::Join { version, name } => {
+            if version != hqm_parse::CLIENT_VERSION || state.clients.contains_key(&addr) {
+                return;
+            }
+            if let Some(player_index) = state.sim.add_player(&name) {
+                state.clients.insert(addr, HQMMockClient {
+                    player_index,
+                    game_id: u32::MAX,
+                    next_packet: 0,
+                    known_packet: u32::MAX,
+                    known_msgpos: 0,
+                    chat_rep: None,
+                    baselines: DeltaBaselineCache::new()
+                });
+            }
+        }
+        ClientPacket::Update(update) => {
+            let step = state.sim.step_number();
+            let current_game = state.sim.game_id();
+            let client = match state.clients.get_mut(&addr) {
+                Some(client) => client,
+                None => return
+            };
+            if update.game_id != current_game {
+                // Still catching up with a new game
+                client.game_id = update.game_id;
+                return;
+            }
+            let player_index = client.player_index;
+            if client.game_id != update.game_id {
+                client.game_id = update.game_id;
+                client.known_packet = u32::MAX;
+                client.chat_rep = None;
+            }
+            if update.known_packet != u32::MAX && (client.known_packet == u32::MAX || update.known_packet > client.known_packet) {
+                client.known_packet = update.known_packet;
+            }
+            client.known_msgpos = client.known_msgpos.max(update.known_msgpos);
+            let chat = match update.chat {
+                Some((rep, message)) if client.chat_rep != Some(rep) => {
+                    client.chat_rep = Some(rep);
+                    Some(message)
+                }
+                _ => None
+            };
+            state.inputs.push(HQMMockInput {
+                player_index,
+                step,
+                game_id: update.game_id,
+                input: update.input.clone(),
+                known_packet: update.known_packet,
+                known_msgpos: update.known_msgpos
+            });
+            state.sim.set_input(player_index, update.input);
+            if let Some(message) = chat {
+                state.sim.chat(Some(player_index), &message);
+                state.chats.push((player_index, message));
+            }
+        }
+        ClientPacket::Exit => {
+            if let Some(client) = state.clients.remove(&addr) {
+                state.sim.remove_player(client.player_index);
+            }
+        }
+    }
+}
+
+/// Steps the game and returns the packet for every client
+fn step(state: &mut HQMMockState) -> Vec<(SocketAddr, Vec<u8>)> {
+    state.sim.step();
+    let sim = &state.sim;
+    state.clients.iter_mut().map(|(addr, client)| {
+        let data = if client.game_id != sim.game_id() {
+            client.baselines.clear();
+            client.known_packet = u32::MAX;
+            client.known_msgpos = 0;
+            hqm_parse::encode_server_packet(&ServerPacket::NewGame { game_id: sim.game_id() }, &client.baselines)
+        } else {
+            let mut update = sim.update_packet(client.player_index, client.known_msgpos as u32);
+            update.packet = client.next_packet;
+            update.known_packet = if client.known_packet != u32::MAX
+                // Clients can claim any packet, one from the future wraps around to far in the past
+                && client.next_packet.wrapping_sub(client.known_packet) < BASELINE_WINDOW {
+                client.known_packet
+            } else {
+                u32::MAX
+            };
+            client.next_packet += 1;
+            let data = hqm_parse::encode_server_packet(&ServerPacket::GameUpdate(update.clone()), &client.baselines);
+            client.baselines.insert(update.packet, update.objects);
+            data
+        };
+        (*addr, data)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_bot::testing::wait_until;
+    use crate::hqm_bot::{HQMBotLogic, HQMBotSession, HQMExitReason};
+    use crate::hqm_game::{HQMGameState, HQMGameStateObject, HQMMessage};
+    use nalgebra::{Point3, Vector3};
+
+    #[derive(Default)]
+    struct Seen {
+        ticks: u32,
+        on_ice: u32,
+        positions: Vec<(u32, Point3<f32>)>,
+    }
+
+    /// Joins red, skates forward and says hello once it is on the ice
+    struct Skater {
+        seen: Arc<Mutex<Seen>>,
+        greeted: bool,
+    }
+
+    impl HQMBotLogic for Skater {
+        fn new_game(&mut self) {}
+
+        fn tick(&mut self, state: &HQMGameState, _messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+            let mut seen = self.seen.lock().unwrap();
+            seen.ticks += 1;
+            match state.me() {
+                Some(me) => {
+                    seen.on_ice += 1;
+                    seen.positions.push((state.step, me.skater.pos));
+                    let chat = if self.greeted { None } else { Some("hello".to_owned()) };
+                    self.greeted = true;
+                    (HQMPlayerInput { fwbw: 1.0, ..Default::default() }, chat)
+                }
+                None => (HQMPlayerInput { join_red: true, ..Default::default() }, None)
+            }
+        }
+    }
+
+    /// Plays until the bot has been on the ice for `on_ice` ticks and its greeting has arrived
+    async fn play(server: &MockServer, on_ice: u32) -> (Arc<Mutex<Seen>>, HQMExitReason) {
+        let seen = Arc::new(Mutex::new(Seen::default()));
+        let mut session = HQMBotSession::new("Mock".to_owned(), Skater { seen: seen.clone(), greeted: false });
+        let shutdown = session.shutdown_handle();
+        let addr = server.addr();
+        let task = tokio::spawn(async move { session.start(addr).await.unwrap() });
+        wait_until(Duration::from_secs(5), "ticks on the ice and a greeting", || {
+            seen.lock().unwrap().on_ice >= on_ice && !server.chats().is_empty()
+        }).await;
+        shutdown.stop();
+        let summary = task.await.unwrap();
+        assert_eq!(summary.games_played, 1);
+        (seen, summary.exit_reason)
+    }
+
+    #[tokio::test]
+    async fn session_joins_and_plays() {
+        let server = MockServer::start(MockServerConfig::default()).await.unwrap();
+        let (seen, exit_reason) = play(&server, 30).await;
+        assert_eq!(exit_reason, HQMExitReason::Stopped);
+
+        let seen = std::mem::take(&mut *seen.lock().unwrap());
+        let inputs = server.inputs();
+        assert!(inputs.iter().any(|x| x.input.join_red));
+        assert!(inputs.iter().any(|x| x.input.fwbw == 1.0));
+        assert!(inputs.iter().any(|x| x.known_packet != u32::MAX));
+        assert!(inputs.windows(2).all(|x| x[0].step <= x[1].step));
+        assert!(inputs.last().unwrap().known_msgpos > 0);
+        // The chat message is repeated until echoed, but only arrives once
+        assert_eq!(server.chats(), vec![(0, "hello".to_owned())]);
+
+        // What the bot saw matches the game, through the delta compression
+        let (first_step, first) = seen.positions[0];
+        let (last_step, last) = *seen.positions.last().unwrap();
+        assert!(last_step > first_step);
+        assert!(last.z < first.z);
+        assert!((last.x - first.x).abs() < 0.01);
+
+        // The exit message takes the player off the server
+        wait_until(Duration::from_secs(5), "the player to leave", || server.client_count() == 0).await;
+        assert!(server.with_sim(|sim| sim.objects().iter().skip(1).all(|x| matches!(x, HQMGameStateObject::None))));
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn session_survives_loss_and_reordering() {
+        let config = MockServerConfig { loss: 0.2, reorder: 0.2, seed: 7, ..Default::default() };
+        let server = MockServer::start(config).await.unwrap();
+        server.with_sim(|sim| sim.place_puck(Point3::new(15.0, 0.5, 20.0), Vector3::zeros()));
+        let (seen, _) = play(&server, 30).await;
+
+        let stats = server.stats();
+        assert!(stats.packets_dropped > 0);
+        assert!(stats.packets_reordered > 0);
+        let seen = std::mem::take(&mut *seen.lock().unwrap());
+        // Every decoded position is where a red skater skating towards -z could be
+        let center = server.with_sim(|sim| sim.config().rink.center());
+        for (_, pos) in seen.positions.iter() {
+            assert!((pos.x - center.x).abs() < 0.01, "{:?}", pos);
+            assert!(pos.z <= center.z + 2.01 && pos.z > 0.0, "{:?}", pos);
+        }
+        assert_eq!(server.chats().len(), 1);
+        assert!(server.inputs().iter().all(|x| x.player_index == 0 && x.game_id == server.with_sim(|sim| sim.game_id())));
+        server.stop().await;
+    }
+}
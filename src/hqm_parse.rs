@@ -1,7 +1,10 @@
 use std::cmp::min;
-use nalgebra::{Vector3, Matrix3};
+use std::collections::HashMap;
+use nalgebra::{Vector2, Vector3, Matrix3, Point3};
+use crate::hqm_game::{HQMGameStateObject, HQMGameStatePuck, HQMGameStateSkater, HQMMessage, HQMPlayerInput, HQMTeam};
+
+pub const GAME_HEADER: &[u8] = b"Hock";
 
-#[allow(dead_code)]
 pub fn convert_matrix_from_network(b: u8, v1: u32, v2: u32) -> Matrix3<f32>{
     let r1 = convert_rot_column_from_network(b, v1);
     let r2 = convert_rot_column_from_network(b, v2);
@@ -9,8 +12,15 @@ pub fn convert_matrix_from_network(b: u8, v1: u32, v2: u32) -> Matrix3<f32>{
     Matrix3::from_columns(&[r0, r1, r2])
 }
 
-#[allow(dead_code)]
-fn convert_rot_column_from_network(b: u8, v: u32) -> Vector3<f32> {
+/// Inverse of `convert_matrix_from_network`. Only the second and third columns are sent,
+/// the first one is reconstructed by the receiver as their cross product.
+pub fn convert_matrix_to_network(b: u8, v: &Matrix3<f32>) -> (u32, u32) {
+    let r1 = convert_rot_column_to_network(b, &v.column(1).into_owned());
+    let r2 = convert_rot_column_to_network(b, &v.column(2).into_owned());
+    (r1, r2)
+}
+
+fn octant_triangle(start: u32) -> [Vector3<f32>; 3] {
     let uxp = Vector3::x();
     let uxn = -uxp;
     let uyp = Vector3::y();
@@ -19,62 +29,95 @@ fn convert_rot_column_from_network(b: u8, v: u32) -> Vector3<f32> {
     let uzn = -uzp;
 
     let a = [
-        [&uyp, &uxp, &uzp],
-        [&uyp, &uzp, &uxn],
-        [&uyp, &uzn, &uxp],
-        [&uyp, &uxn, &uzn],
-        [&uzp, &uxp, &uyn],
-        [&uxn, &uzp, &uyn],
-        [&uxp, &uzn, &uyn],
-        [&uzn, &uxn, &uyn]
+        [uyp, uxp, uzp],
+        [uyp, uzp, uxn],
+        [uyp, uzn, uxp],
+        [uyp, uxn, uzn],
+        [uzp, uxp, uyn],
+        [uxn, uzp, uyn],
+        [uxp, uzn, uyn],
+        [uzn, uxn, uyn]
     ];
+    a[start as usize]
+}
 
+fn subdivide_triangle(step: u32, t: [Vector3<f32>; 3]) -> [Vector3<f32>; 3] {
+    let [temp1, temp2, temp3] = t;
+    let c1 = (temp1 + temp2).normalize();
+    let c2 = (temp2 + temp3).normalize();
+    let c3 = (temp3 + temp1).normalize();
+    match step {
+        0 => [temp1, c1, c3],
+        1 => [c1, temp2, c2],
+        2 => [c3, c2, temp3],
+        _ => [c1, c2, c3]
+    }
+}
+
+fn convert_rot_column_from_network(b: u8, v: u32) -> Vector3<f32> {
     let start = v & 7;
 
-    let mut temp1 = a[start as usize][0].clone();
-    let mut temp2 = a[start as usize][1].clone();
-    let mut temp3 = a[start as usize][2].clone();
+    let mut t = octant_triangle(start);
     let mut pos = 3;
     while pos < b {
         let step = (v >> pos) & 3;
-        let c1 = (temp1 + temp2).normalize();
-        let c2 = (temp2 + temp3).normalize();
-        let c3 = (temp3 + temp1).normalize();
-        match step {
-            0 => {
-                temp2 = c1;
-                temp3 = c3;
-            }
-            1 => {
-                temp1 = c1;
-                temp3 = c2;
-            }
-            2 => {
-                temp1 = c3;
-                temp2 = c2;
-            }
-            3 => {
-                temp1 = c1;
-                temp2 = c2;
-                temp3 = c3;
-            }
-            _ => panic!()
-        }
-
+        t = subdivide_triangle(step, t);
         pos += 2;
     }
-    (temp1 + temp2 + temp3).normalize()
+    (t[0] + t[1] + t[2]).normalize()
 
 }
 
-#[derive(Debug)]
+fn convert_rot_column_to_network(b: u8, v: &Vector3<f32>) -> u32 {
+    // Whether v is on the same side as `corner` of the plane through the origin, a and b.
+    // The triangles get tiny after a few steps, so everything is computed relative to a
+    // to keep f32 cancellation from moving the plane.
+    let same_side = |a: &Vector3<f32>, b: &Vector3<f32>, corner: &Vector3<f32>| {
+        let normal = a.cross(&(b - a));
+        normal.dot(&(v - a)) * normal.dot(&(corner - a)) >= 0.0
+    };
+
+    let mut res = 0;
+    if v.x < 0.0 {
+        res |= 1;
+    }
+    if v.z < 0.0 {
+        res |= 2;
+    }
+    if v.y < 0.0 {
+        res |= 4;
+    }
+
+    let mut t = octant_triangle(res);
+    let mut pos = 3;
+    while pos < b {
+        let c1 = (t[0] + t[1]).normalize();
+        let c2 = (t[1] + t[2]).normalize();
+        let c3 = (t[2] + t[0]).normalize();
+        let step = if same_side(&c1, &c3, &t[0]) {
+            0
+        } else if same_side(&c1, &c2, &t[1]) {
+            1
+        } else if same_side(&c2, &c3, &t[2]) {
+            2
+        } else {
+            3
+        };
+        res |= step << pos;
+        t = subdivide_triangle(step, t);
+        pos += 2;
+    }
+    res
+}
+
+#[derive(Debug, Clone)]
 pub enum HQMObjectPacket {
     None,
     Puck(HQMPuckPacket),
     Skater(HQMSkaterPacket)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HQMSkaterPacket {
     pub pos: (u32, u32, u32),
     pub rot: (u32, u32),
@@ -84,12 +127,648 @@ pub struct HQMSkaterPacket {
     pub body_rot: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HQMPuckPacket {
     pub pos: (u32, u32, u32),
     pub rot: (u32, u32),
 }
 
+pub fn convert_object_from_network(packet: &HQMObjectPacket) -> HQMGameStateObject {
+    match packet {
+        HQMObjectPacket::None => HQMGameStateObject::None,
+        HQMObjectPacket::Puck(packet) => {
+            let pos = Point3::new(packet.pos.0 as f32 / 1024.0,
+                                  packet.pos.1 as f32 / 1024.0,
+                                  packet.pos.2 as f32 / 1024.0);
+            let rot = convert_matrix_from_network(31, packet.rot.0, packet.rot.1);
+            HQMGameStateObject::Puck(HQMGameStatePuck {
+                pos,
+                rot,
+                vel: Vector3::zeros(),
+                angular_vel: Vector3::zeros(),
+                acc: Vector3::zeros()
+            })
+        }
+        HQMObjectPacket::Skater(packet) => {
+            let pos = Point3::new(packet.pos.0 as f32 / 1024.0,
+                                  packet.pos.1 as f32 / 1024.0,
+                                  packet.pos.2 as f32 / 1024.0);
+            let rot = convert_matrix_from_network(31, packet.rot.0, packet.rot.1);
+            let stick_pos = Point3::new(
+                (packet.stick_pos.0 as f32 / 1024.0) + pos.x - 4.0,
+                (packet.stick_pos.1 as f32 / 1024.0) + pos.y - 4.0,
+                (packet.stick_pos.2 as f32 / 1024.0) + pos.z - 4.0
+            );
+            let stick_rot = convert_matrix_from_network(25, packet.stick_rot.0, packet.stick_rot.1);
+            HQMGameStateObject::Skater(HQMGameStateSkater {
+                pos,
+                rot,
+                stick_pos,
+                stick_rot,
+                head_rot: (packet.head_rot as f32 - 16384.0) / 8192.0,
+                body_rot: (packet.body_rot as f32 - 16384.0) / 8192.0,
+                vel: Vector3::zeros(),
+                angular_vel: Vector3::zeros(),
+                acc: Vector3::zeros(),
+                stick_vel: Vector3::zeros()
+            })
+        }
+    }
+}
+
+/// The inverse of `convert_object_from_network`, rounding to what the wire format can hold.
+/// Velocities are not sent.
+pub fn convert_object_to_network(object: &HQMGameStateObject) -> HQMObjectPacket {
+    let pos = |p: &Point3<f32>| (to_fixed(p.x, 17), to_fixed(p.y, 17), to_fixed(p.z, 17));
+    match object {
+        HQMGameStateObject::None => HQMObjectPacket::None,
+        HQMGameStateObject::Puck(puck) => HQMObjectPacket::Puck(HQMPuckPacket {
+            pos: pos(&puck.pos),
+            rot: convert_matrix_to_network(31, &puck.rot)
+        }),
+        HQMGameStateObject::Skater(skater) => {
+            let stick = skater.stick_pos - skater.pos;
+            let angle = |v: f32| ((v * 8192.0 + 16384.0).round().max(0.0) as u32).min(0xffff);
+            HQMObjectPacket::Skater(HQMSkaterPacket {
+                pos: pos(&skater.pos),
+                rot: convert_matrix_to_network(31, &skater.rot),
+                stick_pos: (to_fixed(stick.x + 4.0, 13), to_fixed(stick.y + 4.0, 13), to_fixed(stick.z + 4.0, 13)),
+                stick_rot: convert_matrix_to_network(25, &skater.stick_rot),
+                head_rot: angle(skater.head_rot),
+                body_rot: angle(skater.body_rot)
+            })
+        }
+    }
+}
+
+/// Metres to 1/1024 metre units, clamped to `bits` bits
+fn to_fixed(v: f32, bits: u8) -> u32 {
+    ((v * 1024.0).round().max(0.0) as u32).min((1 << bits) - 1)
+}
+
+/// Object packets the client has received, keyed on the low 8 bits of the packet number
+/// the same way the server refers to them when it delta-compresses against `known_packet`.
+#[derive(Debug, Default)]
+pub struct DeltaBaselineCache {
+    packets: HashMap<u32, Vec<HQMObjectPacket>>,
+}
+
+impl DeltaBaselineCache {
+    pub fn new() -> Self {
+        DeltaBaselineCache { packets: HashMap::new() }
+    }
+
+    pub fn get(&self, packet: u32) -> Option<&[HQMObjectPacket]> {
+        if packet == u32::MAX {
+            return None;
+        }
+        self.packets.get(&(packet & 0xff)).map(|x| x.as_slice())
+    }
+
+    pub fn insert(&mut self, packet: u32, objects: Vec<HQMObjectPacket>) {
+        self.packets.insert(packet & 0xff, objects);
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HQMGameUpdatePacket {
+    pub game_id: u32,
+    pub step: u32,
+    pub game_over: bool,
+    pub red_score: u32,
+    pub blue_score: u32,
+    pub time: u32,
+    pub goal_time: u32,
+    pub period: u32,
+    pub own_player_index: usize,
+    pub packet: u32,
+    pub known_packet: u32,
+    pub objects: Vec<HQMObjectPacket>,
+    /// Message index of the first entry in `messages`
+    pub message_pos: u32,
+    pub messages: Vec<HQMMessage>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ServerPacket {
+    GameUpdate(HQMGameUpdatePacket),
+    NewGame {
+        game_id: u32
+    },
+}
+
+pub fn decode_server_packet(msg: &[u8], baselines: &DeltaBaselineCache) -> Result<ServerPacket, ProtocolError> {
+    let mut parser = HQMMessageReader::new(msg);
+
+    let header = parser.read_bytes_aligned(4)?;
+    if header != GAME_HEADER {
+        return Err(ProtocolError::BadHeader);
+    }
+
+    let command = parser.read_byte_aligned()?;
+    match command {
+        5 => decode_game_update(&mut parser, baselines).map(ServerPacket::GameUpdate),
+        6 => {
+            let game_id = parser.read_u32_aligned()?;
+            Ok(ServerPacket::NewGame { game_id })
+        }
+        _ => Err(ProtocolError::UnknownCommand(command))
+    }
+}
+
+fn decode_game_update(parser: &mut HQMMessageReader, baselines: &DeltaBaselineCache) -> Result<HQMGameUpdatePacket, ProtocolError> {
+    let game_id = parser.read_u32_aligned()?;
+    let step = parser.read_u32_aligned()?;
+    let game_over = parser.read_bits(1)? == 1;
+    let red_score = parser.read_bits(8)?;
+    let blue_score = parser.read_bits(8)?;
+
+    let time = parser.read_bits(16)?;
+    let goal_time = parser.read_bits(16)?;
+
+    let period = parser.read_bits(8)?;
+
+    let own_player_index = parser.read_bits(8)? as usize;
+
+    let packet = parser.read_u32_aligned()?;
+    let known_packet = parser.read_u32_aligned()?;
+
+    let objects = decode_objects(parser, baselines.get(known_packet))?;
+
+    let message_num = parser.read_bits(4)?;
+    let message_pos = parser.read_bits(16)?;
+    let mut messages = Vec::with_capacity(message_num as usize);
+    for _ in 0..message_num {
+        messages.push(decode_message(parser)?);
+    }
+
+    Ok(HQMGameUpdatePacket {
+        game_id,
+        step,
+        game_over,
+        red_score,
+        blue_score,
+        time,
+        goal_time,
+        period,
+        own_player_index,
+        packet,
+        known_packet,
+        objects,
+        message_pos,
+        messages
+    })
+}
+
+pub(crate) fn decode_objects(parser: &mut HQMMessageReader, old_packet: Option<&[HQMObjectPacket]>) -> Result<Vec<HQMObjectPacket>, ProtocolError> {
+    let mut objects = Vec::with_capacity(32);
+    for i in 0..32 {
+        let exists = parser.read_bits(1)? == 1;
+        let object = if exists {
+            let obj_type = parser.read_bits(2)?;
+            match obj_type {
+                0 => {
+                    let old = old_packet.and_then(|x| match x.get(i)? {
+                        HQMObjectPacket::Skater(skater) => Some(skater),
+                        _ => None
+                    });
+                    let x = parser.read_pos(17, old.map(|x| x.pos.0))?;
+                    let y = parser.read_pos(17, old.map(|x| x.pos.1))?;
+                    let z = parser.read_pos(17, old.map(|x| x.pos.2))?;
+                    let r1 = parser.read_pos(31, old.map(|x| x.rot.0))?;
+                    let r2 = parser.read_pos(31, old.map(|x| x.rot.1))?;
+
+                    let stick_x = parser.read_pos(13, old.map(|x| x.stick_pos.0))?;
+                    let stick_y = parser.read_pos(13, old.map(|x| x.stick_pos.1))?;
+                    let stick_z = parser.read_pos(13, old.map(|x| x.stick_pos.2))?;
+
+                    let stick_r1 = parser.read_pos(25, old.map(|x| x.stick_rot.0))?;
+                    let stick_r2 = parser.read_pos(25, old.map(|x| x.stick_rot.1))?;
+
+                    let head_rot = parser.read_pos(16, old.map(|x| x.head_rot))?;
+                    let body_rot = parser.read_pos(16, old.map(|x| x.body_rot))?;
+
+                    HQMObjectPacket::Skater(HQMSkaterPacket {
+                        pos: (x, y, z),
+                        rot: (r1, r2),
+                        stick_pos: (stick_x, stick_y, stick_z),
+                        stick_rot: (stick_r1, stick_r2),
+                        head_rot,
+                        body_rot,
+                    })
+                }
+                1 => {
+                    let old = old_packet.and_then(|x| match x.get(i)? {
+                        HQMObjectPacket::Puck(puck) => Some(puck),
+                        _ => None
+                    });
+                    let x = parser.read_pos(17, old.map(|x| x.pos.0))?;
+                    let y = parser.read_pos(17, old.map(|x| x.pos.1))?;
+                    let z = parser.read_pos(17, old.map(|x| x.pos.2))?;
+                    let r1 = parser.read_pos(31, old.map(|x| x.rot.0))?;
+                    let r2 = parser.read_pos(31, old.map(|x| x.rot.1))?;
+
+                    HQMObjectPacket::Puck(HQMPuckPacket {
+                        pos: (x, y, z),
+                        rot: (r1, r2),
+                    })
+                }
+                _ => return Err(ProtocolError::UnknownObjectType(obj_type))
+            }
+        } else {
+            HQMObjectPacket::None
+        };
+        objects.push(object);
+    }
+    Ok(objects)
+}
+
+fn read_player_index(parser: &mut HQMMessageReader) -> Result<Option<usize>, ProtocolError> {
+    Ok(match parser.read_bits(6)? {
+        63 => None,
+        x => Some(x as usize)
+    })
+}
+
+fn read_string(parser: &mut HQMMessageReader, len: u32) -> Result<String, ProtocolError> {
+    // Characters are sent as 7-bit values, so every byte is already valid ASCII
+    let mut s = String::with_capacity(len as usize);
+    for _ in 0..len {
+        s.push(char::from(parser.read_bits(7)? as u8));
+    }
+    Ok(s.trim_matches(char::from(0)).to_string())
+}
+
+pub(crate) fn decode_message(parser: &mut HQMMessageReader) -> Result<HQMMessage, ProtocolError> {
+    let message_type = parser.read_bits(6)?;
+    match message_type {
+        0 => {
+            // Player update
+            let player_index = parser.read_bits(6)? as usize;
+            let in_server = parser.read_bits(1)? == 1;
+            let team = match parser.read_bits(2)? {
+                0 => Some(HQMTeam::Red),
+                1 => Some(HQMTeam::Blue),
+                _ => None
+            };
+            let object = read_player_index(parser)?.zip(team);
+            let player_name = read_string(parser, 31)?;
+            Ok(HQMMessage::PlayerUpdate {
+                player_name,
+                object,
+                player_index,
+                in_server
+            })
+        }
+        1 => {
+            // Goal
+            let team = match parser.read_bits(2)? {
+                0 => HQMTeam::Red,
+                _ => HQMTeam::Blue,
+            };
+            let goal_player_index = read_player_index(parser)?;
+            let assist_player_index = read_player_index(parser)?;
+            Ok(HQMMessage::Goal {
+                team,
+                goal_player_index,
+                assist_player_index
+            })
+        }
+        2 => {
+            // Chat
+            let player_index = read_player_index(parser)?;
+            let size = parser.read_bits(6)?;
+            let message = read_string(parser, size)?;
+            Ok(HQMMessage::Chat {
+                player_index,
+                message
+            })
+        }
+        _ => Err(ProtocolError::BadMessageType(message_type))
+    }
+}
+
+/// Large enough for a game update with 32 skaters written without delta compression
+/// and a full set of 15 messages.
+const MAX_SERVER_PACKET_SIZE: usize = 4096;
+
+/// Encodes a packet the way the server would send it. Object positions are
+/// delta-compressed against the baseline for `known_packet`, if there is one.
+///
+/// The wire format has room for at most 15 messages and 63 chat characters;
+/// anything beyond that is not written. Names and chat are ASCII only, other
+/// characters are replaced with '?'.
+pub fn encode_server_packet(packet: &ServerPacket, baselines: &DeltaBaselineCache) -> Vec<u8> {
+    let mut buf = vec![0u8; MAX_SERVER_PACKET_SIZE];
+    let mut writer = HQMMessageWriter::new(&mut buf);
+    writer.write_bytes_aligned(GAME_HEADER);
+    match packet {
+        ServerPacket::GameUpdate(update) => {
+            writer.write_byte_aligned(5);
+            encode_game_update(&mut writer, update, baselines);
+        }
+        ServerPacket::NewGame { game_id } => {
+            writer.write_byte_aligned(6);
+            writer.write_u32_aligned(*game_id);
+        }
+    }
+    let bytes_written = writer.get_bytes_written();
+    buf.truncate(bytes_written);
+    buf
+}
+
+fn encode_game_update(writer: &mut HQMMessageWriter, update: &HQMGameUpdatePacket, baselines: &DeltaBaselineCache) {
+    writer.write_u32_aligned(update.game_id);
+    writer.write_u32_aligned(update.step);
+    writer.write_bits(1, if update.game_over { 1 } else { 0 });
+    writer.write_bits(8, update.red_score);
+    writer.write_bits(8, update.blue_score);
+    writer.write_bits(16, update.time);
+    writer.write_bits(16, update.goal_time);
+    writer.write_bits(8, update.period);
+    writer.write_bits(8, update.own_player_index as u32);
+
+    writer.write_u32_aligned(update.packet);
+    writer.write_u32_aligned(update.known_packet);
+
+    encode_objects(writer, &update.objects, baselines.get(update.known_packet));
+
+    let message_num = update.messages.len().min(15);
+    writer.write_bits(4, message_num as u32);
+    writer.write_bits(16, update.message_pos);
+    for message in &update.messages[0..message_num] {
+        encode_message(writer, message);
+    }
+}
+
+pub(crate) fn encode_objects(writer: &mut HQMMessageWriter, objects: &[HQMObjectPacket], old_packet: Option<&[HQMObjectPacket]>) {
+    for i in 0..32 {
+        match objects.get(i) {
+            Some(HQMObjectPacket::Skater(skater)) => {
+                let old = old_packet.and_then(|x| match x.get(i)? {
+                    HQMObjectPacket::Skater(skater) => Some(skater),
+                    _ => None
+                });
+                writer.write_bits(1, 1);
+                writer.write_bits(2, 0);
+                writer.write_pos(17, skater.pos.0, old.map(|x| x.pos.0));
+                writer.write_pos(17, skater.pos.1, old.map(|x| x.pos.1));
+                writer.write_pos(17, skater.pos.2, old.map(|x| x.pos.2));
+                writer.write_pos(31, skater.rot.0, old.map(|x| x.rot.0));
+                writer.write_pos(31, skater.rot.1, old.map(|x| x.rot.1));
+
+                writer.write_pos(13, skater.stick_pos.0, old.map(|x| x.stick_pos.0));
+                writer.write_pos(13, skater.stick_pos.1, old.map(|x| x.stick_pos.1));
+                writer.write_pos(13, skater.stick_pos.2, old.map(|x| x.stick_pos.2));
+
+                writer.write_pos(25, skater.stick_rot.0, old.map(|x| x.stick_rot.0));
+                writer.write_pos(25, skater.stick_rot.1, old.map(|x| x.stick_rot.1));
+
+                writer.write_pos(16, skater.head_rot, old.map(|x| x.head_rot));
+                writer.write_pos(16, skater.body_rot, old.map(|x| x.body_rot));
+            }
+            Some(HQMObjectPacket::Puck(puck)) => {
+                let old = old_packet.and_then(|x| match x.get(i)? {
+                    HQMObjectPacket::Puck(puck) => Some(puck),
+                    _ => None
+                });
+                writer.write_bits(1, 1);
+                writer.write_bits(2, 1);
+                writer.write_pos(17, puck.pos.0, old.map(|x| x.pos.0));
+                writer.write_pos(17, puck.pos.1, old.map(|x| x.pos.1));
+                writer.write_pos(17, puck.pos.2, old.map(|x| x.pos.2));
+                writer.write_pos(31, puck.rot.0, old.map(|x| x.rot.0));
+                writer.write_pos(31, puck.rot.1, old.map(|x| x.rot.1));
+            }
+            Some(HQMObjectPacket::None) | None => {
+                writer.write_bits(1, 0);
+            }
+        }
+    }
+}
+
+fn write_player_index(writer: &mut HQMMessageWriter, index: Option<usize>) {
+    writer.write_bits(6, index.map_or(63, |x| x as u32));
+}
+
+/// Strings are sent as 7-bit characters, so anything that isn't ASCII is sent as '?'
+pub(crate) fn to_wire_string(s: &str) -> Vec<u8> {
+    s.chars().map(|c| if c.is_ascii() { c as u8 } else { b'?' }).collect()
+}
+
+fn write_string(writer: &mut HQMMessageWriter, len: usize, bytes: &[u8]) {
+    for i in 0..len {
+        writer.write_bits(7, bytes.get(i).copied().unwrap_or(0) as u32);
+    }
+}
+
+pub(crate) fn encode_message(writer: &mut HQMMessageWriter, message: &HQMMessage) {
+    match message {
+        HQMMessage::PlayerUpdate { player_name, object, player_index, in_server } => {
+            writer.write_bits(6, 0);
+            writer.write_bits(6, *player_index as u32);
+            writer.write_bits(1, if *in_server { 1 } else { 0 });
+            let (object_index, team) = match object {
+                Some((object_index, HQMTeam::Red)) => (Some(*object_index), 0),
+                Some((object_index, HQMTeam::Blue)) => (Some(*object_index), 1),
+                None => (None, 3)
+            };
+            writer.write_bits(2, team);
+            write_player_index(writer, object_index);
+            write_string(writer, 31, &to_wire_string(player_name));
+        }
+        HQMMessage::Goal { team, goal_player_index, assist_player_index } => {
+            writer.write_bits(6, 1);
+            writer.write_bits(2, match team {
+                HQMTeam::Red => 0,
+                HQMTeam::Blue => 1
+            });
+            write_player_index(writer, *goal_player_index);
+            write_player_index(writer, *assist_player_index);
+        }
+        HQMMessage::Chat { player_index, message } => {
+            writer.write_bits(6, 2);
+            write_player_index(writer, *player_index);
+            let message = to_wire_string(message);
+            let size = message.len().min(63);
+            writer.write_bits(6, size as u32);
+            write_string(writer, size, &message);
+        }
+    }
+}
+
+/// Protocol version sent in the join message
+pub const CLIENT_VERSION: u8 = 55;
+
+#[derive(Debug, Clone)]
+pub struct HQMClientUpdatePacket {
+    pub game_id: u32,
+    pub input: HQMPlayerInput,
+    pub known_packet: u32,
+    pub known_msgpos: u16,
+    /// Chat message with the 3-bit repeat counter the server uses to skip messages it has already seen
+    pub chat: Option<(u32, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ClientPacket {
+    Join {
+        version: u8,
+        name: String
+    },
+    Update(HQMClientUpdatePacket),
+    Exit,
+}
+
+pub fn decode_client_packet(msg: &[u8]) -> Result<ClientPacket, ProtocolError> {
+    let mut parser = HQMMessageReader::new(msg);
+
+    let header = parser.read_bytes_aligned(4)?;
+    if header != GAME_HEADER {
+        return Err(ProtocolError::BadHeader);
+    }
+
+    let command = parser.read_byte_aligned()?;
+    match command {
+        2 => {
+            let version = parser.read_byte_aligned()?;
+            let name_bytes = parser.read_bytes_aligned(32)?;
+            let name = String::from_utf8_lossy(&name_bytes).trim_end_matches('\0').to_owned();
+            Ok(ClientPacket::Join { version, name })
+        }
+        4 => decode_client_update(&mut parser).map(ClientPacket::Update),
+        7 => Ok(ClientPacket::Exit),
+        _ => Err(ProtocolError::UnknownCommand(command))
+    }
+}
+
+fn decode_client_update(parser: &mut HQMMessageReader) -> Result<HQMClientUpdatePacket, ProtocolError> {
+    let game_id = parser.read_u32_aligned()?;
+    let stick_angle = parser.read_f32_aligned()?;
+    let turn = parser.read_f32_aligned()?;
+    let unknown = parser.read_f32_aligned()?;
+    let fwbw = parser.read_f32_aligned()?;
+    let stick_x = parser.read_f32_aligned()?;
+    let stick_y = parser.read_f32_aligned()?;
+    let head_rot = parser.read_f32_aligned()?;
+    let body_rot = parser.read_f32_aligned()?;
+    let keys = parser.read_u32_aligned()?;
+    let input = HQMPlayerInput {
+        stick_angle,
+        turn,
+        unknown,
+        fwbw,
+        stick: Vector2::new(stick_x, stick_y),
+        head_rot,
+        body_rot,
+        jump: keys & 0x1 != 0,
+        crouch: keys & 0x2 != 0,
+        join_red: keys & 0x4 != 0,
+        join_blue: keys & 0x8 != 0,
+        shift_rotate: keys & 0x10 != 0,
+        spectate: keys & 0x20 != 0
+    };
+    let known_packet = parser.read_u32_aligned()?;
+    let known_msgpos = parser.read_u16_aligned()?;
+    let chat = if parser.read_bits(1)? == 1 {
+        let rep = parser.read_bits(3)?;
+        let size = parser.read_bits(8)?;
+        let bytes = parser.read_bytes_aligned(size as usize)?;
+        Some((rep, String::from_utf8_lossy(&bytes).into_owned()))
+    } else {
+        None
+    };
+    Ok(HQMClientUpdatePacket {
+        game_id,
+        input,
+        known_packet,
+        known_msgpos,
+        chat
+    })
+}
+
+/// Join name and chat message bytes past the limit of the wire format are not written.
+pub fn encode_client_packet(packet: &ClientPacket) -> Vec<u8> {
+    let mut buf = vec![0u8; 512];
+    let mut writer = HQMMessageWriter::new(&mut buf);
+    writer.write_bytes_aligned(GAME_HEADER);
+    match packet {
+        ClientPacket::Join { version, name } => {
+            writer.write_byte_aligned(2);
+            writer.write_byte_aligned(*version);
+            let mut name_bytes = Vec::from(name.as_bytes());
+            name_bytes.truncate(32);
+            while name_bytes.len() < 32 {
+                name_bytes.push(0);
+            }
+            writer.write_bytes_aligned(&name_bytes);
+        }
+        ClientPacket::Update(update) => {
+            writer.write_byte_aligned(4);
+            encode_client_update(&mut writer, update);
+        }
+        ClientPacket::Exit => {
+            writer.write_byte_aligned(7);
+        }
+    }
+    let bytes_written = writer.get_bytes_written();
+    buf.truncate(bytes_written);
+    buf
+}
+
+fn encode_client_update(writer: &mut HQMMessageWriter, update: &HQMClientUpdatePacket) {
+    let input = &update.input;
+    writer.write_u32_aligned(update.game_id);
+    writer.write_f32_aligned(input.stick_angle);
+    writer.write_f32_aligned(input.turn);
+    writer.write_f32_aligned(input.unknown);
+    writer.write_f32_aligned(input.fwbw);
+    writer.write_f32_aligned(input.stick[0]);
+    writer.write_f32_aligned(input.stick[1]);
+    writer.write_f32_aligned(input.head_rot);
+    writer.write_f32_aligned(input.body_rot);
+
+    writer.write_u32_aligned({
+        let mut x = 0;
+        if input.jump {
+            x |= 1;
+        }
+        if input.crouch {
+            x |= 2;
+        }
+        if input.join_red {
+            x |= 4;
+        }
+        if input.join_blue {
+            x |= 8;
+        }
+        if input.shift_rotate {
+            x |= 0x10;
+        }
+        if input.spectate {
+            x |= 0x20;
+        }
+        x
+    });
+
+    writer.write_u32_aligned(update.known_packet);
+    writer.write_u16_aligned(update.known_msgpos);
+    if let Some((rep, message)) = &update.chat {
+        writer.write_bits(1, 1);
+        writer.write_bits(3, *rep);
+        let bytes = message.as_bytes();
+        let size = bytes.len().min(255);
+        writer.write_bits(8, size as u32);
+        writer.write_bytes_aligned(&bytes[0..size]);
+    } else {
+        writer.write_bits(1, 0);
+    }
+}
+
 pub struct HQMMessageWriter<'a> {
     buf: &'a mut [u8],
     pos: usize,
@@ -99,7 +778,7 @@ pub struct HQMMessageWriter<'a> {
 impl<'a> HQMMessageWriter<'a> {
 
     pub fn get_bytes_written(&self) -> usize {
-        return if self.bit_pos > 0 { self.pos + 1 } else { self.pos };
+        if self.bit_pos > 0 { self.pos + 1 } else { self.pos }
     }
 
     pub fn get_pos(&self) -> usize {
@@ -153,25 +832,28 @@ impl<'a> HQMMessageWriter<'a> {
         self.write_u32_aligned(f32::to_bits(v));
     }
 
+    /// Writes `v` as a 3, 6 or 12-bit signed delta against `old_v` when it fits,
+    /// or as an absolute `n`-bit value otherwise. Mirrors `HQMMessageReader::read_pos`.
     pub fn write_pos(&mut self, n: u8, v: u32, old_v: Option<u32>) {
-        let diff = match old_v {
-            Some(old_v) => (v as i32) - (old_v as i32),
-            None => i32::MAX
-        };
-        if diff >= -(2^2) && diff <= 2^2 - 1 {
-            self.write_bits(2, 0);
-            self.write_bits(3, diff as u32);
-        } else if diff >= -(2^5) && diff <= 2^5 - 1 {
-            self.write_bits(2, 1);
-            self.write_bits(6, diff as u32);
-        } else if diff >= -(2^11) && diff <= 2^11 - 1 {
-            self.write_bits(2, 2);
-            self.write_bits(12, diff as u32);
-        } else {
-            self.write_bits(2, 3);
-            self.write_bits(n, v);
+        let diff = old_v.map(|old_v| v as i64 - old_v as i64);
+        match diff {
+            Some(diff) if (-(1 << 2)..(1 << 2)).contains(&diff) => {
+                self.write_bits(2, 0);
+                self.write_bits(3, diff as u32);
+            }
+            Some(diff) if (-(1 << 5)..(1 << 5)).contains(&diff) => {
+                self.write_bits(2, 1);
+                self.write_bits(6, diff as u32);
+            }
+            Some(diff) if (-(1 << 11)..(1 << 11)).contains(&diff) => {
+                self.write_bits(2, 2);
+                self.write_bits(12, diff as u32);
+            }
+            _ => {
+                self.write_bits(2, 3);
+                self.write_bits(n, v);
+            }
         }
-
     }
 
     pub fn write_bits(&mut self, n: u8, v: u32) {
@@ -214,6 +896,33 @@ impl<'a> HQMMessageWriter<'a> {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProtocolError {
+    BadHeader,
+    UnknownCommand(u8),
+    TruncatedPacket,
+    UnknownObjectType(u32),
+    MissingDeltaBaseline,
+    BadMessageType(u32),
+    UnsupportedVersion(u32),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::BadHeader => write!(f, "bad packet header"),
+            ProtocolError::UnknownCommand(c) => write!(f, "unknown command {}", c),
+            ProtocolError::TruncatedPacket => write!(f, "truncated packet"),
+            ProtocolError::UnknownObjectType(t) => write!(f, "unknown object type {}", t),
+            ProtocolError::MissingDeltaBaseline => write!(f, "delta-encoded value without a known baseline"),
+            ProtocolError::BadMessageType(t) => write!(f, "bad message type {}", t),
+            ProtocolError::UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
 pub struct HQMMessageReader<'a> {
     buf: &'a [u8],
     pub(crate) pos: usize,
@@ -222,100 +931,81 @@ pub struct HQMMessageReader<'a> {
 
 impl<'a> HQMMessageReader<'a> {
 
-    #[allow(dead_code)]
     pub fn get_pos(&self) -> usize {
         self.pos
     }
 
-    fn safe_get_byte (&self, pos: usize) -> u8 {
-        if pos < self.buf.len () {
-            self.buf[pos]
-        } else {
-            0
-        }
+    fn safe_get_byte (&self, pos: usize) -> Result<u8, ProtocolError> {
+        self.buf.get(pos).copied().ok_or(ProtocolError::TruncatedPacket)
     }
 
-    pub fn read_byte_aligned(&mut self) -> u8 {
+    pub fn read_byte_aligned(&mut self) -> Result<u8, ProtocolError> {
         self.align();
-        let res = self.safe_get_byte(self.pos);
-        self.pos = self.pos + 1;
-        return res;
-
+        let res = self.safe_get_byte(self.pos)?;
+        self.pos += 1;
+        Ok(res)
     }
 
-    pub fn read_bytes_aligned(&mut self, n: usize) -> Vec<u8> {
+    pub fn read_bytes_aligned(&mut self, n: usize) -> Result<Vec<u8>, ProtocolError> {
         self.align();
 
         let mut res = Vec::with_capacity(n);
         for i in self.pos..(self.pos + n) {
-            res.push(self.safe_get_byte(i))
+            res.push(self.safe_get_byte(i)?)
         }
-        self.pos = self.pos + n;
-        return res;
+        self.pos += n;
+        Ok(res)
     }
 
-    pub fn read_u16_aligned(&mut self) -> u16 {
+    #[allow(dead_code)]
+    pub fn read_u16_aligned(&mut self) -> Result<u16, ProtocolError> {
         self.align();
-        let b1 = self.safe_get_byte(self.pos) as u16;
-        let b2 = self.safe_get_byte(self.pos + 1) as u16;
-        self.pos = self.pos + 2;
-        return b1 | b2 << 8;
+        let b1 = self.safe_get_byte(self.pos)? as u16;
+        let b2 = self.safe_get_byte(self.pos + 1)? as u16;
+        self.pos += 2;
+        Ok(b1 | b2 << 8)
     }
 
-    pub fn read_u32_aligned(&mut self) -> u32 {
+    pub fn read_u32_aligned(&mut self) -> Result<u32, ProtocolError> {
         self.align();
-        let b1 = self.safe_get_byte(self.pos) as u32;
-        let b2 = self.safe_get_byte(self.pos + 1) as u32;
-        let b3 = self.safe_get_byte(self.pos + 2) as u32;
-        let b4 = self.safe_get_byte(self.pos + 3) as u32;
-        self.pos = self.pos + 4;
-        return b1 | b2 << 8 | b3 << 16 | b4 << 24;
+        let b1 = self.safe_get_byte(self.pos)? as u32;
+        let b2 = self.safe_get_byte(self.pos + 1)? as u32;
+        let b3 = self.safe_get_byte(self.pos + 2)? as u32;
+        let b4 = self.safe_get_byte(self.pos + 3)? as u32;
+        self.pos += 4;
+        Ok(b1 | b2 << 8 | b3 << 16 | b4 << 24)
     }
 
-    pub fn read_f32_aligned(&mut self) -> f32 {
-        let i = self.read_u32_aligned();
-        return f32::from_bits(i);
+    #[allow(dead_code)]
+    pub fn read_f32_aligned(&mut self) -> Result<f32, ProtocolError> {
+        let i = self.read_u32_aligned()?;
+        Ok(f32::from_bits(i))
     }
 
-    #[allow(dead_code)]
-    pub fn read_pos(&mut self, b: u8, old_value: Option<u32>) -> u32 {
-        let pos_type = self.read_bits(2);
-        match pos_type {
-            0 => {
-                let diff = self.read_bits_signed(3);
-                let old_value = old_value.unwrap() as i32;
-                (old_value + diff).max(0) as u32
-            }
-            1 => {
-                let diff = self.read_bits_signed(6);
-                let old_value = old_value.unwrap() as i32;
-                (old_value + diff).max(0) as u32
-            },
-            2 => {
-                let diff = self.read_bits_signed(12);
-                let old_value = old_value.unwrap() as i32;
-                (old_value + diff).max(0) as u32
-            },
-            3 => {
-                self.read_bits(b)
-            },
-            _ => panic!(),
-        }
+    pub fn read_pos(&mut self, b: u8, old_value: Option<u32>) -> Result<u32, ProtocolError> {
+        let pos_type = self.read_bits(2)?;
+        let diff = match pos_type {
+            0 => self.read_bits_signed(3)?,
+            1 => self.read_bits_signed(6)?,
+            2 => self.read_bits_signed(12)?,
+            _ => return self.read_bits(b),
+        };
+        let old_value = old_value.ok_or(ProtocolError::MissingDeltaBaseline)? as i32;
+        Ok((old_value + diff).max(0) as u32)
     }
 
-    #[allow(dead_code)]
-    pub fn read_bits_signed(&mut self, b: u8) -> i32 {
-        let a = self.read_bits(b);
+    pub fn read_bits_signed(&mut self, b: u8) -> Result<i32, ProtocolError> {
+        let a = self.read_bits(b)?;
 
         if a >= 1 << (b-1) {
-            (-1 << b) | (a as i32)
+            Ok((-1 << b) | (a as i32))
         } else {
-            a as i32
+            Ok(a as i32)
         }
 
     }
 
-    pub fn read_bits(&mut self, b: u8) -> u32 {
+    pub fn read_bits(&mut self, b: u8) -> Result<u32, ProtocolError> {
         let mut bits_remaining = b;
         let mut res = 0u32;
         let mut p = 0;
@@ -323,9 +1013,9 @@ impl<'a> HQMMessageReader<'a> {
             let bits_possible_to_write = 8 - self.bit_pos;
             let bits = min(bits_remaining, bits_possible_to_write);
             let mask = !(!0u32 << bits);
-            let a = (self.safe_get_byte(self.pos) as u32 >> self.bit_pos) & mask;
+            let a = (self.safe_get_byte(self.pos)? as u32 >> self.bit_pos) & mask;
 
-            res = res | (a << p);
+            res |= a << p;
 
             if bits_remaining >= bits_possible_to_write {
                 bits_remaining -= bits_possible_to_write;
@@ -337,7 +1027,7 @@ impl<'a> HQMMessageReader<'a> {
                 bits_remaining = 0;
             }
         }
-        return res;
+        Ok(res)
     }
 
     pub fn align(&mut self) {
@@ -357,3 +1047,347 @@ impl<'a> HQMMessageReader<'a> {
         HQMMessageReader { buf, pos: 0, bit_pos: 0 }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small deterministic xorshift generator, so failures are reproducible
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    const POS_WIDTHS: [u8; 5] = [17, 31, 13, 25, 16];
+
+    fn expected_bits(n: u8, v: u32, old_v: Option<u32>) -> usize {
+        match old_v.map(|old_v| v as i64 - old_v as i64) {
+            Some(diff) if (-4..4).contains(&diff) => 2 + 3,
+            Some(diff) if (-32..32).contains(&diff) => 2 + 6,
+            Some(diff) if (-2048..2048).contains(&diff) => 2 + 12,
+            _ => 2 + n as usize
+        }
+    }
+
+    fn round_trip_pos(n: u8, v: u32, old_v: Option<u32>) {
+        let mut buf = [0u8; 16];
+        let mut writer = HQMMessageWriter::new(&mut buf);
+        // Start unaligned so values straddle byte boundaries
+        writer.write_bits(5, 0b10101);
+        writer.write_pos(n, v, old_v);
+        writer.write_bits(7, 0x55);
+
+        let mut reader = HQMMessageReader::new(&buf);
+        assert_eq!(reader.read_bits(5), Ok(0b10101));
+        let start = reader.pos * 8 + reader.bit_pos as usize;
+        assert_eq!(reader.read_pos(n, old_v), Ok(v), "n={} v={} old={:?}", n, v, old_v);
+        let end = reader.pos * 8 + reader.bit_pos as usize;
+        assert_eq!(end - start, expected_bits(n, v, old_v), "n={} v={} old={:?}", n, v, old_v);
+        assert_eq!(reader.read_bits(7), Ok(0x55));
+    }
+
+    #[test]
+    fn write_pos_round_trips_boundary_deltas() {
+        let edges = [0i64, 1, -1, 3, 4, -4, -5, 31, 32, -32, -33, 2047, 2048, -2048, -2049];
+        for &n in POS_WIDTHS.iter() {
+            let max = (1u32 << n) - 1;
+            for &old in [max / 2, 5000.min(max), max - 10].iter() {
+                for &diff in edges.iter() {
+                    let v = old as i64 + diff;
+                    if v >= 0 && v <= max as i64 {
+                        round_trip_pos(n, v as u32, Some(old));
+                    }
+                }
+            }
+            round_trip_pos(n, 0, Some(max));
+            round_trip_pos(n, max, Some(0));
+            round_trip_pos(n, 0, None);
+            round_trip_pos(n, max, None);
+        }
+    }
+
+    #[test]
+    fn write_pos_round_trips_random_values() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for &n in POS_WIDTHS.iter() {
+            let max = (1u64 << n) - 1;
+            for _ in 0..20000 {
+                let old = rng.below(max + 1) as u32;
+                let v = match rng.below(4) {
+                    // Bias towards the small delta ranges
+                    0 => (old as i64 + rng.below(8) as i64 - 4).max(0).min(max as i64) as u32,
+                    1 => (old as i64 + rng.below(64) as i64 - 32).max(0).min(max as i64) as u32,
+                    2 => (old as i64 + rng.below(4096) as i64 - 2048).max(0).min(max as i64) as u32,
+                    _ => rng.below(max + 1) as u32
+                };
+                let old_v = if rng.below(8) == 0 { None } else { Some(old) };
+                round_trip_pos(n, v, old_v);
+            }
+        }
+    }
+
+    #[test]
+    fn decoder_rejects_bad_packets() {
+        let mut buf = [0u8; 16];
+        let mut writer = HQMMessageWriter::new(&mut buf);
+        writer.write_bytes_aligned(GAME_HEADER);
+        writer.write_byte_aligned(6);
+        writer.write_u32_aligned(42);
+        let len = writer.get_bytes_written();
+        let baselines = DeltaBaselineCache::new();
+        match decode_server_packet(&buf[0..len], &baselines) {
+            Ok(ServerPacket::NewGame { game_id }) => assert_eq!(game_id, 42),
+            x => panic!("unexpected {:?}", x)
+        }
+
+        assert_eq!(decode_server_packet(b"Hack\x06\0\0\0\0", &baselines).err(), Some(ProtocolError::BadHeader));
+        assert_eq!(decode_server_packet(b"Ho", &baselines).err(), Some(ProtocolError::TruncatedPacket));
+        assert_eq!(decode_server_packet(b"Hock\x09", &baselines).err(), Some(ProtocolError::UnknownCommand(9)));
+        assert_eq!(decode_server_packet(&buf[0..len - 1], &baselines).err(), Some(ProtocolError::TruncatedPacket));
+    }
+
+    #[test]
+    fn decoder_survives_short_baseline() {
+        let skater = HQMObjectPacket::Skater(HQMSkaterPacket {
+            pos: (1000, 1000, 1000),
+            rot: (1, 1),
+            stick_pos: (1000, 1000, 1000),
+            stick_rot: (1, 1),
+            head_rot: 1,
+            body_rot: 1,
+        });
+        let mut objects = vec![HQMObjectPacket::None; 32];
+        objects[31] = skater;
+        let update = HQMGameUpdatePacket {
+            game_id: 1,
+            step: 1,
+            game_over: false,
+            red_score: 0,
+            blue_score: 0,
+            time: 0,
+            goal_time: 0,
+            period: 0,
+            own_player_index: 0,
+            packet: 2,
+            known_packet: 1,
+            objects: objects.clone(),
+            message_pos: 0,
+            messages: vec![]
+        };
+        let mut baselines = DeltaBaselineCache::new();
+        baselines.insert(1, objects);
+        let encoded = encode_server_packet(&ServerPacket::GameUpdate(update), &baselines);
+
+        // A baseline that doesn't have every slot is missing, not a reason to panic
+        let mut short = DeltaBaselineCache::new();
+        short.insert(1, vec![HQMObjectPacket::None; 4]);
+        assert_eq!(decode_server_packet(&encoded, &short).err(), Some(ProtocolError::MissingDeltaBaseline));
+    }
+
+    fn round_trip_message(message: HQMMessage) -> HQMMessage {
+        let update = HQMGameUpdatePacket {
+            game_id: 1,
+            step: 1,
+            game_over: false,
+            red_score: 0,
+            blue_score: 0,
+            time: 0,
+            goal_time: 0,
+            period: 0,
+            own_player_index: 0,
+            packet: 0,
+            known_packet: u32::MAX,
+            objects: vec![HQMObjectPacket::None; 32],
+            message_pos: 0,
+            messages: vec![message]
+        };
+        let baselines = DeltaBaselineCache::new();
+        match decode_server_packet(&encode_server_packet(&ServerPacket::GameUpdate(update), &baselines), &baselines) {
+            Ok(ServerPacket::GameUpdate(mut update)) => update.messages.remove(0),
+            x => panic!("unexpected {:?}", x)
+        }
+    }
+
+    #[test]
+    fn encoder_writes_new_game_and_messages() {
+        let baselines = DeltaBaselineCache::new();
+        let encoded = encode_server_packet(&ServerPacket::NewGame { game_id: 9 }, &baselines);
+        assert_eq!(encoded, b"Hock\x06\x09\0\0\0");
+
+        match round_trip_message(HQMMessage::Goal { team: HQMTeam::Red, goal_player_index: Some(4), assist_player_index: None }) {
+            HQMMessage::Goal { team: HQMTeam::Red, goal_player_index: Some(4), assist_player_index: None } => {}
+            x => panic!("unexpected {:?}", x)
+        }
+
+        // Long chat is cut to what fits
+        let long = "x".repeat(100);
+        match round_trip_message(HQMMessage::Chat { player_index: None, message: long }) {
+            HQMMessage::Chat { player_index: None, message } => assert_eq!(message, "x".repeat(63)),
+            x => panic!("unexpected {:?}", x)
+        }
+    }
+
+    #[test]
+    fn encoder_replaces_non_ascii() {
+        match round_trip_message(HQMMessage::Chat { player_index: Some(1), message: "Gråt é!".to_owned() }) {
+            HQMMessage::Chat { message, .. } => assert_eq!(message, "Gr?t ?!"),
+            x => panic!("unexpected {:?}", x)
+        }
+        match round_trip_message(HQMMessage::PlayerUpdate {
+            player_name: "Björn".to_owned(),
+            object: None,
+            player_index: 2,
+            in_server: true
+        }) {
+            HQMMessage::PlayerUpdate { player_name, .. } => assert_eq!(player_name, "Bj?rn"),
+            x => panic!("unexpected {:?}", x)
+        }
+    }
+
+    #[test]
+    fn game_update_round_trips_with_delta_baseline() {
+        let skater = |x: u32| HQMObjectPacket::Skater(HQMSkaterPacket {
+            pos: (x, 1024, 30000),
+            rot: (123456, 7654321),
+            stick_pos: (4096, 4000, 4500),
+            stick_rot: (98765, 4321),
+            head_rot: 16384,
+            body_rot: 16000,
+        });
+        let puck = |z: u32| HQMObjectPacket::Puck(HQMPuckPacket {
+            pos: (15360, 200, z),
+            rot: (1, 2),
+        });
+        let mut objects = vec![HQMObjectPacket::None; 32];
+        objects[0] = puck(30000);
+        objects[3] = skater(10000);
+        let first = HQMGameUpdatePacket {
+            game_id: 7,
+            step: 100,
+            game_over: false,
+            red_score: 1,
+            blue_score: 2,
+            time: 30000,
+            goal_time: 0,
+            period: 1,
+            own_player_index: 3,
+            packet: 100,
+            known_packet: u32::MAX,
+            objects: objects.clone(),
+            message_pos: 0,
+            messages: vec![
+                HQMMessage::PlayerUpdate {
+                    player_name: "Bot".to_owned(),
+                    object: Some((3, HQMTeam::Blue)),
+                    player_index: 3,
+                    in_server: true
+                },
+                HQMMessage::Chat {
+                    player_index: Some(3),
+                    message: "Hello".to_owned()
+                }
+            ]
+        };
+
+        let mut baselines = DeltaBaselineCache::new();
+        let encoded = encode_server_packet(&ServerPacket::GameUpdate(first.clone()), &baselines);
+        let decoded = match decode_server_packet(&encoded, &baselines) {
+            Ok(ServerPacket::GameUpdate(update)) => update,
+            x => panic!("unexpected {:?}", x)
+        };
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", first));
+        baselines.insert(decoded.packet, decoded.objects);
+
+        objects[0] = puck(29990);
+        objects[3] = skater(10500);
+        let second = HQMGameUpdatePacket {
+            step: 101,
+            packet: 101,
+            known_packet: 100,
+            objects,
+            message_pos: 2,
+            messages: vec![],
+            ..first
+        };
+        let encoded_delta = encode_server_packet(&ServerPacket::GameUpdate(second.clone()), &baselines);
+        let decoded = match decode_server_packet(&encoded_delta, &baselines) {
+            Ok(ServerPacket::GameUpdate(update)) => update,
+            x => panic!("unexpected {:?}", x)
+        };
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", second));
+
+        assert_eq!(decode_server_packet(&encoded_delta, &DeltaBaselineCache::new()).err(),
+                   Some(ProtocolError::MissingDeltaBaseline));
+        assert_eq!(decode_server_packet(&encoded_delta[0..20], &baselines).err(),
+                   Some(ProtocolError::TruncatedPacket));
+    }
+
+    fn random_rotation(rng: &mut Rng) -> Matrix3<f32> {
+        let mut unit = || (rng.below(2_000_001) as f32 / 1_000_000.0) - 1.0;
+        let axis = Vector3::new(unit(), unit(), unit());
+        let angle = unit() * std::f32::consts::PI;
+        match nalgebra::Unit::try_new(axis, 1e-3) {
+            Some(axis) => nalgebra::Rotation3::from_axis_angle(&axis, angle).into_inner(),
+            None => Matrix3::identity()
+        }
+    }
+
+    fn column_angle(a: &Matrix3<f32>, b: &Matrix3<f32>, i: usize) -> f32 {
+        // acos loses too much precision near 1 in f32 for the 31-bit case
+        let a = a.column(i).normalize();
+        let b = b.column(i).normalize();
+        a.cross(&b).norm().atan2(a.dot(&b))
+    }
+
+    fn check_matrix_round_trip(bits: u8) {
+        // Every subdivision step halves the edges of a triangle that starts out as an octant
+        let steps = (bits as i32 - 3 + 1) / 2;
+        let bound = std::f32::consts::FRAC_PI_2 / 2f32.powi(steps) + 1e-5;
+
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut rotations = vec![Matrix3::identity()];
+        for _ in 0..5000 {
+            rotations.push(random_rotation(&mut rng));
+        }
+        for rot in rotations.iter() {
+            let (v1, v2) = convert_matrix_to_network(bits, rot);
+            assert!(v1 < 1 << bits && v2 < 1 << bits);
+            let decoded = convert_matrix_from_network(bits, v1, v2);
+            assert!(column_angle(rot, &decoded, 1) <= bound, "{} bits: {:?} decoded as {:?}", bits, rot, decoded);
+            assert!(column_angle(rot, &decoded, 2) <= bound, "{} bits: {:?} decoded as {:?}", bits, rot, decoded);
+            assert!(column_angle(rot, &decoded, 0) <= 2.0 * bound, "{} bits: {:?} decoded as {:?}", bits, rot, decoded);
+        }
+    }
+
+    #[test]
+    fn rotation_round_trips_within_bound_31_bits() {
+        check_matrix_round_trip(31);
+    }
+
+    #[test]
+    fn rotation_round_trips_within_bound_25_bits() {
+        check_matrix_round_trip(25);
+    }
+
+    #[test]
+    fn rotation_encoding_is_stable() {
+        // Re-encoding a decoded rotation must give back the same wire value
+        let mut rng = Rng(0x1234_5678_9abc_def1);
+        for _ in 0..1000 {
+            let (v1, v2) = convert_matrix_to_network(31, &random_rotation(&mut rng));
+            let decoded = convert_matrix_from_network(31, v1, v2);
+            assert_eq!(convert_matrix_to_network(31, &decoded), (v1, v2));
+        }
+    }
+}
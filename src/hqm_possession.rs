@@ -0,0 +1,167 @@
+use crate::hqm_events::GameEvent;
+use crate::hqm_game::{HQMGameState, HQMGameStateSkater, HQMTeam};
+use nalgebra::{Point3, Vector3};
+
+// Stick blade geometry, in the stick's own coordinates
+pub(crate) const BLADE_OFFSET: f32 = -0.1875;
+pub(crate) const BLADE_HALF_LENGTH: f32 = 0.25;
+/// Largest distance between the puck centre and the middle of the blade that counts as control
+const POSSESSION_REACH: f32 = 0.25;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HQMPuckTouch {
+    pub player_index: usize,
+    pub team: HQMTeam,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct HQMPossession {
+    /// Player whose stick blade is within reach of the puck, None if the puck is loose
+    pub holder: Option<HQMPuckTouch>,
+    /// Number of steps `holder` has had the puck
+    pub duration: u32,
+    /// Last player to have the puck within reach, kept after the puck leaves the stick
+    pub last_touch: Option<HQMPuckTouch>,
+    /// Number of steps since `last_touch` had the puck, None while nobody has touched it
+    pub steps_since_last_touch: Option<u32>,
+}
+
+/// Works out who controls the puck from consecutive game states
+#[derive(Debug, Default)]
+pub struct HQMPossessionTracker {
+    possession: HQMPossession,
+    holder_since: u32,
+    last_touch_step: u32,
+    last_step: Option<u32>,
+}
+
+impl HQMPossessionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn possession(&self) -> &HQMPossession {
+        &self.possession
+    }
+
+    /// Sets `state.possession`. Returns a `PossessionChanged` event if the holder changed.
+    /// States older than the latest one seen are given the current possession unchanged.
+    pub fn update(&mut self, state: &mut HQMGameState) -> Option<GameEvent> {
+        if self.last_step.is_some_and(|last| state.step <= last) {
+            state.possession = self.possession;
+            return None;
+        }
+        self.last_step = Some(state.step);
+
+        let holder = find_holder(state);
+        let previous = self.possession.holder;
+        if holder != previous {
+            self.holder_since = state.step;
+        }
+        if holder.is_some() {
+            self.last_touch_step = state.step;
+            self.possession.last_touch = holder;
+        }
+        self.possession.holder = holder;
+        self.possession.duration = if holder.is_some() { state.step - self.holder_since } else { 0 };
+        self.possession.steps_since_last_touch = self.possession.last_touch.map(|_| state.step - self.last_touch_step);
+        state.possession = self.possession;
+
+        if holder != previous {
+            Some(GameEvent::PossessionChanged { previous, current: holder })
+        } else {
+            None
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// The skater with the stick blade closest to the puck, if any is within reach
+fn find_holder(state: &HQMGameState) -> Option<HQMPuckTouch> {
+    let puck = state.puck()?;
+    state.skaters().into_iter()
+        .map(|x| (blade_distance(x.skater, &puck.pos), x))
+        .filter(|(distance, _)| *distance < POSSESSION_REACH)
+        .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap())
+        .map(|(_, x)| HQMPuckTouch {
+            player_index: x.player.index,
+            team: x.team
+        })
+}
+
+/// Distance from `pos` to the line segment along the middle of the stick blade
+fn blade_distance(skater: &HQMGameStateSkater, pos: &Point3<f32>) -> f32 {
+    let axis = skater.stick_rot * Vector3::z();
+    let center = skater.stick_pos + axis * BLADE_OFFSET;
+    let along = (pos - center).dot(&axis).clamp(-BLADE_HALF_LENGTH, BLADE_HALF_LENGTH);
+    (pos - (center + axis * along)).norm()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_game::HQMGameStateObject;
+    use crate::hqm_game::testing::{puck, HQMGameStateBuilder};
+    use nalgebra::Matrix3;
+
+    fn skater(stick_pos: Point3<f32>) -> HQMGameStateObject {
+        HQMGameStateObject::Skater(HQMGameStateSkater {
+            pos: stick_pos + Vector3::new(0.0, 1.0, 1.0),
+            rot: Matrix3::identity(),
+            stick_pos,
+            stick_rot: Matrix3::identity(),
+            head_rot: 0.0,
+            body_rot: 0.0,
+            vel: Vector3::zeros(),
+            angular_vel: Vector3::zeros(),
+            acc: Vector3::zeros(),
+            stick_vel: Vector3::zeros()
+        })
+    }
+
+    fn state(step: u32, puck_pos: Point3<f32>) -> HQMGameState {
+        HQMGameStateBuilder::new()
+            .player(0, "A", Some((0, HQMTeam::Red)))
+            .player(1, "B", Some((1, HQMTeam::Blue)))
+            .objects(vec![
+                skater(Point3::new(10.0, 0.0, 30.0)),
+                skater(Point3::new(20.0, 0.0, 30.0)),
+                puck(puck_pos, Vector3::zeros())
+            ])
+            .step(step)
+            .build()
+    }
+
+    #[test]
+    fn possession_follows_the_closest_blade() {
+        let mut tracker = HQMPossessionTracker::new();
+        let red = HQMPuckTouch { player_index: 0, team: HQMTeam::Red };
+
+        let mut s = state(10, Point3::new(15.0, 0.02, 30.0));
+        assert_eq!(tracker.update(&mut s), None);
+        assert!(s.possession.holder.is_none() && s.possession.last_touch.is_none());
+        assert_eq!(s.possession.steps_since_last_touch, None);
+
+        let mut s = state(20, Point3::new(10.1, 0.02, 29.8));
+        assert_eq!(tracker.update(&mut s), Some(GameEvent::PossessionChanged { previous: None, current: Some(red) }));
+        assert_eq!(s.possession.holder, Some(red));
+        assert_eq!(s.possession.duration, 0);
+
+        let mut s = state(50, Point3::new(10.0, 0.02, 29.9));
+        assert_eq!(tracker.update(&mut s), None);
+        assert_eq!(s.possession.duration, 30);
+
+        // A late packet doesn't change anything
+        let mut s = state(40, Point3::new(15.0, 0.02, 30.0));
+        assert_eq!(tracker.update(&mut s), None);
+        assert_eq!(s.possession.holder, Some(red));
+
+        let mut s = state(60, Point3::new(15.0, 0.02, 30.0));
+        assert_eq!(tracker.update(&mut s), Some(GameEvent::PossessionChanged { previous: Some(red), current: None }));
+        assert_eq!(s.possession.last_touch, Some(red));
+        assert_eq!(s.possession.steps_since_last_touch, Some(10));
+    }
+}
@@ -0,0 +1,236 @@
+use crate::hqm_game::{HQMGameStatePuck, HQMTeam};
+use crate::hqm_rink::Rink;
+use nalgebra::{Point3, Vector3};
+
+// Physics constants per step, approximating what the server does
+pub(crate) const GRAVITY: f32 = 0.000680;
+pub(crate) const PUCK_RADIUS: f32 = 0.125;
+const PUCK_HALF_HEIGHT: f32 = 0.0206;
+const POST_RADIUS: f32 = 0.1;
+const ICE_RESTITUTION: f32 = 0.1;
+const ICE_FRICTION: f32 = 0.05;
+const BOARD_HEIGHT: f32 = 1.0;
+const BOARD_RESTITUTION: f32 = 0.5;
+const GLASS_RESTITUTION: f32 = 0.7;
+const WALL_FRICTION: f32 = 0.1;
+const POST_RESTITUTION: f32 = 0.6;
+const NET_RESTITUTION: f32 = 0.2;
+
+#[derive(Debug, Copy, Clone)]
+pub struct HQMPredictedPuck {
+    pub pos: Point3<f32>,
+    pub vel: Vector3<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HQMPuckPrediction {
+    /// One entry per step, starting one step after the state the prediction was made from.
+    /// Stops early if the puck goes in.
+    pub steps: Vec<HQMPredictedPuck>,
+    /// Number of steps until the puck is predicted to go in, and the team that scores
+    pub goal: Option<(usize, HQMTeam)>,
+}
+
+impl HQMPuckPrediction {
+    /// Predicted puck position after `steps` steps, if the prediction goes that far
+    pub fn pos_after(&self, steps: usize) -> Option<Point3<f32>> {
+        if steps == 0 {
+            return None;
+        }
+        self.steps.get(steps - 1).map(|x| x.pos)
+    }
+}
+
+/// Predicts the puck path from two received states, `step_gap` steps apart.
+pub fn predict_puck(rink: &Rink, current: &HQMGameStatePuck, previous: &HQMGameStatePuck, step_gap: u32, steps: usize) -> HQMPuckPrediction {
+    let vel = if step_gap > 0 {
+        (current.pos - previous.pos) / step_gap as f32
+    } else {
+        current.vel
+    };
+    predict_puck_from(rink, current.pos, vel, steps)
+}
+
+pub fn predict_puck_from(rink: &Rink, pos: Point3<f32>, vel: Vector3<f32>, steps: usize) -> HQMPuckPrediction {
+    let mut puck = HQMPredictedPuck { pos, vel };
+    let mut prediction = HQMPuckPrediction {
+        steps: Vec::with_capacity(steps),
+        goal: None
+    };
+    for i in 0..steps {
+        let goal = step_puck(rink, &mut puck);
+        prediction.steps.push(puck);
+        if let Some(team) = goal {
+            prediction.goal = Some((i + 1, team));
+            break;
+        }
+    }
+    prediction
+}
+
+/// Advances the puck one step. Returns the scoring team if the puck went in.
+pub(crate) fn step_puck(rink: &Rink, puck: &mut HQMPredictedPuck) -> Option<HQMTeam> {
+    let old_pos = puck.pos;
+    puck.vel.y -= GRAVITY;
+    puck.pos += puck.vel;
+
+    collide_ice(puck);
+    collide_boards(rink, puck);
+
+    for &(defending_team, scoring_team) in [(HQMTeam::Blue, HQMTeam::Red), (HQMTeam::Red, HQMTeam::Blue)].iter() {
+        if collide_net(rink, puck, &old_pos, defending_team) {
+            return Some(scoring_team);
+        }
+    }
+    None
+}
+
+/// Reflects the velocity component going into the surface, and slows down the
+/// tangential component with the normal impulse scaled by `friction`
+pub(crate) fn bounce(vel: &mut Vector3<f32>, normal: &Vector3<f32>, restitution: f32, friction: f32) {
+    let normal_speed = vel.dot(normal);
+    if normal_speed >= 0.0 {
+        return;
+    }
+    let impulse = -normal_speed * (1.0 + restitution);
+    let mut tangent = *vel - normal * normal_speed;
+    let tangent_speed = tangent.norm();
+    let reduction = friction * impulse;
+    if tangent_speed > reduction {
+        tangent *= (tangent_speed - reduction) / tangent_speed;
+    } else {
+        tangent = Vector3::zeros();
+    }
+    *vel = tangent + normal * (-normal_speed * restitution);
+}
+
+fn collide_ice(puck: &mut HQMPredictedPuck) {
+    if puck.pos.y < PUCK_HALF_HEIGHT {
+        puck.pos.y = PUCK_HALF_HEIGHT;
+        bounce(&mut puck.vel, &Vector3::y(), ICE_RESTITUTION, ICE_FRICTION);
+    }
+}
+
+fn collide_boards(rink: &Rink, puck: &mut HQMPredictedPuck) {
+    if let Some((normal, penetration)) = rink.board_contact(&puck.pos, PUCK_RADIUS) {
+        puck.pos += normal * penetration;
+        let restitution = if puck.pos.y < BOARD_HEIGHT { BOARD_RESTITUTION } else { GLASS_RESTITUTION };
+        bounce(&mut puck.vel, &normal, restitution, WALL_FRICTION);
+    }
+}
+
+/// Handles posts, crossbar and the outside of the net defended by `team`.
+/// Returns true if the puck is completely over the goal line inside the net.
+fn collide_net(rink: &Rink, puck: &mut HQMPredictedPuck, old_pos: &Point3<f32>, team: HQMTeam) -> bool {
+    let center_x = rink.width / 2.0;
+    let goal_line = rink.goal_line_z(team);
+    let direction = rink.net_direction(team);
+    let net = &rink.net;
+    let reach = POST_RADIUS + PUCK_RADIUS;
+
+    for &post_x in [center_x - net.half_width, center_x + net.half_width].iter() {
+        let diff = Vector3::new(puck.pos.x - post_x, 0.0, puck.pos.z - goal_line);
+        let distance = diff.norm();
+        if puck.pos.y < net.height && distance < reach && distance > 0.0 {
+            let normal = diff / distance;
+            puck.pos += normal * (reach - distance);
+            bounce(&mut puck.vel, &normal, POST_RESTITUTION, WALL_FRICTION);
+        }
+    }
+    if (puck.pos.x - center_x).abs() < net.half_width {
+        let diff = Vector3::new(0.0, puck.pos.y - net.height, puck.pos.z - goal_line);
+        let distance = diff.norm();
+        if distance < reach && distance > 0.0 {
+            let normal = diff / distance;
+            puck.pos += normal * (reach - distance);
+            bounce(&mut puck.vel, &normal, POST_RESTITUTION, WALL_FRICTION);
+        }
+    }
+
+    let inside_frame = |pos: &Point3<f32>| (pos.x - center_x).abs() < net.half_width && pos.y < net.height;
+    let depth = (puck.pos.z - goal_line) * direction;
+    if !inside_frame(&puck.pos) || depth <= 0.0 || depth >= net.depth {
+        return false;
+    }
+
+    let old_depth = (old_pos.z - goal_line) * direction;
+    if inside_frame(old_pos) && old_depth < net.depth {
+        // Came in through the goal mouth, or was already inside
+        return depth > PUCK_RADIUS;
+    }
+
+    // Hit the net from the outside
+    if (old_pos.x - center_x).abs() >= net.half_width {
+        let side = (old_pos.x - center_x).signum();
+        puck.pos.x = center_x + side * net.half_width;
+        bounce(&mut puck.vel, &Vector3::new(side, 0.0, 0.0), NET_RESTITUTION, WALL_FRICTION);
+    } else if old_pos.y >= net.height {
+        puck.pos.y = net.height;
+        bounce(&mut puck.vel, &Vector3::y(), NET_RESTITUTION, WALL_FRICTION);
+    } else {
+        puck.pos.z = goal_line + direction * net.depth;
+        bounce(&mut puck.vel, &Vector3::new(0.0, 0.0, direction), NET_RESTITUTION, WALL_FRICTION);
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_puck_slows_down_in_a_straight_line() {
+        let rink = Rink::standard();
+        let prediction = predict_puck_from(&rink, Point3::new(15.0, PUCK_HALF_HEIGHT, 30.5), Vector3::new(0.05, 0.0, 0.0), 100);
+        assert_eq!(prediction.steps.len(), 100);
+        assert!(prediction.goal.is_none());
+        let mut last = prediction.steps[0];
+        for puck in prediction.steps.iter().skip(1) {
+            assert!((puck.pos.y - PUCK_HALF_HEIGHT).abs() < 1e-6);
+            assert!((puck.pos.z - 30.5).abs() < 1e-6);
+            assert!(puck.pos.x > last.pos.x);
+            assert!(puck.vel.x < last.vel.x && puck.vel.x > 0.04);
+            last = *puck;
+        }
+    }
+
+    #[test]
+    fn puck_bounces_off_boards() {
+        let rink = Rink::standard();
+        let prediction = predict_puck_from(&rink, Point3::new(28.0, PUCK_HALF_HEIGHT, 30.5), Vector3::new(0.2, 0.0, 0.0), 50);
+        let max_x = prediction.steps.iter().map(|x| x.pos.x).fold(0.0, f32::max);
+        assert!(max_x <= rink.width - PUCK_RADIUS + 1e-4);
+        let last = prediction.steps.last().unwrap();
+        assert!(last.vel.x < 0.0 && last.vel.x > -0.2);
+    }
+
+    #[test]
+    fn puck_falls_to_the_ice() {
+        let rink = Rink::standard();
+        let prediction = predict_puck_from(&rink, Point3::new(15.0, 2.0, 30.5), Vector3::zeros(), 200);
+        let last = prediction.steps.last().unwrap();
+        assert!((last.pos.y - PUCK_HALF_HEIGHT).abs() < 1e-3);
+        assert!(prediction.steps.iter().all(|x| x.pos.y >= PUCK_HALF_HEIGHT));
+    }
+
+    #[test]
+    fn shot_on_goal_is_detected() {
+        let rink = Rink::standard();
+        let goal_line = rink.goal_line_z(HQMTeam::Blue);
+        // Shooting towards the net blue defends
+        let prediction = predict_puck_from(&rink, Point3::new(15.2, 0.3, 12.0), Vector3::new(0.0, 0.0, -0.4), 100);
+        let (steps, team) = prediction.goal.unwrap();
+        assert_eq!(team, HQMTeam::Red);
+        assert_eq!(prediction.steps.len(), steps);
+        assert!(prediction.steps[steps - 1].pos.z < goal_line - PUCK_RADIUS);
+
+        // Wide of the post
+        let prediction = predict_puck_from(&rink, Point3::new(18.0, 0.3, 12.0), Vector3::new(0.0, 0.0, -0.4), 100);
+        assert!(prediction.goal.is_none());
+
+        // Into the back of the net from behind
+        let prediction = predict_puck_from(&rink, Point3::new(15.0, PUCK_HALF_HEIGHT, 1.0), Vector3::new(0.0, 0.0, 0.2), 30);
+        assert!(prediction.goal.is_none());
+        assert!(prediction.steps.iter().all(|x| x.pos.z <= goal_line - rink.net.depth + 1e-4));
+    }
+}
@@ -0,0 +1,394 @@
+//! Replay files record every datagram a session receives and sends.
+//!
+//! A replay starts with the magic bytes `HQMR` and a version byte, followed by records of
+//! a kind byte, a little endian `u32` with the number of milliseconds since the recording
+//! started, a little endian `u16` payload length and the payload itself. Records are only
+//! ever appended, so a file cut short by a crash can still be read up to the last whole record.
+//! A file can hold several sessions one after the other, each starting with a `SessionStart`
+//! record that times in the session are relative to.
+
+use crate::hqm_parse::{self, ClientPacket};
+use crate::hqm_tracker::{HQMGameTracker, HQMTick, HQMTrackerUpdate};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const REPLAY_MAGIC: &[u8] = b"HQMR";
+const REPLAY_VERSION: u8 = 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HQMReplayRecordKind {
+    /// Datagram received from the server
+    Received,
+    /// Datagram sent to the server
+    Sent,
+    /// The session forgot the current game, after losing the connection
+    Reset,
+    /// A session started recording, with nothing known about the game yet
+    SessionStart,
+}
+
+impl HQMReplayRecordKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            HQMReplayRecordKind::Received => 0,
+            HQMReplayRecordKind::Sent => 1,
+            HQMReplayRecordKind::Reset => 2,
+            HQMReplayRecordKind::SessionStart => 3,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(HQMReplayRecordKind::Received),
+            1 => Some(HQMReplayRecordKind::Sent),
+            2 => Some(HQMReplayRecordKind::Reset),
+            3 => Some(HQMReplayRecordKind::SessionStart),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HQMReplayRecord {
+    /// Time since the session started recording
+    pub time: Duration,
+    pub kind: HQMReplayRecordKind,
+    pub data: Vec<u8>,
+}
+
+pub struct HQMReplayWriter<W: Write> {
+    writer: W,
+    start: Instant,
+}
+
+impl HQMReplayWriter<BufWriter<File>> {
+    /// Creates a new replay file, replacing any existing file
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Opens a replay file to record a new session at the end of, creating it if needed.
+    /// Earlier sessions in the file are kept.
+    pub fn append<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut header = Vec::new();
+        (&mut file).take(REPLAY_MAGIC.len() as u64 + 1).read_to_end(&mut header)?;
+        let mut writer = if header.is_empty() {
+            Self::new(BufWriter::new(file))?
+        } else {
+            check_header(&header)?;
+            HQMReplayWriter {
+                writer: BufWriter::new(file),
+                start: Instant::now()
+            }
+        };
+        writer.record(HQMReplayRecordKind::SessionStart, &[])?;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> HQMReplayWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(REPLAY_MAGIC)?;
+        writer.write_all(&[REPLAY_VERSION])?;
+        Ok(HQMReplayWriter {
+            writer,
+            start: Instant::now()
+        })
+    }
+
+    pub fn record(&mut self, kind: HQMReplayRecordKind, data: &[u8]) -> io::Result<()> {
+        self.record_at(self.start.elapsed(), kind, data)
+    }
+
+    pub fn record_at(&mut self, time: Duration, kind: HQMReplayRecordKind, data: &[u8]) -> io::Result<()> {
+        if data.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "datagram too large for a replay record"));
+        }
+        let millis = time.as_millis().min(u32::MAX as u128) as u32;
+        self.writer.write_all(&[kind.to_byte()])?;
+        self.writer.write_all(&millis.to_le_bytes())?;
+        self.writer.write_all(&(data.len() as u16).to_le_bytes())?;
+        self.writer.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+pub struct HQMReplayReader<R: Read> {
+    reader: R,
+}
+
+impl HQMReplayReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> HQMReplayReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+        check_header(&header)?;
+        Ok(HQMReplayReader { reader })
+    }
+
+    /// Reads the next record. Returns None at the end of the file, including when the
+    /// last record is incomplete.
+    pub fn read_record(&mut self) -> io::Result<Option<HQMReplayRecord>> {
+        let mut header = [0u8; 7];
+        match read_exact_or_eof(&mut self.reader, &mut header)? {
+            true => {}
+            false => return Ok(None)
+        }
+        let kind = HQMReplayRecordKind::from_byte(header[0])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown replay record kind {}", header[0])))?;
+        let millis = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+        let len = u16::from_le_bytes([header[5], header[6]]) as usize;
+        let mut data = vec![0u8; len];
+        match read_exact_or_eof(&mut self.reader, &mut data)? {
+            true => Ok(Some(HQMReplayRecord {
+                time: Duration::from_millis(millis as u64),
+                kind,
+                data
+            })),
+            false => Ok(None)
+        }
+    }
+
+    /// Decodes the records the same way the live session does
+    pub fn entries(self) -> HQMReplayEntries<R> {
+        HQMReplayEntries {
+            reader: self,
+            tracker: HQMGameTracker::default()
+        }
+    }
+}
+
+impl<R: Read> Iterator for HQMReplayReader<R> {
+    type Item = io::Result<HQMReplayRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn check_header(header: &[u8]) -> io::Result<()> {
+    if header.len() < 5 || &header[0..4] != REPLAY_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a replay file"));
+    }
+    if header[4] != REPLAY_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported replay version {}", header[4])));
+    }
+    Ok(())
+}
+
+/// Fills `buf` completely and returns true, or returns false if the reader ends first
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum HQMReplayEntry {
+    /// The bot joined a new game
+    NewGame {
+        time: Duration,
+        game_id: u32
+    },
+    /// A game update, exactly as it was given to the bot logic
+    Tick {
+        time: Duration,
+        tick: Box<HQMTick>
+    },
+    /// A packet the bot sent
+    Sent {
+        time: Duration,
+        packet: ClientPacket
+    },
+}
+
+/// Replay records decoded into the states and messages the live bot saw.
+/// Packets that could not be decoded are skipped, like the live session does.
+pub struct HQMReplayEntries<R: Read> {
+    reader: HQMReplayReader<R>,
+    tracker: HQMGameTracker,
+}
+
+impl<R: Read> Iterator for HQMReplayEntries<R> {
+    type Item = io::Result<HQMReplayEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let record = match self.reader.read_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e))
+            };
+            let time = record.time;
+            match record.kind {
+                HQMReplayRecordKind::Received => match self.tracker.handle_packet(&record.data) {
+                    Ok(HQMTrackerUpdate::Tick(tick)) => return Some(Ok(HQMReplayEntry::Tick { time, tick })),
+                    Ok(HQMTrackerUpdate::NewGame { game_id, started: true }) => return Some(Ok(HQMReplayEntry::NewGame { time, game_id })),
+                    Ok(HQMTrackerUpdate::NewGame { started: false, .. }) | Err(_) => {}
+                },
+                HQMReplayRecordKind::Sent => {
+                    if let Ok(packet) = hqm_parse::decode_client_packet(&record.data) {
+                        return Some(Ok(HQMReplayEntry::Sent { time, packet }));
+                    }
+                }
+                HQMReplayRecordKind::Reset | HQMReplayRecordKind::SessionStart => self.tracker.reset(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_game::{HQMMessage, HQMPlayerInput, HQMTeam};
+    use crate::hqm_parse::{DeltaBaselineCache, HQMClientUpdatePacket, HQMGameUpdatePacket, HQMObjectPacket, HQMPuckPacket, ServerPacket};
+
+    fn update(step: u32, packet: u32, known_packet: u32, messages: Vec<HQMMessage>) -> ServerPacket {
+        ServerPacket::GameUpdate(HQMGameUpdatePacket {
+            game_id: 9,
+            step,
+            game_over: false,
+            red_score: 1,
+            blue_score: 0,
+            time: 20000,
+            goal_time: 0,
+            period: 1,
+            own_player_index: 0,
+            packet,
+            known_packet,
+            objects: vec![HQMObjectPacket::Puck(HQMPuckPacket {
+                pos: (15 * 1024, 100 + step, 30 * 1024 + step * 10),
+                rot: (0, 0)
+            }), HQMObjectPacket::None],
+            message_pos: 0,
+            messages
+        })
+    }
+
+    #[test]
+    fn replay_yields_what_the_bot_saw() {
+        let join = HQMMessage::PlayerUpdate {
+            player_name: "Bot".to_owned(),
+            object: Some((0, HQMTeam::Red)),
+            player_index: 0,
+            in_server: true
+        };
+        let mut server_baselines = DeltaBaselineCache::new();
+        let mut writer = HQMReplayWriter::new(Vec::new()).unwrap();
+        let mut record = |time: u64, kind, data: &[u8]| {
+            writer.record_at(Duration::from_millis(time), kind, data).unwrap();
+        };
+
+        record(0, HQMReplayRecordKind::Received, &hqm_parse::encode_server_packet(&ServerPacket::NewGame { game_id: 9 }, &server_baselines));
+        let first = update(100, 0, u32::MAX, vec![join.clone()]);
+        record(10, HQMReplayRecordKind::Received, &hqm_parse::encode_server_packet(&first, &server_baselines));
+        if let ServerPacket::GameUpdate(u) = first {
+            server_baselines.insert(u.packet, u.objects);
+        }
+        record(11, HQMReplayRecordKind::Sent, &hqm_parse::encode_client_packet(&ClientPacket::Update(HQMClientUpdatePacket {
+            game_id: 9,
+            input: HQMPlayerInput { fwbw: 1.0, ..Default::default() },
+            known_packet: 0,
+            known_msgpos: 1,
+            chat: Some((0, "hello".to_owned()))
+        })));
+        // Same message again, and delta-compressed against the first packet
+        let second = update(102, 1, 0, vec![join]);
+        record(20, HQMReplayRecordKind::Received, &hqm_parse::encode_server_packet(&second, &server_baselines));
+        if let ServerPacket::GameUpdate(u) = second {
+            server_baselines.insert(u.packet, u.objects);
+        }
+        record(25, HQMReplayRecordKind::Received, b"garbage");
+        record(30, HQMReplayRecordKind::Reset, &[]);
+        // The baseline is gone after the reset, so this can't be decoded
+        record(40, HQMReplayRecordKind::Received, &hqm_parse::encode_server_packet(&update(104, 2, 1, vec![]), &server_baselines));
+
+        let mut data = writer.into_inner();
+        // A record cut short at the end is ignored
+        data.extend_from_slice(&[0, 1, 0]);
+
+        let entries: Vec<HQMReplayEntry> = HQMReplayReader::new(data.as_slice()).unwrap().entries().collect::<io::Result<_>>().unwrap();
+        assert_eq!(entries.len(), 4);
+        assert!(matches!(entries[0], HQMReplayEntry::NewGame { game_id: 9, .. }));
+        match &entries[1] {
+            HQMReplayEntry::Tick { time, tick } => {
+                assert_eq!(*time, Duration::from_millis(10));
+                assert_eq!(tick.state.step, 100);
+                assert_eq!(tick.messages.len(), 1);
+                assert_eq!(tick.state.players[&0].name, "Bot");
+            }
+            x => panic!("unexpected entry {:?}", x)
+        }
+        match &entries[2] {
+            HQMReplayEntry::Sent { packet: ClientPacket::Update(update), .. } => {
+                assert_eq!(update.input.fwbw, 1.0);
+                assert_eq!(update.chat, Some((0, "hello".to_owned())));
+            }
+            x => panic!("unexpected entry {:?}", x)
+        }
+        match &entries[3] {
+            HQMReplayEntry::Tick { tick, .. } => {
+                assert_eq!(tick.state.step, 102);
+                assert!(tick.messages.is_empty());
+                let puck = tick.state.puck().unwrap();
+                assert_eq!(puck.pos.z, (30 * 1024 + 1020) as f32 / 1024.0);
+                assert!(puck.vel.z > 0.0);
+            }
+            x => panic!("unexpected entry {:?}", x)
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(HQMReplayReader::new(&b"Hock\x01"[..]).is_err());
+        assert!(HQMReplayReader::new(&b"HQMR\x07"[..]).is_err());
+        let mut reader = HQMReplayReader::new(&b"HQMR\x01\x09\0\0\0\0\0\0"[..]).unwrap();
+        assert_eq!(reader.read_record().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sessions_are_appended() {
+        let path = std::env::temp_dir().join(format!("hqm-replay-append-{}.hqmr", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let baselines = DeltaBaselineCache::new();
+        for game_id in [3u32, 4].iter() {
+            let mut writer = HQMReplayWriter::append(&path).unwrap();
+            let new_game = hqm_parse::encode_server_packet(&ServerPacket::NewGame { game_id: *game_id }, &baselines);
+            writer.record(HQMReplayRecordKind::Received, &new_game).unwrap();
+            writer.flush().unwrap();
+        }
+
+        let reader = HQMReplayReader::open(&path).unwrap();
+        let records: Vec<HQMReplayRecord> = HQMReplayReader::open(&path).unwrap().collect::<io::Result<_>>().unwrap();
+        let kinds: Vec<HQMReplayRecordKind> = records.iter().map(|x| x.kind).collect();
+        assert_eq!(kinds, vec![HQMReplayRecordKind::SessionStart, HQMReplayRecordKind::Received,
+                               HQMReplayRecordKind::SessionStart, HQMReplayRecordKind::Received]);
+        // The second session is a new game again, even if the first one never said goodbye
+        let games: Vec<u32> = reader.entries().filter_map(|x| match x.unwrap() {
+            HQMReplayEntry::NewGame { game_id, .. } => Some(game_id),
+            _ => None
+        }).collect();
+        assert_eq!(games, vec![3, 4]);
+
+        std::fs::write(&path, b"not a replay").unwrap();
+        assert_eq!(HQMReplayWriter::append(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
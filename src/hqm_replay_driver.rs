@@ -0,0 +1,220 @@
+use crate::hqm_bot::HQMBotLogic;
+use crate::hqm_game::HQMPlayerInput;
+use crate::hqm_parse::ClientPacket;
+use crate::hqm_replay::{HQMReplayEntry, HQMReplayReader};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+/// What the logic produced for one recorded game update
+#[derive(Debug, Clone)]
+pub struct HQMReplayStep {
+    /// Time since the recording started
+    pub time: Duration,
+    pub game_id: u32,
+    pub step: u32,
+    pub input: HQMPlayerInput,
+    pub chat: Option<String>,
+    /// What the recorded bot sent back for the same update
+    pub recorded_input: Option<HQMPlayerInput>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HQMReplayRun {
+    pub steps: Vec<HQMReplayStep>,
+    /// Chat messages produced by the logic, in order
+    pub chats: Vec<String>,
+    /// Chat messages the recorded bot sent, in order
+    pub recorded_chats: Vec<String>,
+}
+
+impl HQMReplayRun {
+    /// Steps where the logic produced a different input than the recorded bot
+    pub fn input_differences(&self) -> impl Iterator<Item = &HQMReplayStep> {
+        self.steps.iter().filter(|x| x.recorded_input.as_ref().is_some_and(|recorded| *recorded != x.input))
+    }
+}
+
+/// Runs bot logic over a recorded session the same way `HQMBotSession` would, without a network
+pub struct ReplayDriver<T: HQMBotLogic> {
+    logic: T,
+    run: HQMReplayRun,
+    /// Index of the step waiting for the recorded bot's answer
+    awaiting_answer: Option<usize>,
+    last_recorded_chat: Option<(u32, String)>,
+}
+
+impl<T: HQMBotLogic> ReplayDriver<T> {
+    pub fn new(logic: T) -> Self {
+        ReplayDriver {
+            logic,
+            run: HQMReplayRun::default(),
+            awaiting_answer: None,
+            last_recorded_chat: None
+        }
+    }
+
+    pub fn logic(&self) -> &T {
+        &self.logic
+    }
+
+    pub fn logic_mut(&mut self) -> &mut T {
+        &mut self.logic
+    }
+
+    /// What has been collected so far
+    pub fn run(&self) -> &HQMReplayRun {
+        &self.run
+    }
+
+    /// Feeds one replay entry to the logic. Returns the input and chat it produced,
+    /// if the entry was a game update.
+    pub fn feed(&mut self, entry: &HQMReplayEntry) -> Option<(HQMPlayerInput, Option<String>)> {
+        match entry {
+            HQMReplayEntry::NewGame { .. } => {
+                self.awaiting_answer = None;
+                self.logic.new_game();
+                None
+            }
+            HQMReplayEntry::Tick { time, tick } => {
+                if !tick.events.is_empty() {
+                    self.logic.game_events(&tick.state, &tick.events);
+                }
+                let (input, chat) = self.logic.tick(&tick.state, &tick.messages);
+                if let Some(chat) = &chat {
+                    self.run.chats.push(chat.clone());
+                }
+                self.awaiting_answer = Some(self.run.steps.len());
+                self.run.steps.push(HQMReplayStep {
+                    time: *time,
+                    game_id: tick.state.game_id,
+                    step: tick.state.step,
+                    input: input.clone(),
+                    chat: chat.clone(),
+                    recorded_input: None
+                });
+                Some((input, chat))
+            }
+            HQMReplayEntry::Sent { packet: ClientPacket::Update(update), .. } => {
+                // The session answers every packet it receives right away, so this
+                // is the answer to the latest update, if that hasn't been answered yet
+                if let Some(index) = self.awaiting_answer.take() {
+                    self.run.steps[index].recorded_input = Some(update.input.clone());
+                }
+                // A chat message is repeated until the server echoes it
+                if update.chat.is_some() && update.chat != self.last_recorded_chat {
+                    self.run.recorded_chats.extend(update.chat.as_ref().map(|(_, message)| message.clone()));
+                }
+                self.last_recorded_chat = update.chat.clone();
+                None
+            }
+            HQMReplayEntry::Sent { .. } => None
+        }
+    }
+
+    /// Feeds a whole recording to the logic
+    pub fn run_reader<R: Read>(&mut self, reader: HQMReplayReader<R>) -> io::Result<&HQMReplayRun> {
+        for entry in reader.entries() {
+            self.feed(&entry?);
+        }
+        Ok(&self.run)
+    }
+
+    pub fn run_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<&HQMReplayRun> {
+        self.run_reader(HQMReplayReader::open(path)?)
+    }
+
+    pub fn into_run(self) -> HQMReplayRun {
+        self.run
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_bot::testing::{game_update, new_game, wait_until, ScriptedServer};
+    use crate::hqm_bot::{HQMBotSession, HQMSessionConfig};
+    use crate::hqm_game::{HQMGameState, HQMMessage, HQMTeam};
+    use crate::hqm_parse::{HQMObjectPacket, HQMPuckPacket};
+    use std::path::PathBuf;
+
+    /// Skates towards the puck along z and greets once per game
+    struct Chaser {
+        greeted: bool,
+        reverse: bool,
+    }
+
+    impl HQMBotLogic for Chaser {
+        fn new_game(&mut self) {
+            self.greeted = false;
+        }
+
+        fn tick(&mut self, state: &HQMGameState, _messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+            let mut input = HQMPlayerInput::default();
+            if let Some(puck) = state.puck() {
+                input.fwbw = if (puck.pos.z > 30.5) != self.reverse { 1.0 } else { -1.0 };
+            }
+            let chat = if self.greeted { None } else { Some("gl hf".to_owned()) };
+            self.greeted = true;
+            (input, chat)
+        }
+    }
+
+    /// Records a live session of a `Chaser` in a game of 20 updates, with the puck crossing the
+    /// middle of the rink
+    async fn record_session(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hqm-replay-driver-{}-{}.hqmr", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut packets = vec![new_game(3)];
+        packets.extend((0..20u32).map(|i| game_update(3, i, vec![HQMObjectPacket::Puck(HQMPuckPacket {
+            pos: (15 * 1024, 100, 25 * 1024 + i * 600),
+            rot: (0, 0)
+        })], vec![HQMMessage::PlayerUpdate {
+            player_name: "Chaser".to_owned(),
+            object: Some((0, HQMTeam::Red)),
+            player_index: 0,
+            in_server: true
+        }])));
+        let server = ScriptedServer::start(packets).await.unwrap();
+        let config = HQMSessionConfig {
+            replay_path: Some(path.clone()),
+            ..Default::default()
+        };
+        let mut session = HQMBotSession::with_config("Chaser".to_owned(), Chaser { greeted: false, reverse: false }, config);
+        let shutdown = session.shutdown_handle();
+        let addr = server.addr();
+        let task = tokio::spawn(async move { session.start(addr).await.unwrap() });
+        // The join message and an answer to each of the 21 packets
+        wait_until(Duration::from_secs(5), "the last answer", || server.received().len() == 22).await;
+        shutdown.stop();
+        task.await.unwrap();
+        server.stop().await;
+        path
+    }
+
+    #[tokio::test]
+    async fn same_logic_reproduces_the_recording() {
+        let path = record_session("same").await;
+        let mut driver = ReplayDriver::new(Chaser { greeted: false, reverse: false });
+        let run = driver.run_file(&path).unwrap();
+        assert_eq!(run.steps.len(), 20);
+        assert!(run.steps.iter().all(|x| x.recorded_input.is_some()));
+        assert_eq!(run.input_differences().count(), 0);
+        assert_eq!(run.chats, vec!["gl hf".to_owned()]);
+        assert_eq!(run.recorded_chats, run.chats);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn changed_logic_shows_up_as_differences() {
+        let path = record_session("changed").await;
+        let mut driver = ReplayDriver::new(Chaser { greeted: false, reverse: true });
+        let run = driver.run_file(&path).unwrap();
+        assert_eq!(run.input_differences().count(), 20);
+        let first = run.input_differences().next().unwrap();
+        assert_eq!(first.step, 0);
+        assert_eq!(first.input.fwbw, 1.0);
+        assert_eq!(first.recorded_input.as_ref().unwrap().fwbw, -1.0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+use crate::hqm_game::HQMTeam;
+use nalgebra::{Point3, Vector3};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HQMZone {
+    Defensive,
+    Neutral,
+    Offensive,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HQMFaceoffSpotKind {
+    Center,
+    /// Neutral zone spot next to the blue line of the given team's defensive zone
+    Neutral(HQMTeam),
+    /// End zone spot in the given team's defensive zone
+    EndZone(HQMTeam),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct HQMFaceoffSpot {
+    pub kind: HQMFaceoffSpotKind,
+    pub pos: Point3<f32>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct HQMNetDimensions {
+    /// Distance from the middle of the goal mouth to each post
+    pub half_width: f32,
+    pub height: f32,
+    /// How far the net extends behind the goal line
+    pub depth: f32,
+}
+
+/// Rink layout in the same metre coordinates as `HQMGameState`. x goes across the rink,
+/// y is up and z runs along the rink. Blue defends the net at the low z end, red the one
+/// at the high z end.
+#[derive(Debug, Clone)]
+pub struct Rink {
+    pub width: f32,
+    pub length: f32,
+    pub corner_radius: f32,
+    /// Distance from the end boards to the goal line
+    pub goal_line_distance: f32,
+    /// Distance from the end boards to the middle of the blue line
+    pub blue_line_distance: f32,
+    pub line_width: f32,
+    pub net: HQMNetDimensions,
+    pub faceoff_spots: Vec<HQMFaceoffSpot>,
+}
+
+impl Default for Rink {
+    fn default() -> Self {
+        Rink::standard()
+    }
+}
+
+impl Rink {
+    /// The rink used by standard HQM servers
+    pub fn standard() -> Self {
+        let width = 30.0;
+        let length = 61.0;
+        let goal_line_distance = 4.0;
+        let line_width = 0.3;
+        let blue_line_neutral_zone_edge = 22.86;
+        let center_x = width / 2.0;
+
+        let mut faceoff_spots = vec![HQMFaceoffSpot {
+            kind: HQMFaceoffSpotKind::Center,
+            pos: Point3::new(center_x, 0.0, length / 2.0)
+        }];
+        for &(team, sign, end) in [(HQMTeam::Blue, 1.0, 0.0), (HQMTeam::Red, -1.0, length)].iter() {
+            for &x in [center_x - 7.0, center_x + 7.0].iter() {
+                faceoff_spots.push(HQMFaceoffSpot {
+                    kind: HQMFaceoffSpotKind::Neutral(team),
+                    pos: Point3::new(x, 0.0, end + sign * (blue_line_neutral_zone_edge + 1.5))
+                });
+                faceoff_spots.push(HQMFaceoffSpot {
+                    kind: HQMFaceoffSpotKind::EndZone(team),
+                    pos: Point3::new(x, 0.0, end + sign * (goal_line_distance + 6.0))
+                });
+            }
+        }
+
+        Rink {
+            width,
+            length,
+            corner_radius: 8.5,
+            goal_line_distance,
+            blue_line_distance: blue_line_neutral_zone_edge - line_width / 2.0,
+            line_width,
+            net: HQMNetDimensions {
+                half_width: 1.5,
+                height: 1.0,
+                depth: 1.0
+            },
+            faceoff_spots
+        }
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        Point3::new(self.width / 2.0, 0.0, self.length / 2.0)
+    }
+
+    /// Which way is "into the net" along z for the net defended by `team`
+    pub fn net_direction(&self, team: HQMTeam) -> f32 {
+        match team {
+            HQMTeam::Blue => -1.0,
+            HQMTeam::Red => 1.0,
+        }
+    }
+
+    /// z of the goal line in front of the net defended by `team`
+    pub fn goal_line_z(&self, team: HQMTeam) -> f32 {
+        match team {
+            HQMTeam::Blue => self.goal_line_distance,
+            HQMTeam::Red => self.length - self.goal_line_distance,
+        }
+    }
+
+    /// z of the middle of the blue line of the zone defended by `team`
+    pub fn blue_line_z(&self, team: HQMTeam) -> f32 {
+        match team {
+            HQMTeam::Blue => self.blue_line_distance,
+            HQMTeam::Red => self.length - self.blue_line_distance,
+        }
+    }
+
+    /// Middle of the goal mouth, on the ice, of the net defended by `team`
+    pub fn net_center(&self, team: HQMTeam) -> Point3<f32> {
+        Point3::new(self.width / 2.0, 0.0, self.goal_line_z(team))
+    }
+
+    /// The goal mouth of the net defended by `team`: bottom of the left post, top of the
+    /// left post, top of the right post, bottom of the right post.
+    pub fn net_mouth(&self, team: HQMTeam) -> [Point3<f32>; 4] {
+        let z = self.goal_line_z(team);
+        let left = self.width / 2.0 - self.net.half_width;
+        let right = self.width / 2.0 + self.net.half_width;
+        [
+            Point3::new(left, 0.0, z),
+            Point3::new(left, self.net.height, z),
+            Point3::new(right, self.net.height, z),
+            Point3::new(right, 0.0, z),
+        ]
+    }
+
+    /// Zone of `pos` from the point of view of `team`. The blue lines belong to the neutral zone.
+    pub fn zone(&self, pos: &Point3<f32>, team: HQMTeam) -> HQMZone {
+        let half_line = self.line_width / 2.0;
+        let blue_side = if pos.z < self.blue_line_z(HQMTeam::Blue) - half_line {
+            Some(HQMTeam::Blue)
+        } else if pos.z > self.blue_line_z(HQMTeam::Red) + half_line {
+            Some(HQMTeam::Red)
+        } else {
+            None
+        };
+        match blue_side {
+            Some(defending) if defending == team => HQMZone::Defensive,
+            Some(_) => HQMZone::Offensive,
+            None => HQMZone::Neutral
+        }
+    }
+
+    /// Horizontal distance from `pos` to the boards, negative if outside the rink
+    pub fn distance_to_boards(&self, pos: &Point3<f32>) -> f32 {
+        match self.corner_center(pos) {
+            Some(center) => {
+                let dx = pos.x - center.0;
+                let dz = pos.z - center.1;
+                self.corner_radius - (dx * dx + dz * dz).sqrt()
+            }
+            None => pos.x.min(self.width - pos.x).min(pos.z).min(self.length - pos.z)
+        }
+    }
+
+    /// Inward normal and penetration depth if a sphere of `radius` at `pos` reaches past the boards
+    pub(crate) fn board_contact(&self, pos: &Point3<f32>, radius: f32) -> Option<(Vector3<f32>, f32)> {
+        let x = pos.x;
+        let z = pos.z;
+        if let Some((cx, cz)) = self.corner_center(pos) {
+            let diff = Vector3::new(cx - x, 0.0, cz - z);
+            let distance = diff.norm();
+            let penetration = distance - (self.corner_radius - radius);
+            if penetration > 0.0 && distance > 0.0 {
+                Some((diff / distance, penetration))
+            } else {
+                None
+            }
+        } else if x < radius {
+            Some((Vector3::x(), radius - x))
+        } else if x > self.width - radius {
+            Some((-Vector3::x(), x - (self.width - radius)))
+        } else if z < radius {
+            Some((Vector3::z(), radius - z))
+        } else if z > self.length - radius {
+            Some((-Vector3::z(), z - (self.length - radius)))
+        } else {
+            None
+        }
+    }
+
+    /// Center (x, z) of the rounded corner `pos` is next to, if any
+    pub(crate) fn corner_center(&self, pos: &Point3<f32>) -> Option<(f32, f32)> {
+        let r = self.corner_radius;
+        let cx = if pos.x < r {
+            r
+        } else if pos.x > self.width - r {
+            self.width - r
+        } else {
+            return None;
+        };
+        let cz = if pos.z < r {
+            r
+        } else if pos.z > self.length - r {
+            self.length - r
+        } else {
+            return None;
+        };
+        Some((cx, cz))
+    }
+
+    pub fn faceoff_spot(&self, kind: HQMFaceoffSpotKind) -> impl Iterator<Item = &HQMFaceoffSpot> {
+        self.faceoff_spots.iter().filter(move |x| x.kind == kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zones_are_mirrored_between_teams() {
+        let rink = Rink::standard();
+        let near_blue_net = Point3::new(15.0, 0.0, 10.0);
+        assert_eq!(rink.zone(&near_blue_net, HQMTeam::Blue), HQMZone::Defensive);
+        assert_eq!(rink.zone(&near_blue_net, HQMTeam::Red), HQMZone::Offensive);
+        assert_eq!(rink.zone(&rink.center(), HQMTeam::Red), HQMZone::Neutral);
+        let on_red_blue_line = Point3::new(3.0, 0.0, rink.blue_line_z(HQMTeam::Red));
+        assert_eq!(rink.zone(&on_red_blue_line, HQMTeam::Blue), HQMZone::Neutral);
+    }
+
+    #[test]
+    fn distance_to_boards_follows_rounded_corners() {
+        let rink = Rink::standard();
+        assert_eq!(rink.distance_to_boards(&Point3::new(2.0, 0.0, 30.5)), 2.0);
+        assert_eq!(rink.distance_to_boards(&Point3::new(15.0, 0.0, 60.0)), 1.0);
+        // Right in the corner, outside the rounded boards
+        assert!(rink.distance_to_boards(&Point3::new(0.5, 0.0, 0.5)) < 0.0);
+        let corner = rink.distance_to_boards(&Point3::new(8.5 - 6.0, 0.0, 8.5));
+        assert!((corner - 2.5).abs() < 1e-5);
+    }
+}
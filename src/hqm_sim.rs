@@ -0,0 +1,884 @@
+use crate::hqm_clock::REGULATION_PERIODS;
+use crate::hqm_game::{HQMGameStateObject, HQMGameStatePuck, HQMGameStateSkater, HQMMessage, HQMPlayerInput, HQMTeam};
+use crate::hqm_parse::{self, HQMGameUpdatePacket, ServerPacket};
+use crate::hqm_possession::{HQMPuckTouch, BLADE_HALF_LENGTH, BLADE_OFFSET};
+use crate::hqm_predict::{self, bounce, HQMPredictedPuck, GRAVITY, PUCK_RADIUS};
+use crate::hqm_rink::Rink;
+use crate::hqm_tracker::{HQMGameTracker, HQMTick};
+use nalgebra::{Matrix3, Point3, Rotation3, Vector3};
+use std::collections::{BTreeMap, VecDeque};
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, FRAC_PI_8, PI};
+use std::sync::Arc;
+
+const OBJECT_SLOTS: usize = 32;
+const MAX_PLAYERS: usize = 64;
+/// The wire format has room for this many messages per update
+const MAX_MESSAGES_PER_UPDATE: usize = 15;
+const PUCK_TOUCHES: usize = 8;
+
+// Skater physics constants per step, approximating what the server does
+const SKATER_RADIUS: f32 = 0.5;
+const SKATER_HEIGHT: f32 = 0.75;
+const CROUCH_HEIGHT: f32 = 0.25;
+const STAND_UP_SPEED: f32 = 0.05;
+const JUMP_SPEED: f32 = 0.025;
+const MAX_SPEED: f32 = 0.05;
+const MAX_BACKWARDS_SPEED: f32 = 0.0333;
+const ACCELERATION: f32 = 0.000208;
+const DECELERATION: f32 = 0.000555;
+const GLIDE_FRICTION: f32 = 0.00005;
+/// Share of the sideways velocity the skates take away each step
+const SIDE_GRIP: f32 = 0.05;
+const MAX_TURN_SPEED: f32 = 0.04;
+const TURN_ACCELERATION: f32 = 0.0025;
+const SKATER_RESTITUTION: f32 = 0.2;
+const SKATER_BOARD_FRICTION: f32 = 0.1;
+
+// Stick constants. The blade follows a point on a sphere around the skater, picked by
+// the azimuth and inclination in `HQMPlayerInput::stick`.
+const STICK_LENGTH: f32 = 1.6;
+/// Inclination of the stick with `stick.y` at zero, puts the blade on the ice in front of the skater
+const STICK_REST_INCLINATION: f32 = -0.45;
+const MIN_BLADE_HEIGHT: f32 = 0.05;
+const STICK_FOLLOW: f32 = 0.3;
+const MAX_STICK_SPEED: f32 = 0.35;
+const MAX_BLADE_TILT: f32 = FRAC_PI_4;
+const BLADE_RADIUS: f32 = 0.05;
+const STICK_RESTITUTION: f32 = 0.2;
+const STICK_FRICTION: f32 = 0.1;
+/// How much an open or closed blade face lifts the puck it hits
+const BLADE_LIFT: f32 = 1.0;
+
+#[derive(Debug, Clone)]
+pub struct HQMSimConfig {
+    pub rink: Arc<Rink>,
+    /// Steps of warmup before the first period, zero to start in period 1
+    pub warmup_length: u32,
+    pub period_length: u32,
+    /// Steps from a goal to the next faceoff
+    pub goal_break: u32,
+    pub team_max: usize,
+}
+
+impl Default for HQMSimConfig {
+    fn default() -> Self {
+        HQMSimConfig {
+            rink: Arc::new(Rink::standard()),
+            warmup_length: 0,
+            period_length: 30000,
+            goal_break: 700,
+            team_max: 5
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct HQMSimPlayer {
+    name: String,
+    input: HQMPlayerInput,
+    object: Option<(usize, HQMTeam)>,
+}
+
+#[derive(Debug, Clone)]
+struct HQMSimSkater {
+    player_index: usize,
+    team: HQMTeam,
+    pos: Point3<f32>,
+    vel: Vector3<f32>,
+    /// Rotation around the y axis, zero facing -z
+    yaw: f32,
+    turn_speed: f32,
+    jump_held: bool,
+    /// Middle of the blade
+    blade_pos: Point3<f32>,
+    stick_vel: Vector3<f32>,
+    stick_rot: Matrix3<f32>,
+    stick_tilt: f32,
+    head_rot: f32,
+    body_rot: f32,
+}
+
+#[derive(Debug, Clone)]
+struct HQMSimPuck {
+    puck: HQMPredictedPuck,
+    /// Players that touched the puck, latest first
+    touches: VecDeque<HQMPuckTouch>,
+}
+
+/// Runs a game locally, stepping skater and puck physics from player inputs without a server.
+/// The physics approximate the real server: skaters only turn around the vertical axis and
+/// do not collide with the nets or the puck, only sticks do.
+#[derive(Debug)]
+pub struct HQMSimulator {
+    config: HQMSimConfig,
+    game_id: u32,
+    step: u32,
+    red_score: u32,
+    blue_score: u32,
+    period: u32,
+    time: u32,
+    goal_time: u32,
+    game_over: bool,
+    players: BTreeMap<usize, HQMSimPlayer>,
+    /// Slot 0 is always the puck
+    skaters: Vec<Option<HQMSimSkater>>,
+    puck: HQMSimPuck,
+    messages: Vec<HQMMessage>,
+}
+
+impl HQMSimulator {
+    pub fn new(config: HQMSimConfig) -> Self {
+        let mut sim = HQMSimulator {
+            config,
+            game_id: 0,
+            step: 0,
+            red_score: 0,
+            blue_score: 0,
+            period: 0,
+            time: 0,
+            goal_time: 0,
+            game_over: false,
+            players: BTreeMap::new(),
+            skaters: vec![None; OBJECT_SLOTS],
+            puck: HQMSimPuck {
+                puck: HQMPredictedPuck { pos: Point3::origin(), vel: Vector3::zeros() },
+                touches: VecDeque::new()
+            },
+            messages: Vec::new()
+        };
+        sim.new_game();
+        sim
+    }
+
+    pub fn config(&self) -> &HQMSimConfig {
+        &self.config
+    }
+
+    pub fn game_id(&self) -> u32 {
+        self.game_id
+    }
+
+    pub fn step_number(&self) -> u32 {
+        self.step
+    }
+
+    pub fn game_over(&self) -> bool {
+        self.game_over
+    }
+
+    /// Starts a new game with the same players, all of them spectating
+    pub fn new_game(&mut self) {
+        self.game_id += 1;
+        self.red_score = 0;
+        self.blue_score = 0;
+        self.goal_time = 0;
+        self.game_over = false;
+        if self.config.warmup_length > 0 {
+            self.period = 0;
+            self.time = self.config.warmup_length;
+        } else {
+            self.period = 1;
+            self.time = self.config.period_length;
+        }
+        self.skaters.iter_mut().for_each(|x| *x = None);
+        self.messages.clear();
+        for (index, player) in self.players.iter_mut() {
+            player.object = None;
+            self.messages.push(HQMMessage::PlayerUpdate {
+                player_name: player.name.clone(),
+                object: None,
+                player_index: *index,
+                in_server: true
+            });
+        }
+        self.faceoff();
+    }
+
+    /// Adds a spectating player. Returns its player index, None if the server is full.
+    pub fn add_player(&mut self, name: &str) -> Option<usize> {
+        let index = (0..MAX_PLAYERS).find(|x| !self.players.contains_key(x))?;
+        self.players.insert(index, HQMSimPlayer {
+            name: name.to_owned(),
+            input: HQMPlayerInput::default(),
+            object: None
+        });
+        self.messages.push(HQMMessage::PlayerUpdate {
+            player_name: name.to_owned(),
+            object: None,
+            player_index: index,
+            in_server: true
+        });
+        Some(index)
+    }
+
+    pub fn remove_player(&mut self, player_index: usize) -> bool {
+        if let Some(player) = self.players.remove(&player_index) {
+            if let Some((object_index, _)) = player.object {
+                self.skaters[object_index] = None;
+            }
+            self.messages.push(HQMMessage::PlayerUpdate {
+                player_name: player.name,
+                object: None,
+                player_index,
+                in_server: false
+            });
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Sets the input used for `player_index` from the next step on
+    pub fn set_input(&mut self, player_index: usize, input: HQMPlayerInput) {
+        if let Some(player) = self.players.get_mut(&player_index) {
+            player.input = input;
+        }
+    }
+
+    pub fn chat(&mut self, player_index: Option<usize>, message: &str) {
+        self.messages.push(HQMMessage::Chat {
+            player_index,
+            message: message.to_owned()
+        });
+    }
+
+    /// Moves the puck, forgetting who touched it
+    pub fn place_puck(&mut self, pos: Point3<f32>, vel: Vector3<f32>) {
+        self.puck.puck = HQMPredictedPuck { pos, vel };
+        self.puck.touches.clear();
+    }
+
+    /// Moves the skater of `player_index` to a standstill at `pos`, facing `yaw` radians
+    /// around the y axis from -z. Returns false if the player is not on the ice.
+    pub fn place_skater(&mut self, player_index: usize, pos: Point3<f32>, yaw: f32) -> bool {
+        let (object_index, _) = match self.players.get(&player_index).and_then(|x| x.object) {
+            Some(object) => object,
+            None => return false
+        };
+        let input = self.players[&player_index].input.clone();
+        if let Some(skater) = &mut self.skaters[object_index] {
+            skater.place(pos, yaw, &input);
+        }
+        true
+    }
+
+    /// All objects in their network slots, with exact positions and velocities
+    pub fn objects(&self) -> Vec<HQMGameStateObject> {
+        let mut objects: Vec<HQMGameStateObject> = self.skaters.iter().map(|x| match x {
+            Some(skater) => HQMGameStateObject::Skater(skater.to_state()),
+            None => HQMGameStateObject::None
+        }).collect();
+        objects[0] = HQMGameStateObject::Puck(HQMGameStatePuck {
+            pos: self.puck.puck.pos,
+            rot: Matrix3::identity(),
+            vel: self.puck.puck.vel,
+            angular_vel: Vector3::zeros(),
+            acc: Vector3::zeros()
+        });
+        objects
+    }
+
+    /// The update the server would send `player_index` after this step, starting with
+    /// message `known_msgpos`. Objects are rounded to what the network can carry.
+    pub fn update_packet(&self, player_index: usize, known_msgpos: u32) -> HQMGameUpdatePacket {
+        let message_pos = (known_msgpos as usize).min(self.messages.len());
+        let message_end = (message_pos + MAX_MESSAGES_PER_UPDATE).min(self.messages.len());
+        HQMGameUpdatePacket {
+            game_id: self.game_id,
+            step: self.step,
+            game_over: self.game_over,
+            red_score: self.red_score,
+            blue_score: self.blue_score,
+            time: self.time,
+            goal_time: self.goal_time,
+            period: self.period,
+            own_player_index: player_index,
+            packet: self.step,
+            known_packet: u32::MAX,
+            objects: self.objects().iter().map(hqm_parse::convert_object_to_network).collect(),
+            message_pos: message_pos as u32,
+            messages: self.messages[message_pos..message_end].to_vec()
+        }
+    }
+
+    /// Advances the game one step, 1/100 of a second
+    pub fn step(&mut self) {
+        self.step += 1;
+        self.update_teams();
+
+        let rink = self.config.rink.clone();
+        for skater in self.skaters.iter_mut().flatten() {
+            let input = &self.players[&skater.player_index].input;
+            skater.skate(&rink, input);
+            skater.move_stick(input);
+        }
+        self.collide_skaters();
+
+        for skater in self.skaters.iter().flatten() {
+            if skater.hit_puck(&mut self.puck.puck) {
+                let touch = HQMPuckTouch { player_index: skater.player_index, team: skater.team };
+                if self.puck.touches.front() != Some(&touch) {
+                    self.puck.touches.push_front(touch);
+                    self.puck.touches.truncate(PUCK_TOUCHES);
+                }
+            }
+        }
+        let goal = hqm_predict::step_puck(&rink, &mut self.puck.puck);
+
+        if self.game_over {
+            return;
+        }
+        if self.goal_time > 0 {
+            self.goal_time -= 1;
+            if self.goal_time == 0 {
+                self.faceoff();
+            }
+            return;
+        }
+        if let Some(team) = goal {
+            self.goal(team);
+            return;
+        }
+        self.time = self.time.saturating_sub(1);
+        if self.time == 0 {
+            self.end_period();
+        }
+    }
+
+    /// Puts players on the ice or takes them off as their inputs ask
+    fn update_teams(&mut self) {
+        let indices: Vec<usize> = self.players.keys().copied().collect();
+        for player_index in indices {
+            let player = &self.players[&player_index];
+            let wanted = if player.input.spectate {
+                None
+            } else if player.input.join_red {
+                Some(HQMTeam::Red)
+            } else if player.input.join_blue {
+                Some(HQMTeam::Blue)
+            } else {
+                continue;
+            };
+            let current = player.object.map(|(_, team)| team);
+            if wanted == current {
+                continue;
+            }
+            if let Some(team) = wanted {
+                if self.skaters.iter().flatten().filter(|x| x.team == team).count() >= self.config.team_max {
+                    continue;
+                }
+            }
+            if let Some((object_index, _)) = player.object {
+                self.skaters[object_index] = None;
+            }
+            let object = match wanted {
+                Some(team) => match self.spawn(player_index, team) {
+                    Some(object_index) => Some((object_index, team)),
+                    None => continue
+                },
+                None => None
+            };
+            let player = self.players.get_mut(&player_index).unwrap();
+            player.object = object;
+            self.messages.push(HQMMessage::PlayerUpdate {
+                player_name: player.name.clone(),
+                object,
+                player_index,
+                in_server: true
+            });
+        }
+    }
+
+    fn spawn(&mut self, player_index: usize, team: HQMTeam) -> Option<usize> {
+        let object_index = (1..OBJECT_SLOTS).find(|x| self.skaters[*x].is_none())?;
+        let teammates = self.skaters.iter().flatten().filter(|x| x.team == team).count();
+        let (pos, yaw) = self.faceoff_position(team, teammates);
+        let input = &self.players[&player_index].input;
+        let mut skater = HQMSimSkater {
+            player_index,
+            team,
+            pos,
+            vel: Vector3::zeros(),
+            yaw,
+            turn_speed: 0.0,
+            jump_held: false,
+            blade_pos: pos,
+            stick_vel: Vector3::zeros(),
+            stick_rot: Matrix3::identity(),
+            stick_tilt: 0.0,
+            head_rot: 0.0,
+            body_rot: 0.0
+        };
+        skater.place(pos, yaw, input);
+        self.skaters[object_index] = Some(skater);
+        Some(object_index)
+    }
+
+    /// Where the `n`th skater of `team` lines up for a faceoff. Each team lines up in its
+    /// own half, facing the other net.
+    fn faceoff_position(&self, team: HQMTeam, n: usize) -> (Point3<f32>, f32) {
+        let center = self.config.rink.center();
+        let x = center.x + [0.0, -4.0, 4.0][n % 3];
+        let distance = 2.0 + 2.0 * (n / 3) as f32;
+        match team {
+            HQMTeam::Red => (Point3::new(x, SKATER_HEIGHT, center.z + distance), 0.0),
+            HQMTeam::Blue => (Point3::new(x, SKATER_HEIGHT, center.z - distance), PI),
+        }
+    }
+
+    fn faceoff(&mut self) {
+        let center = self.config.rink.center();
+        self.place_puck(center + Vector3::new(0.0, 0.5, 0.0), Vector3::zeros());
+        let mut counts = [0usize; 2];
+        for i in 0..OBJECT_SLOTS {
+            if let Some(skater) = &self.skaters[i] {
+                let count = &mut counts[(skater.team == HQMTeam::Blue) as usize];
+                let (pos, yaw) = self.faceoff_position(skater.team, *count);
+                *count += 1;
+                let input = self.players[&skater.player_index].input.clone();
+                self.skaters[i].as_mut().unwrap().place(pos, yaw, &input);
+            }
+        }
+    }
+
+    fn goal(&mut self, team: HQMTeam) {
+        if self.period == 0 {
+            // Goals in warmup don't count
+            self.faceoff();
+            return;
+        }
+        match team {
+            HQMTeam::Red => self.red_score += 1,
+            HQMTeam::Blue => self.blue_score += 1,
+        }
+        let mut scorers = self.puck.touches.iter().filter(|x| x.team == team);
+        let goal_player_index = scorers.next().map(|x| x.player_index);
+        let assist_player_index = scorers.map(|x| x.player_index).find(|x| Some(*x) != goal_player_index);
+        self.messages.push(HQMMessage::Goal {
+            team,
+            goal_player_index,
+            assist_player_index
+        });
+        self.goal_time = self.config.goal_break.max(1);
+        if self.period > REGULATION_PERIODS {
+            // Sudden death
+            self.game_over = true;
+        }
+    }
+
+    fn end_period(&mut self) {
+        if self.period >= REGULATION_PERIODS && self.red_score != self.blue_score {
+            self.game_over = true;
+            return;
+        }
+        if self.period == 0 {
+            self.red_score = 0;
+            self.blue_score = 0;
+        }
+        self.period += 1;
+        self.time = self.config.period_length;
+        self.faceoff();
+    }
+
+    fn collide_skaters(&mut self) {
+        for j in 1..OBJECT_SLOTS {
+            let (before, after) = self.skaters.split_at_mut(j);
+            let b = match &mut after[0] {
+                Some(b) => b,
+                None => continue
+            };
+            for a in before.iter_mut().flatten() {
+                let mut diff = a.pos - b.pos;
+                diff.y = 0.0;
+                let distance = diff.norm();
+                if distance >= 2.0 * SKATER_RADIUS || distance == 0.0 {
+                    continue;
+                }
+                let normal = diff / distance;
+                let push = normal * (SKATER_RADIUS - distance / 2.0);
+                a.pos += push;
+                b.pos -= push;
+                let normal_speed = (a.vel - b.vel).dot(&normal);
+                if normal_speed < 0.0 {
+                    let impulse = normal * (normal_speed * (1.0 + SKATER_RESTITUTION) / 2.0);
+                    a.vel -= impulse;
+                    b.vel += impulse;
+                }
+            }
+        }
+    }
+}
+
+impl HQMSimSkater {
+    fn rotation(&self) -> Rotation3<f32> {
+        Rotation3::from_axis_angle(&Vector3::y_axis(), self.yaw)
+    }
+
+    fn place(&mut self, pos: Point3<f32>, yaw: f32, input: &HQMPlayerInput) {
+        self.pos = pos;
+        self.vel = Vector3::zeros();
+        self.yaw = yaw;
+        self.turn_speed = 0.0;
+        let (target, rot) = self.stick_target(input);
+        self.blade_pos = target;
+        self.stick_rot = rot;
+        self.stick_vel = Vector3::zeros();
+    }
+
+    fn skate(&mut self, rink: &Rink, input: &HQMPlayerInput) {
+        let rotation = self.rotation();
+        let forward = rotation * -Vector3::z();
+        let right = rotation * Vector3::x();
+        let turn = input.turn.clamp(-1.0, 1.0);
+        let fwbw = input.fwbw.clamp(-1.0, 1.0);
+        let height = if input.crouch { CROUCH_HEIGHT } else { SKATER_HEIGHT };
+        let on_ice = self.pos.y <= height + 0.001;
+
+        let target_turn = if input.shift_rotate {
+            accelerate(&mut self.vel, &(right * turn.signum()), turn.abs(), MAX_BACKWARDS_SPEED);
+            0.0
+        } else {
+            // Turning right is a negative rotation around y
+            -turn * MAX_TURN_SPEED
+        };
+        self.turn_speed += (target_turn - self.turn_speed).clamp(-TURN_ACCELERATION, TURN_ACCELERATION);
+        self.yaw = (self.yaw + self.turn_speed + PI).rem_euclid(2.0 * PI) - PI;
+
+        if fwbw > 0.0 {
+            accelerate(&mut self.vel, &forward, fwbw, MAX_SPEED);
+        } else if fwbw < 0.0 {
+            accelerate(&mut self.vel, &-forward, -fwbw, MAX_BACKWARDS_SPEED);
+        } else {
+            let horizontal = Vector3::new(self.vel.x, 0.0, self.vel.z);
+            let speed = horizontal.norm();
+            if speed > 0.0 {
+                self.vel -= horizontal * (GLIDE_FRICTION.min(speed) / speed);
+            }
+        }
+        if on_ice && !input.shift_rotate {
+            let side = self.vel.dot(&right);
+            self.vel -= right * (side * SIDE_GRIP);
+        }
+
+        if on_ice && input.jump && !self.jump_held {
+            self.vel.y = JUMP_SPEED;
+        }
+        self.jump_held = input.jump;
+        self.vel.y -= GRAVITY;
+        self.pos += self.vel;
+        if self.pos.y < height {
+            self.pos.y = (self.pos.y.max(CROUCH_HEIGHT) + STAND_UP_SPEED).min(height);
+            self.vel.y = 0.0;
+        }
+
+        if let Some((normal, penetration)) = rink.board_contact(&self.pos, SKATER_RADIUS) {
+            self.pos += normal * penetration;
+            bounce(&mut self.vel, &normal, SKATER_RESTITUTION, SKATER_BOARD_FRICTION);
+        }
+
+        self.head_rot = input.head_rot.clamp(-PI, PI);
+        self.body_rot = input.body_rot.clamp(-FRAC_PI_2, FRAC_PI_2);
+    }
+
+    /// Where the middle of the blade is headed, and the stick rotation there
+    fn stick_target(&self, input: &HQMPlayerInput) -> (Point3<f32>, Matrix3<f32>) {
+        let azimuth = input.stick.x.clamp(-FRAC_PI_2, FRAC_PI_2);
+        let inclination = input.stick.y.clamp(-5.0 * PI / 16.0, FRAC_PI_8) + STICK_REST_INCLINATION;
+        // Positive azimuth moves the stick to the right, a negative rotation around y
+        let facing = Rotation3::from_axis_angle(&Vector3::y_axis(), self.yaw - azimuth);
+        let reach = facing * -Vector3::z();
+        let mut target = self.pos + reach * (inclination.cos() * STICK_LENGTH) + Vector3::y() * (inclination.sin() * STICK_LENGTH);
+        target.y = target.y.max(MIN_BLADE_HEIGHT);
+        // The blade points away from the skater with its face to the side, and
+        // `stick_angle` tilts the face
+        let tilt = input.stick_angle.clamp(-1.0, 1.0) * MAX_BLADE_TILT;
+        let rot = facing * Rotation3::from_axis_angle(&Vector3::z_axis(), tilt);
+        (target, rot.into_inner())
+    }
+
+    fn move_stick(&mut self, input: &HQMPlayerInput) {
+        let (target, rot) = self.stick_target(input);
+        let carried = self.blade_pos + self.vel;
+        let mut follow = (target - carried) * STICK_FOLLOW;
+        let follow_speed = follow.norm();
+        if follow_speed > MAX_STICK_SPEED {
+            follow *= MAX_STICK_SPEED / follow_speed;
+        }
+        let new_pos = carried + follow;
+        self.stick_vel = new_pos - self.blade_pos;
+        self.blade_pos = new_pos;
+        self.stick_rot = rot;
+        self.stick_tilt = input.stick_angle.clamp(-1.0, 1.0) * MAX_BLADE_TILT;
+    }
+
+    /// Pushes the puck out of the blade and gives it the stick's speed. Returns true if they touched.
+    fn hit_puck(&self, puck: &mut HQMPredictedPuck) -> bool {
+        // A fast stick moves further than the blade is thick in one step, so check
+        // along the way to keep it from passing through the puck
+        let substeps = (self.stick_vel.norm() / BLADE_RADIUS).ceil().max(1.0) as usize;
+        (1..=substeps).any(|i| {
+            let blade_pos = self.blade_pos - self.stick_vel * (1.0 - i as f32 / substeps as f32);
+            self.hit_puck_at(&blade_pos, puck)
+        })
+    }
+
+    fn hit_puck_at(&self, blade_pos: &Point3<f32>, puck: &mut HQMPredictedPuck) -> bool {
+        let reach = PUCK_RADIUS + BLADE_RADIUS;
+        let axis = self.stick_rot * Vector3::z();
+        let along = (puck.pos - blade_pos).dot(&axis).clamp(-BLADE_HALF_LENGTH, BLADE_HALF_LENGTH);
+        let closest = blade_pos + axis * along;
+        let diff = puck.pos - closest;
+        let distance = diff.norm();
+        if distance >= reach || distance == 0.0 {
+            return false;
+        }
+        puck.pos += diff * ((reach - distance) / distance);
+
+        let mut normal = diff / distance;
+        normal.y += self.stick_tilt.sin().abs() * BLADE_LIFT;
+        normal.normalize_mut();
+        let mut relative = puck.vel - self.stick_vel;
+        let normal_speed = relative.dot(&normal);
+        if normal_speed < 0.0 {
+            relative -= normal * (normal_speed * (1.0 + STICK_RESTITUTION));
+        }
+        let tangent = relative - normal * relative.dot(&normal);
+        relative -= tangent * STICK_FRICTION;
+        puck.vel = self.stick_vel + relative;
+        true
+    }
+
+    fn to_state(&self) -> HQMGameStateSkater {
+        let axis = self.stick_rot * Vector3::z();
+        HQMGameStateSkater {
+            pos: self.pos,
+            rot: self.rotation().into_inner(),
+            stick_pos: self.blade_pos - axis * BLADE_OFFSET,
+            stick_rot: self.stick_rot,
+            head_rot: self.head_rot,
+            body_rot: self.body_rot,
+            vel: self.vel,
+            angular_vel: Vector3::new(0.0, self.turn_speed, 0.0),
+            acc: Vector3::zeros(),
+            stick_vel: self.stick_vel
+        }
+    }
+}
+
+/// Speeds `vel` up along the unit vector `dir` by `amount` of the full acceleration,
+/// braking harder while still moving the other way
+fn accelerate(vel: &mut Vector3<f32>, dir: &Vector3<f32>, amount: f32, max_speed: f32) {
+    let speed = vel.dot(dir);
+    if speed < max_speed {
+        let acceleration = if speed < 0.0 { DECELERATION } else { ACCELERATION };
+        *vel += dir * (acceleration * amount).min(max_speed - speed);
+    }
+}
+
+/// Sees a simulated game the way one player's bot would see it over the network
+#[derive(Debug)]
+pub struct HQMSimObserver {
+    player_index: usize,
+    tracker: HQMGameTracker,
+}
+
+impl HQMSimObserver {
+    pub fn new(sim: &HQMSimulator, player_index: usize) -> Self {
+        HQMSimObserver {
+            player_index,
+            tracker: HQMGameTracker::new(sim.config.rink.clone())
+        }
+    }
+
+    pub fn player_index(&self) -> usize {
+        self.player_index
+    }
+
+    /// The state and new messages after the latest step
+    pub fn observe(&mut self, sim: &HQMSimulator) -> HQMTick {
+        if self.tracker.current_game() != sim.game_id() {
+            self.tracker.handle_server_packet(ServerPacket::NewGame { game_id: sim.game_id() });
+        }
+        let packet = sim.update_packet(self.player_index, self.tracker.known_msgpos() as u32);
+        self.tracker.handle_game_update(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector2;
+
+    fn joined(sim: &mut HQMSimulator, name: &str, team: HQMTeam) -> usize {
+        let index = sim.add_player(name).unwrap();
+        let mut input = HQMPlayerInput::default();
+        match team {
+            HQMTeam::Red => input.join_red = true,
+            HQMTeam::Blue => input.join_blue = true,
+        }
+        sim.set_input(index, input);
+        sim.step();
+        sim.set_input(index, HQMPlayerInput::default());
+        index
+    }
+
+    #[test]
+    fn joining_shows_up_for_the_observer() {
+        let mut sim = HQMSimulator::new(HQMSimConfig::default());
+        let index = joined(&mut sim, "Sim", HQMTeam::Blue);
+        let mut observer = HQMSimObserver::new(&sim, index);
+        let tick = observer.observe(&sim);
+        assert_eq!(tick.state.game_id, sim.game_id());
+        assert_eq!(tick.state.period, 1);
+        let me = tick.state.me().unwrap();
+        assert_eq!(me.team, HQMTeam::Blue);
+        assert!(me.skater.pos.z < sim.config().rink.center().z);
+        assert!(tick.state.puck().is_some());
+        assert!(observer.observe(&sim).messages.is_empty());
+    }
+
+    #[test]
+    fn skaters_skate_and_turn() {
+        let mut sim = HQMSimulator::new(HQMSimConfig::default());
+        let index = joined(&mut sim, "Sim", HQMTeam::Red);
+        let start = sim.objects();
+        let start = match &start[1] {
+            HQMGameStateObject::Skater(skater) => skater.pos,
+            _ => panic!("no skater")
+        };
+
+        sim.set_input(index, HQMPlayerInput { fwbw: 1.0, ..Default::default() });
+        for _ in 0..300 {
+            sim.step();
+        }
+        let skater = match &sim.objects()[1] {
+            HQMGameStateObject::Skater(skater) => skater.clone(),
+            _ => panic!("no skater")
+        };
+        // Red faces -z
+        assert!(start.z - skater.pos.z > 4.0);
+        assert!((skater.pos.x - start.x).abs() < 0.01);
+        assert!(skater.vel.norm() <= MAX_SPEED + 0.0001);
+
+        sim.set_input(index, HQMPlayerInput { fwbw: 1.0, turn: 1.0, ..Default::default() });
+        for _ in 0..50 {
+            sim.step();
+        }
+        let skater = match &sim.objects()[1] {
+            HQMGameStateObject::Skater(skater) => skater.clone(),
+            _ => panic!("no skater")
+        };
+        // Turned right, towards +x
+        let forward = skater.rot * -Vector3::z();
+        assert!(forward.x > 0.5);
+        assert!(skater.vel.x > 0.0);
+    }
+
+    #[test]
+    fn stick_pushes_the_puck() {
+        let mut sim = HQMSimulator::new(HQMSimConfig::default());
+        let index = joined(&mut sim, "Sim", HQMTeam::Red);
+        let mut observer = HQMSimObserver::new(&sim, index);
+        sim.place_skater(index, Point3::new(15.0, SKATER_HEIGHT, 40.0), 0.0);
+        sim.place_puck(Point3::new(15.0, 0.0206, 37.0), Vector3::zeros());
+        sim.set_input(index, HQMPlayerInput { fwbw: 1.0, ..Default::default() });
+        let mut held = false;
+        for _ in 0..200 {
+            sim.step();
+            let tick = observer.observe(&sim);
+            held |= tick.state.possession.holder.is_some_and(|x| x.player_index == index);
+        }
+        assert!(held);
+        assert_eq!(sim.puck.touches.front().map(|x| x.player_index), Some(index));
+        assert!(sim.puck.puck.pos.z < 35.0);
+        assert!(sim.puck.puck.vel.z < 0.0);
+
+        // A quick sweep with an open blade sends the puck off the ice
+        sim.place_skater(index, Point3::new(15.0, SKATER_HEIGHT, 40.0), 0.0);
+        sim.set_input(index, HQMPlayerInput { stick: Vector2::new(-1.2, 0.0), stick_angle: 1.0, ..Default::default() });
+        for _ in 0..20 {
+            sim.step();
+        }
+        let blade = sim.skaters[1].as_ref().unwrap().blade_pos;
+        sim.place_puck(blade + Vector3::new(0.3, -blade.y + 0.0206, 0.0), Vector3::zeros());
+        sim.set_input(index, HQMPlayerInput { stick: Vector2::new(1.2, 0.0), stick_angle: 1.0, ..Default::default() });
+        let mut highest: f32 = 0.0;
+        for _ in 0..30 {
+            sim.step();
+            highest = highest.max(sim.puck.puck.pos.y);
+        }
+        assert!(sim.puck.puck.vel.x.abs() > 0.05 || sim.puck.puck.pos.x > 16.0);
+        assert!(highest > 0.1);
+    }
+
+    #[test]
+    fn goal_then_faceoff() {
+        let mut sim = HQMSimulator::new(HQMSimConfig { goal_break: 100, ..Default::default() });
+        let red = joined(&mut sim, "Red", HQMTeam::Red);
+        let other = joined(&mut sim, "Other", HQMTeam::Red);
+        let mut observer = HQMSimObserver::new(&sim, red);
+        observer.observe(&sim);
+
+        sim.place_skater(red, Point3::new(15.0, SKATER_HEIGHT, 10.0), 0.0);
+        sim.place_puck(Point3::new(15.0, 0.0206, 6.0), Vector3::new(0.0, 0.0, -0.1));
+        sim.puck.touches.push_front(HQMPuckTouch { player_index: other, team: HQMTeam::Red });
+        sim.puck.touches.push_front(HQMPuckTouch { player_index: red, team: HQMTeam::Red });
+        let mut goal = None;
+        for _ in 0..50 {
+            sim.step();
+            let tick = observer.observe(&sim);
+            for message in tick.messages {
+                if let HQMMessage::Goal { team, goal_player_index, assist_player_index } = message {
+                    goal = Some((team, goal_player_index, assist_player_index));
+                }
+            }
+        }
+        assert_eq!(goal, Some((HQMTeam::Red, Some(red), Some(other))));
+        let tick = observer.observe(&sim);
+        assert_eq!(tick.state.red_score, 1);
+        assert!(tick.state.goal_interruption);
+        let time = tick.state.time;
+
+        // The clock stands still during the break
+        while sim.goal_time > 0 {
+            sim.step();
+        }
+        let tick = observer.observe(&sim);
+        assert!(!tick.state.goal_interruption);
+        assert_eq!(tick.state.time, time);
+        let center = sim.config().rink.center();
+        assert!((tick.state.puck().unwrap().pos.z - center.z).abs() < 0.01);
+        assert!(tick.state.me().unwrap().skater.pos.z > center.z);
+    }
+
+    #[test]
+    fn periods_overtime_and_game_over() {
+        let mut sim = HQMSimulator::new(HQMSimConfig { warmup_length: 10, period_length: 100, goal_break: 10, ..Default::default() });
+        assert_eq!(sim.period, 0);
+        for _ in 0..10 {
+            sim.step();
+        }
+        assert_eq!((sim.period, sim.time), (1, 100));
+        for _ in 0..300 {
+            sim.step();
+        }
+        // Tied after regulation
+        assert_eq!((sim.period, sim.time), (4, 100));
+        assert!(!sim.game_over());
+
+        let center = sim.config().rink.center();
+        sim.place_puck(Point3::new(center.x, 0.0206, 6.0), Vector3::new(0.0, 0.0, -0.1));
+        for _ in 0..30 {
+            sim.step();
+        }
+        assert_eq!((sim.red_score, sim.blue_score), (1, 0));
+        assert!(sim.game_over());
+        let time = sim.time;
+        sim.step();
+        assert_eq!(sim.time, time);
+
+        sim.new_game();
+        assert_eq!((sim.period, sim.red_score), (0, 0));
+        assert!(!sim.game_over());
+    }
+}
@@ -0,0 +1,279 @@
+use crate::hqm_clock::GameClock;
+use crate::hqm_events::{GameEvent, HQMEventDetector};
+use crate::hqm_game::{HQMGameState, HQMGameStateObject, HQMMessage, HQMMotionHistory, HQMPlayer};
+use crate::hqm_parse::{self, DeltaBaselineCache, HQMGameUpdatePacket, ProtocolError, ServerPacket};
+use crate::hqm_possession::{HQMPossession, HQMPossessionTracker};
+use crate::hqm_rink::Rink;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Everything the bot logic gets to see for one game update
+#[derive(Debug, Clone)]
+pub struct HQMTick {
+    pub state: HQMGameState,
+    /// Messages not seen in earlier updates
+    pub messages: Vec<HQMMessage>,
+    pub events: Vec<GameEvent>,
+}
+
+#[derive(Debug, Clone)]
+pub enum HQMTrackerUpdate {
+    Tick(Box<HQMTick>),
+    /// The server announced a game. `started` is false if it is the game we are already in.
+    NewGame {
+        game_id: u32,
+        started: bool
+    },
+}
+
+/// Turns the packets received from a server into game states, keeping track of
+/// everything needed to decode the next packet.
+#[derive(Debug)]
+pub struct HQMGameTracker {
+    current_game: u32,
+    known_packet: u32,
+    known_msgpos: u16,
+    players: HashMap<usize, HQMPlayer>,
+    saved_packets: DeltaBaselineCache,
+    motion: HQMMotionHistory,
+    possession: HQMPossessionTracker,
+    events: HQMEventDetector,
+    rink: Arc<Rink>,
+}
+
+impl Default for HQMGameTracker {
+    fn default() -> Self {
+        Self::new(Arc::new(Rink::standard()))
+    }
+}
+
+impl HQMGameTracker {
+    pub fn new(rink: Arc<Rink>) -> Self {
+        HQMGameTracker {
+            current_game: u32::MAX,
+            known_packet: u32::MAX,
+            known_msgpos: 0,
+            players: HashMap::new(),
+            saved_packets: DeltaBaselineCache::new(),
+            motion: HQMMotionHistory::new(),
+            possession: HQMPossessionTracker::new(),
+            events: HQMEventDetector::new(),
+            rink
+        }
+    }
+
+    /// The game we are in, `u32::MAX` before the server has told us
+    pub fn current_game(&self) -> u32 {
+        self.current_game
+    }
+
+    /// Latest packet received, `u32::MAX` if there is none
+    pub fn known_packet(&self) -> u32 {
+        self.known_packet
+    }
+
+    /// Index of the first message not received yet
+    pub fn known_msgpos(&self) -> u16 {
+        self.known_msgpos
+    }
+
+    pub fn handle_packet(&mut self, msg: &[u8]) -> Result<HQMTrackerUpdate, ProtocolError> {
+        let packet = hqm_parse::decode_server_packet(msg, &self.saved_packets)?;
+        Ok(self.handle_server_packet(packet))
+    }
+
+    /// Handles a packet that has already been decoded, for sources other than a live server
+    pub fn handle_server_packet(&mut self, packet: ServerPacket) -> HQMTrackerUpdate {
+        match packet {
+            ServerPacket::GameUpdate(update) => HQMTrackerUpdate::Tick(Box::new(self.handle_game_update(update))),
+            ServerPacket::NewGame { game_id } => {
+                let started = self.current_game != game_id;
+                if started {
+                    self.reset();
+                    self.current_game = game_id;
+                }
+                HQMTrackerUpdate::NewGame { game_id, started }
+            }
+        }
+    }
+
+    /// Handles a game update that has already been decoded. Game updates never start a new game.
+    pub fn handle_game_update(&mut self, update: HQMGameUpdatePacket) -> HQMTick {
+        let first_new = (self.known_msgpos as u32).saturating_sub(update.message_pos) as usize;
+        let messages: Vec<HQMMessage> = update.messages.into_iter().skip(first_new).collect();
+
+        for message in messages.iter() {
+            if let HQMMessage::PlayerUpdate { player_name, object, player_index, in_server } = message {
+                if *in_server {
+                    self.players.insert(*player_index, HQMPlayer {
+                        name: player_name.clone(),
+                        index: *player_index,
+                        object_index: *object
+                    });
+                } else {
+                    self.players.remove(player_index);
+                }
+            }
+        }
+
+        let mut objects: Vec<HQMGameStateObject> = update.objects.iter().map(hqm_parse::convert_object_from_network).collect();
+        self.motion.update(update.step, &mut objects, &self.players);
+
+        let mut state = HQMGameState {
+            game_id: update.game_id,
+            step: update.step,
+            red_score: update.red_score,
+            blue_score: update.blue_score,
+            time: update.time,
+            period: update.period,
+            game_over: update.game_over,
+            goal_interruption: update.goal_time > 0,
+            objects,
+            yourself: update.own_player_index,
+            players: self.players.clone(),
+            rink: self.rink.clone(),
+            possession: HQMPossession::default(),
+            clock: GameClock::new(update.time, update.period, update.goal_time),
+        };
+        let mut events: Vec<GameEvent> = self.possession.update(&mut state).into_iter().collect();
+        events.extend(self.events.update(&state));
+
+        let message_end = update.message_pos + first_new as u32 + messages.len() as u32;
+        if (self.known_msgpos as u32) < message_end {
+            self.known_msgpos = message_end as u16;
+        }
+
+        self.saved_packets.insert(update.packet, update.objects);
+
+        if self.known_packet == u32::MAX || self.known_packet < update.packet {
+            self.known_packet = update.packet;
+        }
+
+        HQMTick {
+            state,
+            messages,
+            events
+        }
+    }
+
+    /// Forgets the current game
+    pub fn reset(&mut self) {
+        self.current_game = u32::MAX;
+        self.known_packet = u32::MAX;
+        self.known_msgpos = 0;
+        self.saved_packets.clear();
+        self.players.clear();
+        self.motion.clear();
+        self.possession.clear();
+        self.events.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_game::HQMTeam;
+
+    fn update(packet: u32, message_pos: u32, messages: Vec<HQMMessage>) -> HQMGameUpdatePacket {
+        HQMGameUpdatePacket {
+            game_id: 1,
+            step: packet,
+            game_over: false,
+            red_score: 0,
+            blue_score: 0,
+            time: 30000,
+            goal_time: 0,
+            period: 1,
+            own_player_index: 0,
+            packet,
+            known_packet: u32::MAX,
+            objects: Vec::new(),
+            message_pos,
+            messages
+        }
+    }
+
+    fn chat(message: &str) -> HQMMessage {
+        HQMMessage::Chat { player_index: None, message: message.to_owned() }
+    }
+
+    fn joined(player_index: usize) -> HQMMessage {
+        HQMMessage::PlayerUpdate {
+            player_name: format!("P{}", player_index),
+            object: Some((0, HQMTeam::Red)),
+            player_index,
+            in_server: true
+        }
+    }
+
+    fn texts(tick: &HQMTick) -> Vec<&str> {
+        tick.messages.iter().map(|x| match x {
+            HQMMessage::Chat { message, .. } => message.as_str(),
+            _ => "?"
+        }).collect()
+    }
+
+    #[test]
+    fn messages_already_seen_are_dropped() {
+        let mut tracker = HQMGameTracker::default();
+        let tick = tracker.handle_game_update(update(0, 0, vec![chat("a"), chat("b")]));
+        assert_eq!(texts(&tick), vec!["a", "b"]);
+        assert_eq!(tracker.known_msgpos(), 2);
+
+        // The server repeats messages until it hears the client has them
+        let tick = tracker.handle_game_update(update(1, 1, vec![chat("b"), chat("c"), chat("d")]));
+        assert_eq!(texts(&tick), vec!["c", "d"]);
+        assert_eq!(tracker.known_msgpos(), 4);
+    }
+
+    #[test]
+    fn update_with_only_old_messages_changes_nothing() {
+        let mut tracker = HQMGameTracker::default();
+        tracker.handle_game_update(update(0, 0, vec![chat("a"), chat("b"), chat("c")]));
+        let tick = tracker.handle_game_update(update(1, 0, vec![chat("a"), chat("b")]));
+        assert!(tick.messages.is_empty());
+        assert_eq!(tracker.known_msgpos(), 3);
+    }
+
+    #[test]
+    fn late_packets_leave_known_packet_alone() {
+        let mut tracker = HQMGameTracker::default();
+        tracker.handle_game_update(update(10, 0, Vec::new()));
+        tracker.handle_game_update(update(12, 0, Vec::new()));
+        tracker.handle_game_update(update(11, 0, Vec::new()));
+        assert_eq!(tracker.known_packet(), 12);
+        // Still kept as a baseline for delta compression
+        assert!(tracker.saved_packets.get(11).is_some());
+    }
+
+    #[test]
+    fn only_a_new_game_id_starts_over() {
+        let mut tracker = HQMGameTracker::default();
+        match tracker.handle_server_packet(ServerPacket::NewGame { game_id: 1 }) {
+            HQMTrackerUpdate::NewGame { game_id: 1, started: true } => {}
+            x => panic!("unexpected update {:?}", x)
+        }
+        tracker.handle_game_update(update(5, 0, vec![joined(3), chat("a")]));
+
+        // The server announces the game again, which isn't a new one
+        match tracker.handle_server_packet(ServerPacket::NewGame { game_id: 1 }) {
+            HQMTrackerUpdate::NewGame { game_id: 1, started: false } => {}
+            x => panic!("unexpected update {:?}", x)
+        }
+        assert_eq!(tracker.current_game(), 1);
+        assert_eq!(tracker.known_packet(), 5);
+        assert_eq!(tracker.known_msgpos(), 2);
+        assert!(tracker.players.contains_key(&3));
+        assert!(tracker.saved_packets.get(5).is_some());
+
+        match tracker.handle_server_packet(ServerPacket::NewGame { game_id: 2 }) {
+            HQMTrackerUpdate::NewGame { game_id: 2, started: true } => {}
+            x => panic!("unexpected update {:?}", x)
+        }
+        assert_eq!(tracker.current_game(), 2);
+        assert_eq!(tracker.known_packet(), u32::MAX);
+        assert_eq!(tracker.known_msgpos(), 0);
+        assert!(tracker.players.is_empty());
+        assert!(tracker.saved_packets.get(5).is_none());
+    }
+}
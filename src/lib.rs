@@ -0,0 +1,20 @@
+pub mod hqm_parse;
+pub mod hqm_bot;
+pub mod hqm_game;
+pub mod hqm_commands;
+pub mod hqm_predict;
+pub mod hqm_rink;
+pub mod hqm_possession;
+pub mod hqm_events;
+pub mod hqm_clock;
+pub mod hqm_tracker;
+pub mod hqm_replay;
+pub mod hqm_hrp;
+pub mod hqm_replay_driver;
+pub mod hqm_export;
+pub mod hqm_sim;
+pub mod hqm_gym;
+pub mod hqm_fleet;
+pub mod hqm_blackboard;
+#[cfg(test)]
+mod hqm_mock_server;
@@ -1,11 +1,11 @@
 use std::env;
 use std::net::{IpAddr, SocketAddr};
-use crate::hqm_game::{HQMMessage, HQMPlayerInput, HQMGameState};
-use crate::hqm_bot::{HQMBotLogic, HQMBotSession};
-
-mod hqm_parse;
-mod hqm_bot;
-mod hqm_game;
+use rust_hqm_bot::hqm_game::{HQMMessage, HQMPlayerInput, HQMGameState};
+use rust_hqm_bot::hqm_bot::{HQMBotLogic, HQMSessionConfig};
+use rust_hqm_bot::hqm_fleet::{BotFleet, HQMFleetConfig};
+use std::str::FromStr;
+use std::time::Duration;
+use rust_hqm_bot::hqm_gym::{self, HQMSimEnv, HQMSimEnvConfig, HQMVecEnv};
 
 struct EmptyBot {
 }
@@ -15,7 +15,7 @@ impl HQMBotLogic for EmptyBot {
 
     }
 
-    fn tick(&mut self, gamestate: &HQMGameState, messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
+    fn tick(&mut self, gamestate: &HQMGameState, _messages: &[HQMMessage]) -> (HQMPlayerInput, Option<String>) {
         let input = Default::default();
         let chat = if gamestate.step % 1000 == 700 {
             Some("Test".to_owned())
@@ -30,14 +30,75 @@ impl HQMBotLogic for EmptyBot {
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
-    let addr = args[1].parse::<IpAddr> ().unwrap();
-    let port = args[2].parse::<u16> ().unwrap();
-    let name = args[3].clone();
+    if args.get(1).map(String::as_str) == Some("gym") {
+        return run_gym(&args[2..]);
+    }
+    if args.len() < 3 {
+        return Err(usage_error("missing server address or port"));
+    }
+    if args.len() < 4 {
+        return Err(usage_error("missing bot name"));
+    }
+    let addr = parse_arg::<IpAddr>(&args[1], "server address")?;
+    let port = parse_arg::<u16>(&args[2], "port")?;
     let addr = SocketAddr::new(addr, port);
 
-    HQMBotSession::new(name, EmptyBot {}).start(addr).await?;
+    // Every name after the port is one more bot on the same server
+    let mut fleet = BotFleet::new(HQMFleetConfig::default());
+    for name in args[3..].iter() {
+        fleet.add_bot(name.clone(), addr, HQMSessionConfig::default(), Box::new(|_| Box::new(EmptyBot {})));
+    }
+    let shutdown = fleet.shutdown_handle();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown.stop();
+        }
+    });
+    let status = fleet.status_handle();
+    let report = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            eprintln!("{}", status.status());
+        }
+    });
+
+    let status = fleet.run().await;
+    report.abort();
+    println!("{}", status);
 
     Ok(())
 
 
 }
+
+/// `gym [environments] [port]` runs simulated environments for a trainer, over
+/// stdin/stdout or on a local TCP port
+fn run_gym(args: &[String]) -> std::io::Result<()> {
+    let count = match args.first() {
+        Some(count) => parse_arg::<usize>(count, "number of environments")?,
+        None => 1
+    };
+    let mut envs = HQMVecEnv::new((0..count).map(|_| HQMSimEnv::new(HQMSimEnvConfig::default())).collect());
+    match args.get(1) {
+        Some(port) => {
+            let addr = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), parse_arg::<u16>(port, "port")?);
+            hqm_gym::serve_bridge(&mut envs, addr)
+        }
+        None => {
+            let stdin = std::io::stdin();
+            hqm_gym::run_bridge(&mut envs, stdin.lock(), std::io::stdout())
+        }
+    }
+}
+
+fn usage_error(message: &str) -> std::io::Error {
+    eprintln!("Usage: rust-hqm-bot <server address> <port> <bot name> [more bot names...]");
+    eprintln!("       rust-hqm-bot gym [environments] [port]");
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+fn parse_arg<T: FromStr>(arg: &str, what: &str) -> std::io::Result<T> {
+    arg.parse::<T>().map_err(|_| usage_error(&format!("invalid {}: {}", what, arg)))
+}